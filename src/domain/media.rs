@@ -1,13 +1,64 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
+use std::str::FromStr;
 
-struct MediaFormat(MediaType, &'str)
+#[derive(Debug)]
+pub enum MediaError {
+    FormatParseError,
+}
+
+/// The kind of media content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaType {
+    Image,
+    Video,
+}
 
-enum MediaFormat {
-    Image(mime: &'static str, ext: &'static str)
+/// Image file formats supported by the transcoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Svg,
 }
 
-// MediaFormat::Image("png/image")
+impl ImageFormat {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Svg => "image/svg+xml",
+        }
+    }
 
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = MediaError;
 
+    /// Parses the format from its file extension.
+    fn from_str(s: &str) -> Result<ImageFormat, MediaError> {
+        match s {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "gif" => Ok(ImageFormat::Gif),
+            "webp" => Ok(ImageFormat::WebP),
+            "svg" => Ok(ImageFormat::Svg),
+            _ => Err(MediaError::FormatParseError),
+        }
+    }
+}
@@ -1,19 +1,64 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
+use crate::storage::blob::hashing::{Hash, HashError};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::str::FromStr;
 use std::usize;
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
 
+/// Checksum of the binary contents of a blob.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksum {
+    /// Name of the hash algorithm used, see `hashing::Hash`.
+    pub algorithm: String,
+    /// The hex encoded digest.
+    pub digest: String,
+}
+
+impl Checksum {
+    pub fn new(buffer: &[u8], hash: &Hash) -> Self {
+        Self {
+            algorithm: hash.name().to_string(),
+            digest: hex::encode(hash.hash_bytes(buffer)),
+        }
+    }
+
+    /// Creates the checksum from a digest computed elsewhere, e.g. incrementally.
+    pub fn from_digest(hash: &Hash, digest: &[u8]) -> Self {
+        Self {
+            algorithm: hash.name().to_string(),
+            digest: hex::encode(digest),
+        }
+    }
+
+    /// Returns true if the buffer matches the checksum.
+    pub fn verify(&self, buffer: &[u8]) -> Result<bool, HashError> {
+        let hash = Hash::from_str(&self.algorithm)?;
+        Ok(Checksum::new(buffer, &hash) == *self)
+    }
+}
+
+/// State of the eager generation of the presets of a media.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PresetsState {
+    /// Queued or running, queued again on startup.
+    Pending,
+    Done,
+}
+
 /// Binary Object Meta Data
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlobMeta {
     // Stores a unique identifier of this blob.
     pub id: Uuid,
 
-    // A Binary Hash like MD5 or similar (configurable).
-    //checksum: Checksum,
+    /// A Binary Hash like SHA-2 or similar (configurable), not known for blobs created
+    /// without their contents.
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
 
     // Metric space embeddings of the content, pHash or similar (configurable).
     //embeddings: HashMap<String, Embedding>,
@@ -21,6 +66,18 @@ pub struct BlobMeta {
     /// The size of this binary object:
     pub size: usize,
 
+    /// Id of the media this blob is a derived variant of, it is removed along with it.
+    #[serde(default)]
+    pub source: Option<Uuid>,
+
+    /// Errors of the presets whose eager generation failed, by preset name.
+    #[serde(default)]
+    pub variant_errors: BTreeMap<String, String>,
+
+    /// State of the eager generation of the presets, None if it was never queued.
+    #[serde(default)]
+    pub presets: Option<PresetsState>,
+
     // Mimetype of the blob:
     //mime: Mimetype,
 
@@ -32,12 +89,24 @@ impl BlobMeta {
     pub fn new(size: usize) -> Self {
         Self {
             id: Uuid::new_v4(),
+            checksum: None,
             size,
+            source: None,
+            variant_errors: BTreeMap::new(),
+            presets: None,
         }
     }
 
-    //pub fn new_from_buffer(buffer: &[u8], hash: &Hash) -> Self {
-    //}
+    pub fn new_from_buffer(buffer: &[u8], hash: &Hash) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            checksum: Some(Checksum::new(buffer, hash)),
+            size: buffer.len(),
+            source: None,
+            variant_errors: BTreeMap::new(),
+            presets: None,
+        }
+    }
 }
 
 impl fmt::Display for BlobMeta {
@@ -1,4 +1,5 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
+pub mod media;
 pub mod meta;
@@ -5,6 +5,7 @@
 // Licensed under the Apache License, Version 2.0, or the MIT License
 pub mod domain;
 pub mod storage;
+pub mod transcoder;
 
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -22,8 +23,23 @@ fn load_fixture(filename: PathBuf) -> Vec<u8> {
 
 use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
 use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+use crate::storage::blob::config::{BlobStorageConfig};
+use crate::storage::meta::config::{MetaStorageConfig};
+use crate::transcoder::config::{TranscodingConfig};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    pub storage_blob_bucket: BucketBlobStorageConfig,
+    /// Address the HTTP service binds to, for instance 127.0.0.1:8080.
+    pub service_bind: String,
+    /// Maximum size of an uploaded media body (in bytes).
+    pub service_max_upload_size: usize,
+    /// Maximum total size of the cached image variants (in bytes), 0 disables the cache.
+    #[serde(default)]
+    pub variant_cache_max_size: usize,
+    #[serde(flatten)]
+    pub storage_blob: BlobStorageConfig,
+    #[serde(flatten)]
+    pub storage_meta: MetaStorageConfig,
+    #[serde(flatten)]
+    pub transcoding: TranscodingConfig,
 }
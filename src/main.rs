@@ -3,58 +3,251 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
-// mod service;
-// use actix_web::{web, App, HttpServer};
-// use service::handler::ping::ping_handler;
+mod service;
+use actix_web::{web, App, HttpServer};
+use service::handler::media::{delete_handler, download_handler, preset_handler, upload_handler};
+use service::handler::job::job_handler;
+use service::handler::ping::ping_handler;
+use service::jobs::{self, JobQueue};
+use service::state::{StorageState, TranscodingState, UploadState};
 extern crate rupee;
 extern crate uuid;
 extern crate vips;
 use rupee::{Config};
-use rupee::domain::meta::BlobMeta;
-use rupee::storage::blob::factory::create_blob_storage;
+use rupee::storage::blob::blocking::BlockingBlobStorage;
+use rupee::storage::blob::factory::{create_blob_storage, create_blob_storage_with_migrator, init_blob_storage};
+use rupee::storage::blob::hashing::Hash;
+use rupee::storage::meta::MetaStorage;
+use rupee::storage::meta::factory::{create_async_meta_storage, create_meta_storage, init_meta_storage};
+use rupee::storage::compaction::compact_buckets;
+use rupee::storage::gc::collect_garbage;
+use rupee::storage::recovery::rebuild_meta_storage;
+use rupee::storage::rotation;
+use rupee::storage::variant::VariantCache;
 use vips::Vips;
 
+use std::env;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::io::Read;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
 
-fn main() {
-    // let vips = Vips::new().expect("unexpected vips init error!");
+const USAGE: &str = "usage: rupee [serve|recover-meta|gc [--dry-run]|rotate-keys [--reencrypt]|compact]
 
-    let config: Config = serde_yaml::from_reader(File::open("res/config.yml").expect("error!")).expect("error!");
+commands:
+    serve          run the http service (default)
+    recover-meta   rebuild the meta storage from the bucket files
+    gc             remove orphaned blobs and meta pointing at missing blobs,
+                   only reports them with --dry-run
+    rotate-keys    rewrap the data keys of encrypted blobs with the current master key,
+                   rewrite the blobs with new data keys with --reencrypt
+    compact        rewrite bucket files with too many deleted blobs";
 
-    println!("test");
-    println!("{:?}", config);
-    println!("{:?}", config.storage_blob_bucket.path);
-    println!("{:?}", config.storage_blob_bucket.max_size);
+fn main() -> std::io::Result<()> {
+    let config: Config = serde_yaml::from_reader(File::open("res/config.yml").expect("error opening config file!"))
+        .expect("error parsing config file!");
 
+    match env::args().nth(1).as_deref() {
+        None | Some("serve") => serve(config),
+        Some("recover-meta") => recover_meta(config),
+        Some("gc") => gc(config, env::args().nth(2).as_deref() == Some("--dry-run")),
+        Some("rotate-keys") => rotate_keys(config, env::args().nth(2).as_deref() == Some("--reencrypt")),
+        Some("compact") => compact(config),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
 }
 
-/*
-fn main() {
-    let mut backend = create_blob_storage("mem").expect("WHAT?");
+/// Rebuilds the configured meta storage from the bucket blob storage directory.
+fn recover_meta(config: Config) -> std::io::Result<()> {
+    init_meta_storage(&config.storage_meta).expect("error initializing meta storage!");
+    let meta_storage = create_meta_storage(config.storage_meta.clone()).expect("error creating meta storage!");
 
-    let buffer: Vec<u8> = vec![0, 42, 0];
-    let meta = BlobMeta::new(buffer.len());
+    let report = rebuild_meta_storage(
+        &config.storage_blob,
+        &config.storage_blob.storage_blob_type,
+        meta_storage.as_ref(),
+    )
+    .expect("error rebuilding meta storage!");
 
-    let blob_ref = backend.put(&meta, buffer).expect("WHAT?");
+    println!("recovered {} blobs, skipped {} existing blobs", report.recovered, report.skipped);
+    Ok(())
+}
 
-    println!("meta: {}", meta);
-    println!("blob_ref: {}", blob_ref);
+/// Cross-references the meta storage with the blob storage, run while the service is stopped.
+fn gc(config: Config, dry_run: bool) -> std::io::Result<()> {
+    init_meta_storage(&config.storage_meta).expect("error initializing meta storage!");
+    let meta_storage = create_meta_storage(config.storage_meta.clone()).expect("error creating meta storage!");
 
-    let buf = backend.get(&meta, &blob_ref).expect("asdf");
+    let report = collect_garbage(
+        &config.storage_blob,
+        &config.storage_blob.storage_blob_type,
+        meta_storage.as_ref(),
+        dry_run,
+    )
+    .expect("error collecting garbage!");
 
-    println!("{}", buf.len());
-    println!("{:?}", buf);
+    for orphan in report.orphans.iter() {
+        println!("orphan: {} {} {}", orphan.id, orphan.location, orphan.blob_ref);
+    }
+    for id in report.dangling.iter() {
+        println!("dangling: {}", id);
+    }
+    println!(
+        "{} blobs in use, {} orphaned blobs, {} dangling refs{}",
+        report.marked,
+        report.orphans.len(),
+        report.dangling.len(),
+        if report.reclaimed { " removed" } else { "" },
+    );
+    Ok(())
 }
-*/
 
-/*
-fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new()
-        .route("/ping", web::get().to(ping_handler))
+/// Rewraps the data keys of the encrypted blobs or rewrites the blobs, run while the
+/// service is stopped.
+fn rotate_keys(config: Config, reencrypt: bool) -> std::io::Result<()> {
+    init_blob_storage(&config.storage_blob).expect("error initializing blob storage!");
+    init_meta_storage(&config.storage_meta).expect("error initializing meta storage!");
+    let blob_storage = create_blob_storage(config.storage_blob.clone()).expect("error creating blob storage!");
+    let meta_storage = create_meta_storage(config.storage_meta.clone()).expect("error creating meta storage!");
+
+    let backend = &config.storage_blob.storage_blob_type;
+    let report = if reencrypt {
+        rotation::reencrypt_blobs(&config.storage_blob, blob_storage.as_ref(), backend, meta_storage.as_ref())
+    } else {
+        rotation::rotate_keys(blob_storage.as_ref(), backend, meta_storage.as_ref())
+    }
+    .expect("error rotating master keys!");
+
+    for id in report.failed.iter() {
+        println!("failed: {}", id);
+    }
+    println!(
+        "rotated {} blob refs, {} unchanged, {} failed",
+        report.rotated,
+        report.unchanged,
+        report.failed.len(),
+    );
+    if !report.failed.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Reclaims the space of deleted blobs in the bucket files, run while the service is stopped.
+fn compact(config: Config) -> std::io::Result<()> {
+    init_blob_storage(&config.storage_blob).expect("error initializing blob storage!");
+    init_meta_storage(&config.storage_meta).expect("error initializing meta storage!");
+    let meta_storage = create_meta_storage(config.storage_meta.clone()).expect("error creating meta storage!");
+
+    let summary = compact_buckets(
+        &config.storage_blob,
+        &config.storage_blob.storage_blob_type,
+        meta_storage.as_ref(),
     )
-    .bind("127.0.0.1:8080")?
-    .run()
+    .expect("error compacting bucket files!");
+
+    for (location, index) in summary.buckets.iter() {
+        println!("compacted: {} #{}", location, index);
+    }
+    println!(
+        "compacted {} bucket files, reclaimed {} bytes, updated {} blob refs",
+        summary.buckets.len(),
+        summary.reclaimed,
+        summary.updated,
+    );
+    Ok(())
+}
+
+/// Creates the storages before the async runtime is started, the blocking postgres
+/// client can't be used inside of it.
+fn serve(config: Config) -> std::io::Result<()> {
+    let presets = config.transcoding.presets().expect("invalid transcoding preset!");
+    let eager_presets = config.transcoding.eager_presets().expect("invalid eager preset!");
+    init_blob_storage(&config.storage_blob).expect("error initializing blob storage!");
+    init_meta_storage(&config.storage_meta).expect("error initializing meta storage!");
+
+    let checksum = Hash::from_str(&config.storage_blob.storage_blob_checksum).expect("unknown checksum algorithm!");
+    if checksum.hasher().is_none() {
+        eprintln!("The {} checksum can't be verified while streaming, configure another algorithm!", checksum.name());
+        process::exit(1);
+    }
+    let (blob_storage, migrator) = create_blob_storage_with_migrator(config.storage_blob.clone())
+        .expect("error creating blob storage!");
+    let meta_storage: Arc<dyn MetaStorage> =
+        Arc::from(create_meta_storage(config.storage_meta.clone()).expect("error creating meta storage!"));
+
+    let pinned = eager_presets.iter().map(|(_, variant)| variant.canonical()).collect();
+    let variants = VariantCache::load(meta_storage.as_ref(), config.variant_cache_max_size, pinned)
+        .expect("error loading the variant cache!");
+
+    // the presets still pending before the last shutdown are generated again
+    let (jobs, receiver) = JobQueue::new(eager_presets, jobs::MAX_ATTEMPTS, jobs::BACKOFF);
+    let resumed = jobs.resume(meta_storage.as_ref()).expect("error resuming the preset jobs!");
+    if resumed > 0 {
+        println!("jobs: resumed the presets of {} media", resumed);
+    }
+
+    // moves blobs of the tiered blob storage to colder tiers in the background
+    if let Some(mut migrator) = migrator {
+        let meta_storage = meta_storage.clone();
+        thread::spawn(move || {
+            match migrator.seed(meta_storage.as_ref()) {
+                Ok(report) => println!(
+                    "tiering: tracking {} blobs, {} previous copies to delete",
+                    report.tracked, report.retired
+                ),
+                Err(err) => eprintln!("tiering: error seeding the migrator: {:?}", err),
+            }
+            loop {
+                thread::sleep(migrator.interval());
+                let report = migrator.run(meta_storage.as_ref());
+                println!(
+                    "tiering: migrated {} blobs, retired {} copies, {} failed, {} left to gc",
+                    report.migrated, report.retired, report.failed, report.abandoned
+                );
+            }
+        });
+    }
+
+    actix_rt::System::new("rupee").block_on(async move {
+        let state = web::Data::new(StorageState::new(
+            config.storage_blob.storage_blob_type.clone(),
+            checksum,
+            Arc::new(BlockingBlobStorage::new(Arc::from(blob_storage))),
+            create_async_meta_storage(config.storage_meta.clone(), meta_storage)
+                .await
+                .expect("error creating async meta storage!"),
+            variants,
+        ));
+        let transcoding = web::Data::new(TranscodingState {
+            presets,
+            strict: config.transcoding.transcoding_strict,
+            limits: config.transcoding.limits(),
+        });
+        let jobs = web::Data::new(jobs);
+        actix_rt::spawn(jobs::run(jobs.clone(), state.clone(), receiver));
+        let upload = web::Data::new(UploadState { max_size: config.service_max_upload_size });
+
+        HttpServer::new(move || App::new()
+            .app_data(state.clone())
+            .app_data(transcoding.clone())
+            .app_data(jobs.clone())
+            .app_data(upload.clone())
+            .route("/ping", web::get().to(ping_handler))
+            .route("/media", web::post().to(upload_handler))
+            .route("/media/{id}", web::get().to(download_handler))
+            .route("/media/{id}", web::delete().to(delete_handler))
+            .route("/media/{id}/{preset}", web::get().to(preset_handler))
+            .route("/jobs/{id}", web::get().to(job_handler))
+        )
+        .bind(&config.service_bind)?
+        .run()
+        .await
+    })
 }
-*/
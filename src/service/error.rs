@@ -0,0 +1,99 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use rupee::storage::blob::BlobStorageError;
+use rupee::storage::meta::MetaStorageError;
+use rupee::storage::store::StoreError;
+use rupee::transcoder::TranscoderError;
+use std::fmt;
+
+/// Errors returned by the request handlers, mapped to http status codes.
+#[derive(Debug)]
+pub enum ServiceError {
+    /// The requested media does not exist.
+    NotFound,
+    /// The request is malformed, for instance an unknown query parameter value.
+    BadRequest(&'static str),
+    /// The uploaded media exceeds the configured maximum size.
+    PayloadTooLarge,
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+    TranscoderError(TranscoderError),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::NotFound => write!(f, "media not found"),
+            ServiceError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ServiceError::PayloadTooLarge => write!(f, "payload too large"),
+            ServiceError::BlobStorageError(err) => write!(f, "blob storage error: {:?}", err),
+            ServiceError::MetaStorageError(err) => write!(f, "meta storage error: {:?}", err),
+            ServiceError::TranscoderError(err) => write!(f, "transcoder error: {:?}", err),
+        }
+    }
+}
+
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::NotFound => StatusCode::NOT_FOUND,
+            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::TranscoderError(err) => match err {
+                TranscoderError::MediaTypeMismatch
+                | TranscoderError::UnsupportedTarget
+                | TranscoderError::InvalidTransformation
+                | TranscoderError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+                TranscoderError::UnsupportedSource | TranscoderError::SourceMismatch => {
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<BlobStorageError> for ServiceError {
+    fn from(error: BlobStorageError) -> Self {
+        eprintln!("Service: Blob Storage Error {:?}", error);
+        ServiceError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for ServiceError {
+    fn from(error: MetaStorageError) -> Self {
+        eprintln!("Service: Meta Storage Error {:?}", error);
+        ServiceError::MetaStorageError(error)
+    }
+}
+
+impl From<StoreError> for ServiceError {
+    fn from(error: StoreError) -> Self {
+        match error {
+            StoreError::NotFound => ServiceError::NotFound,
+            StoreError::BlobStorageError(err) => err.into(),
+            StoreError::MetaStorageError(err) => err.into(),
+        }
+    }
+}
+
+impl From<TranscoderError> for ServiceError {
+    fn from(error: TranscoderError) -> Self {
+        eprintln!("Service: Transcoder Error {:?}", error);
+        ServiceError::TranscoderError(error)
+    }
+}
+
+impl From<BlockingError<TranscoderError>> for ServiceError {
+    fn from(error: BlockingError<TranscoderError>) -> Self {
+        match error {
+            BlockingError::Error(err) => err.into(),
+            BlockingError::Canceled => TranscoderError::ThreadError.into(),
+        }
+    }
+}
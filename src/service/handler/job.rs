@@ -0,0 +1,21 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+use actix_web::{web, HttpResponse};
+use uuid::Uuid;
+use super::super::error::ServiceError;
+use super::super::jobs::JobQueue;
+use super::super::response::job::JobResponse;
+use super::super::state::StorageState;
+
+
+/// Returns the status of the eager preset generation of the media.
+pub async fn job_handler(
+    state: web::Data<StorageState>,
+    jobs: web::Data<JobQueue>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let status = jobs.status(&state, *id).await?;
+
+    Ok(HttpResponse::Ok().json(JobResponse::from(&status)))
+}
@@ -0,0 +1,443 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+use actix_web::dev::SizedStream;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::channel::mpsc::channel;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use rupee::storage::blob::BlobStorageError;
+use rupee::transcoder::variant::{Variant, VariantParams};
+use uuid::Uuid;
+use super::super::error::ServiceError;
+use super::super::jobs::JobQueue;
+use super::super::response::media::MediaResponse;
+use super::super::state::{StorageState, TranscodingState, UploadState};
+
+
+/// Stores the streamed request body as a new media blob, returns its meta data. The
+/// eager presets are generated in the background.
+pub async fn upload_handler(
+    state: web::Data<StorageState>,
+    jobs: web::Data<JobQueue>,
+    upload: web::Data<UploadState>,
+    mut body: web::Payload,
+) -> Result<HttpResponse, ServiceError> {
+    // the payload is bound to the worker thread, its chunks are passed on to the store
+    let (mut sender, receiver) = channel(1);
+    let max_size = upload.max_size;
+    let forward = async move {
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) if size + chunk.len() <= max_size => chunk,
+                received => {
+                    // fails the store instead of ending the stream early
+                    let _ = sender.send(Err(BlobStorageError::IOError)).await;
+                    return match received {
+                        Ok(_) => Err(ServiceError::PayloadTooLarge),
+                        Err(err) => {
+                            eprintln!("Service: error receiving upload: {:?}", err);
+                            Err(ServiceError::BadRequest("error receiving the request body"))
+                        }
+                    };
+                }
+            };
+            size += chunk.len();
+            if sender.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    };
+
+    let (meta, forwarded) = futures::join!(state.store.store_stream(Box::pin(receiver)), forward);
+    forwarded?;
+    let meta = meta?;
+    if let Err(err) = jobs.enqueue(&state, meta.id).await {
+        eprintln!("Service: error queueing the presets of {}: {:?}", meta.id, err);
+    }
+
+    Ok(HttpResponse::Created().json(MediaResponse::from(&meta)))
+}
+
+/// A `Range` request header resolved against the size of a blob.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No (supported) range requested, the whole blob is returned.
+    Full,
+    /// Offset and length of the requested range.
+    Partial(usize, usize),
+    /// The range is outside of the blob.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses the value of a `Range` header, only a single byte range is supported,
+    /// anything else is ignored and answered with the full blob.
+    fn parse(value: &str, size: usize) -> ByteRange {
+        let spec = match value.trim().strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return ByteRange::Full,
+        };
+        let mut parts = spec.splitn(2, '-');
+        let (start, end) = match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => (start.trim(), end.trim()),
+            _ => return ByteRange::Full,
+        };
+
+        if start.is_empty() {
+            // suffix range of the last bytes
+            return match end.parse::<usize>() {
+                Ok(0) => ByteRange::Unsatisfiable,
+                Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+                Ok(suffix) => {
+                    let len = suffix.min(size);
+                    ByteRange::Partial(size - len, len)
+                }
+                Err(_) => ByteRange::Full,
+            };
+        }
+
+        let start = match start.parse::<usize>() {
+            Ok(start) => start,
+            Err(_) => return ByteRange::Full,
+        };
+        let end = match end {
+            "" => usize::MAX,
+            end => match end.parse::<usize>() {
+                Ok(end) if end >= start => end,
+                _ => return ByteRange::Full,
+            },
+        };
+        if start >= size {
+            return ByteRange::Unsatisfiable;
+        }
+        let end = end.min(size - 1);
+        ByteRange::Partial(start, end - start + 1)
+    }
+}
+
+/// Returns the image variant of the media.
+async fn variant_response(
+    state: &StorageState,
+    id: Uuid,
+    variant: &Variant,
+) -> Result<HttpResponse, ServiceError> {
+    let (content_type, buffer) = state.load_variant(id, variant).await?;
+    Ok(HttpResponse::Ok().content_type(content_type).body(buffer))
+}
+
+/// Streams the binary contents of the media, a single byte range of it is returned as
+/// partial content if requested with a `Range` header. With variant parameters in the
+/// query a derived image is returned instead, see `VariantParams`.
+pub async fn download_handler(
+    state: web::Data<StorageState>,
+    transcoding: web::Data<TranscodingState>,
+    req: HttpRequest,
+    id: web::Path<Uuid>,
+    params: web::Query<VariantParams>,
+) -> Result<HttpResponse, ServiceError> {
+    if !params.is_empty() {
+        if transcoding.strict {
+            return Err(ServiceError::BadRequest("variant parameters are disabled, use a preset"));
+        }
+        return variant_response(&state, *id, &params.variant(&transcoding.limits)?).await;
+    }
+
+    let meta = state.store.load_meta(*id).await?;
+    // variants are only served through the variant cache of their original
+    if meta.source.is_some() {
+        return Err(ServiceError::NotFound);
+    }
+
+    let range = match req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(value) => ByteRange::parse(value, meta.size),
+        None => ByteRange::Full,
+    };
+
+    match range {
+        ByteRange::Full => {
+            let (meta, stream) = state.store.load_stream(meta.id).await?;
+            let stream = stream.map_err(|err| ServiceError::from(err).into());
+
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .header(header::ACCEPT_RANGES, "bytes")
+                .body(SizedStream::new(meta.size as u64, stream)))
+        }
+        ByteRange::Partial(offset, len) => {
+            let buffer = state.store.load_range(meta.id, offset, len).await?;
+
+            Ok(HttpResponse::PartialContent()
+                .content_type("application/octet-stream")
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, offset + len - 1, meta.size),
+                )
+                .body(buffer))
+        }
+        ByteRange::Unsatisfiable => Ok(HttpResponse::RangeNotSatisfiable()
+            .header(header::CONTENT_RANGE, format!("bytes */{}", meta.size))
+            .finish()),
+    }
+}
+
+/// Returns the image variant of the media configured as the named preset.
+pub async fn preset_handler(
+    state: web::Data<StorageState>,
+    transcoding: web::Data<TranscodingState>,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, ServiceError> {
+    let (id, preset) = path.into_inner();
+    let variant = transcoding.presets.get(&preset).ok_or(ServiceError::NotFound)?;
+
+    variant_response(&state, id, variant).await
+}
+
+/// Removes the media blob and its meta data, along with its cached variants.
+pub async fn delete_handler(
+    state: web::Data<StorageState>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    // variants are removed with their original
+    if state.store.load_meta(*id).await?.source.is_some() {
+        return Err(ServiceError::NotFound);
+    }
+    state.store.remove(*id).await?;
+    state.variants.invalidate(&state.store, *id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App};
+    use actix_web::dev::{BodySize, MessageBody};
+    use actix_web::http::StatusCode;
+    use rupee::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use rupee::storage::blob::blocking::BlockingBlobStorage;
+    use rupee::storage::blob::hashing::Hash;
+    use rupee::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use rupee::storage::meta::blocking::BlockingMetaStorage;
+    use rupee::storage::variant::VariantCache;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use rupee::transcoder::variant::{VariantLimits, VariantParams};
+    use std::collections::HashMap;
+    use std::fs;
+    use super::{delete_handler, download_handler, preset_handler, upload_handler, ByteRange};
+    use std::time::Duration;
+    use super::super::super::jobs::JobQueue;
+    use super::super::super::state::{StorageState, TranscodingState, UploadState};
+
+    fn job_queue() -> web::Data<JobQueue> {
+        web::Data::new(JobQueue::new(vec![], 1, Duration::from_secs(1)).0)
+    }
+
+    fn upload_state() -> web::Data<UploadState> {
+        web::Data::new(UploadState { max_size: 1024 * 1024 })
+    }
+
+    fn transcoding_state(strict: bool) -> web::Data<TranscodingState> {
+        let thumbnail = VariantParams {
+            width: Some(50),
+            height: Some(50),
+            fit: Some("cover".to_string()),
+            ..Default::default()
+        };
+        let mut presets = HashMap::new();
+        presets.insert("thumbnail".to_string(), thumbnail.variant(&VariantLimits::default()).unwrap());
+        web::Data::new(TranscodingState { presets, strict, limits: VariantLimits::default() })
+    }
+
+    /// Storage state over in-memory blob and meta storage.
+    fn state() -> web::Data<StorageState> {
+        let blob = MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap();
+        let meta = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+        web::Data::new(StorageState::new(
+            "mem".to_string(),
+            Hash::Sha2_256,
+            Arc::new(BlockingBlobStorage::new(Arc::new(blob))),
+            Arc::new(BlockingMetaStorage::new(Arc::new(meta))),
+            VariantCache::new(1024 * 1024, vec![]),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_media_handlers() {
+        let state = state();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(job_queue())
+                .app_data(upload_state())
+                .app_data(transcoding_state(false))
+                .route("/media", web::post().to(upload_handler))
+                .route("/media/{id}", web::get().to(download_handler))
+                .route("/media/{id}", web::delete().to(delete_handler)),
+        )
+        .await;
+
+        let body: Vec<u8> = vec![0, 42, 0, 42, 0];
+
+        let req = test::TestRequest::post().uri("/media").set_payload(body.clone()).to_request();
+        let resp: Value = test::read_response_json(&mut app, req).await;
+        let id = resp["id"].as_str().expect("expected media id in response").to_string();
+        assert_eq!(resp["size"], 5);
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.response().body().size(), BodySize::Sized64(5));
+        assert_eq!(test::read_body(resp).await.to_vec(), body);
+
+        // larger than the upload limit:
+        let req = test::TestRequest::post().uri("/media").set_payload(vec![0; 1024 * 1024 + 1]).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/media/{}", id))
+            .header("Range", "bytes=1-3")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 1-3/5");
+        assert_eq!(test::read_body(resp).await.to_vec(), &body[1..4]);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/media/{}", id))
+            .header("Range", "bytes=5-")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // variants are neither served nor removed as media of their own:
+        let source = id.parse().unwrap();
+        let variant = VariantCache::variant_id(source, "variant");
+        state.variants.put(&state.store, source, variant, vec![42]).await.unwrap();
+        let req = test::TestRequest::get().uri(&format!("/media/{}", variant)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::delete().uri(&format!("/media/{}", variant)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(state.variants.contains(variant).unwrap());
+
+        let req = test::TestRequest::delete().uri(&format!("/media/{}", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!state.variants.contains(variant).unwrap());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(ByteRange::parse("bytes=0-99", 1000), ByteRange::Partial(0, 100));
+        assert_eq!(ByteRange::parse("bytes=900-", 1000), ByteRange::Partial(900, 100));
+        assert_eq!(ByteRange::parse("bytes=900-2000", 1000), ByteRange::Partial(900, 100));
+        assert_eq!(ByteRange::parse("bytes=-100", 1000), ByteRange::Partial(900, 100));
+        assert_eq!(ByteRange::parse("bytes=-2000", 1000), ByteRange::Partial(0, 1000));
+        assert_eq!(ByteRange::parse("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=5-1", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("items=0-1", 1000), ByteRange::Full);
+    }
+
+    #[actix_rt::test]
+    async fn test_media_strict() {
+        let state = state();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state)
+                .app_data(job_queue())
+                .app_data(upload_state())
+                .app_data(transcoding_state(true))
+                .route("/media", web::post().to(upload_handler))
+                .route("/media/{id}", web::get().to(download_handler))
+                .route("/media/{id}/{preset}", web::get().to(preset_handler)),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/media").set_payload(vec![0, 42, 0]).to_request();
+        let resp: Value = test::read_response_json(&mut app, req).await;
+        let id = resp["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}?w=100", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}/hero", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // the original is still served:
+        let req = test::TestRequest::get().uri(&format!("/media/{}", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_media_variant() {
+        let state = state();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state)
+                .app_data(job_queue())
+                .app_data(upload_state())
+                .app_data(transcoding_state(false))
+                .route("/media", web::post().to(upload_handler))
+                .route("/media/{id}", web::get().to(download_handler))
+                .route("/media/{id}/{preset}", web::get().to(preset_handler)),
+        )
+        .await;
+
+        let image = fs::read("res/fixtures/images/rgb.jpeg").expect("error reading fixture file!");
+        let req = test::TestRequest::post().uri("/media").set_payload(image).to_request();
+        let resp: Value = test::read_response_json(&mut app, req).await;
+        let id = resp["id"].as_str().unwrap().to_string();
+
+        let uri = format!("/media/{}?w=100&h=100&fit=cover&crop=attention&format=webp", id);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "image/webp");
+        let variant = test::read_body(resp).await;
+
+        // served from the variant cache:
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "image/webp");
+        assert_eq!(test::read_body(resp).await, variant);
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}/thumbnail", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "image/jpeg");
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}?w=0", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // exceeding the maximum dimension:
+        let req = test::TestRequest::get().uri(&format!("/media/{}?w=100000", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post().uri("/media").set_payload(vec![0, 42, 0]).to_request();
+        let resp: Value = test::read_response_json(&mut app, req).await;
+        let id = resp["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get().uri(&format!("/media/{}?w=100", id)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -1,4 +1,6 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
+pub mod job;
+pub mod media;
 pub mod ping;
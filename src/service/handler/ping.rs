@@ -5,6 +5,6 @@ use actix_web::{web, Responder};
 use super::super::response::pong::PongResponse;
 
 
-pub fn ping_handler() -> impl Responder {
+pub async fn ping_handler() -> impl Responder {
     web::Json(PongResponse::default())
 }
@@ -0,0 +1,485 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Variant Jobs
+//!
+//! Generates the eager presets of uploaded media in the background. The ids of uploaded
+//! media are queued and processed one after another by a single worker. Presets failing
+//! with a possibly transient error are retried with exponential backoff, by queueing the
+//! job again once the delay has passed. The errors of the presets still failing after
+//! the last attempt are recorded in the meta of the media. Jobs failing to load or
+//! update the meta are retried the same way.
+//!
+//! Only queued and running jobs are kept in memory. The meta of the media records
+//! whether its presets are pending or done, pending media are queued again on startup
+//! and the status of a finished job is derived from the meta.
+//!
+use actix_web::web;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use rupee::domain::meta::PresetsState;
+use rupee::storage::meta::{MetaStorage, MetaStorageError};
+use rupee::storage::store::StoreError;
+use rupee::transcoder::variant::Variant;
+use rupee::transcoder::TranscoderError;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use super::error::ServiceError;
+use super::state::StorageState;
+use uuid::Uuid;
+
+/// Number of attempts to generate a preset.
+pub const MAX_ATTEMPTS: usize = 5;
+
+/// Delay before the first retry, doubled for every further retry.
+pub const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Number of meta objects listed at once while resuming the jobs.
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub state: JobState,
+    /// Number of attempts made so far, unknown once the job is finished.
+    pub attempts: Option<usize>,
+    /// Errors of the failing presets, by preset name.
+    pub errors: BTreeMap<String, String>,
+}
+
+struct Job {
+    /// Indices of the presets still to be generated.
+    pending: Vec<usize>,
+    /// Number of failed attempts to load or update the meta.
+    failures: usize,
+    status: JobStatus,
+}
+
+pub struct JobQueue {
+    /// The presets generated for every uploaded media.
+    presets: Vec<(String, Variant)>,
+    max_attempts: usize,
+    backoff: Duration,
+    sender: UnboundedSender<Uuid>,
+    jobs: Mutex<HashMap<Uuid, Job>>,
+}
+
+/// Storage and image library errors may be transient, anything else fails the same
+/// way again.
+fn retryable(err: &ServiceError) -> bool {
+    match err {
+        ServiceError::BlobStorageError(_) | ServiceError::MetaStorageError(_) => true,
+        ServiceError::TranscoderError(err) => {
+            matches!(err, TranscoderError::ImageError(_) | TranscoderError::ThreadError)
+        }
+        _ => false,
+    }
+}
+
+impl JobQueue {
+    /// Creates the queue and the receiver of the queued media, to be passed to `run`.
+    pub fn new(
+        presets: Vec<(String, Variant)>,
+        max_attempts: usize,
+        backoff: Duration,
+    ) -> (Self, UnboundedReceiver<Uuid>) {
+        let (sender, receiver) = unbounded();
+        let queue = Self {
+            presets,
+            max_attempts,
+            backoff,
+            sender,
+            jobs: Mutex::new(HashMap::new()),
+        };
+        (queue, receiver)
+    }
+
+    fn lock_jobs(&self) -> Result<MutexGuard<'_, HashMap<Uuid, Job>>, ServiceError> {
+        self.jobs.lock().map_err(|_| {
+            eprintln!("Jobs: lock is poisoned!");
+            ServiceError::MetaStorageError(MetaStorageError::LockError)
+        })
+    }
+
+    /// Queues the generation of the presets of the media, if any are configured. The
+    /// presets are marked pending in the meta of the media until they are done.
+    pub async fn enqueue(&self, state: &StorageState, id: Uuid) -> Result<(), ServiceError> {
+        if self.presets.is_empty() {
+            return Ok(());
+        }
+
+        let mut meta = state.store.load_meta(id).await?;
+        meta.presets = Some(PresetsState::Pending);
+        state.store.update_meta(meta).await?;
+        self.queue(id)
+    }
+
+    /// Queues the media whose presets were still pending, returns their number.
+    pub fn resume(&self, meta_storage: &dyn MetaStorage) -> Result<usize, ServiceError> {
+        if self.presets.is_empty() {
+            return Ok(0);
+        }
+
+        let mut resumed = 0;
+        let mut after = None;
+        loop {
+            let page = meta_storage.list(after, PAGE_SIZE)?;
+            after = match page.last() {
+                Some(meta) => Some(meta.id),
+                None => break,
+            };
+            for meta in page {
+                if meta.presets == Some(PresetsState::Pending) {
+                    self.queue(meta.id)?;
+                    resumed += 1;
+                }
+            }
+        }
+        Ok(resumed)
+    }
+
+    fn queue(&self, id: Uuid) -> Result<(), ServiceError> {
+        let job = Job {
+            pending: (0..self.presets.len()).collect(),
+            failures: 0,
+            status: JobStatus {
+                state: JobState::Queued,
+                attempts: Some(0),
+                errors: BTreeMap::new(),
+            },
+        };
+        self.lock_jobs()?.insert(id, job);
+        if self.sender.unbounded_send(id).is_err() {
+            eprintln!("Jobs: worker is gone, job of {} is dropped!", id);
+            self.lock_jobs()?.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the status of the job of the media, not found if its presets were never
+    /// queued.
+    pub async fn status(&self, state: &StorageState, id: Uuid) -> Result<JobStatus, ServiceError> {
+        let running = self.lock_jobs()?.get(&id).map(|job| job.status.clone());
+        if let Some(status) = running {
+            return Ok(status);
+        }
+
+        let meta = state.store.load_meta(id).await?;
+        let state = match meta.presets {
+            // queued again on the next startup
+            Some(PresetsState::Pending) => JobState::Queued,
+            Some(PresetsState::Done) if meta.variant_errors.is_empty() => JobState::Done,
+            Some(PresetsState::Done) => JobState::Failed,
+            None => return Err(ServiceError::NotFound),
+        };
+        Ok(JobStatus {
+            state,
+            attempts: None,
+            errors: meta.variant_errors,
+        })
+    }
+
+    /// Generates the pending presets of the media, failed presets are retried later or
+    /// recorded in the meta after the last attempt.
+    async fn process(&self, state: &StorageState, id: Uuid) -> Result<(), ServiceError> {
+        let (pending, attempt, mut errors) = {
+            let mut jobs = self.lock_jobs()?;
+            let job = match jobs.get_mut(&id) {
+                Some(job) => job,
+                None => return Ok(()),
+            };
+            let attempt = job.status.attempts.unwrap_or(0) + 1;
+            job.status.state = JobState::Running;
+            job.status.attempts = Some(attempt);
+            (job.pending.clone(), attempt, job.status.errors.clone())
+        };
+
+        let mut retry = Vec::new();
+        for index in pending {
+            let (name, variant) = &self.presets[index];
+            match state.store_variant(id, variant).await {
+                Ok(()) => {
+                    errors.remove(name);
+                }
+                // removed in the meantime
+                Err(ServiceError::NotFound) => {
+                    self.lock_jobs()?.remove(&id);
+                    return Ok(());
+                }
+                Err(err) => {
+                    if retryable(&err) {
+                        retry.push(index);
+                    }
+                    errors.insert(name.clone(), err.to_string());
+                }
+            }
+        }
+
+        if !retry.is_empty() && attempt < self.max_attempts {
+            if let Some(job) = self.lock_jobs()?.get_mut(&id) {
+                job.pending = retry;
+                job.status.state = JobState::Queued;
+                job.status.errors = errors;
+            }
+            self.retry(id, attempt);
+            return Ok(());
+        }
+
+        // the presets are done, a retry only records the errors
+        if let Some(job) = self.lock_jobs()?.get_mut(&id) {
+            job.pending.clear();
+            job.status.errors = errors.clone();
+        }
+        let mut meta = match state.store.load_meta(id).await {
+            Ok(meta) => meta,
+            Err(StoreError::NotFound) => {
+                self.lock_jobs()?.remove(&id);
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        meta.presets = Some(PresetsState::Done);
+        meta.variant_errors = errors;
+        state.store.update_meta(meta).await?;
+        self.lock_jobs()?.remove(&id);
+
+        Ok(())
+    }
+
+    /// Queues the job again once the delay of the attempt has passed.
+    fn retry(&self, id: Uuid, attempt: usize) {
+        let sender = self.sender.clone();
+        let delay = self.backoff * 2u32.pow(attempt as u32 - 1);
+        actix_rt::spawn(async move {
+            actix_rt::time::delay_for(delay).await;
+            if sender.unbounded_send(id).is_err() {
+                eprintln!("Jobs: worker is gone, retry of {} is dropped!", id);
+            }
+        });
+    }
+
+    /// Retries a job whose processing failed with the same backoff as failing presets.
+    /// After the last attempt the job is dropped, its presets stay pending in the meta
+    /// and are queued again on the next startup.
+    fn failed(&self, id: Uuid) -> Result<(), ServiceError> {
+        let mut jobs = self.lock_jobs()?;
+        let failures = match jobs.get_mut(&id) {
+            Some(job) => {
+                job.failures += 1;
+                job.status.state = JobState::Queued;
+                job.failures
+            }
+            None => return Ok(()),
+        };
+        if failures >= self.max_attempts {
+            eprintln!("Jobs: job of {} is dropped until the next startup!", id);
+            jobs.remove(&id);
+            return Ok(());
+        }
+        drop(jobs);
+
+        self.retry(id, failures);
+        Ok(())
+    }
+}
+
+/// Processes the queued media until all senders of the queue are dropped.
+pub async fn run(queue: web::Data<JobQueue>, state: web::Data<StorageState>, mut receiver: UnboundedReceiver<Uuid>) {
+    while let Some(id) = receiver.next().await {
+        if let Err(err) = queue.process(&state, id).await {
+            eprintln!("Jobs: error processing job of {}: {:?}", id, err);
+            if let Err(err) = queue.failed(id) {
+                eprintln!("Jobs: error retrying job of {}: {:?}", id, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use rupee::domain::meta::PresetsState;
+    use rupee::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use rupee::storage::blob::blocking::BlockingBlobStorage;
+    use rupee::storage::blob::hashing::Hash;
+    use rupee::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use rupee::domain::meta::BlobMeta;
+    use rupee::storage::blob::BlobRef;
+    use rupee::storage::meta::{MetaStorage, MetaStorageError};
+    use rupee::storage::meta::blocking::BlockingMetaStorage;
+    use rupee::storage::variant::VariantCache;
+    use rupee::transcoder::variant::{VariantLimits, VariantParams};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use super::{run, JobQueue, JobState, JobStatus};
+    use uuid::Uuid;
+    use super::super::state::StorageState;
+
+    /// Fails the given number of meta updates recording finished presets, anything else
+    /// is stored in memory.
+    struct FlakyMetaStorage {
+        storage: MemoryMetaStorage,
+        failures: AtomicUsize,
+    }
+
+    impl MetaStorage for FlakyMetaStorage {
+        fn put(&self, meta: BlobMeta, blob_refs: HashMap<String, Box<dyn BlobRef>>) -> Result<(), MetaStorageError> {
+            self.storage.put(meta, blob_refs)
+        }
+
+        fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+            self.storage.get_meta(id)
+        }
+
+        fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+            self.storage.get_blob_refs(id)
+        }
+
+        fn update_blob_refs(&self, id: Uuid, blob_refs: HashMap<String, Box<dyn BlobRef>>) -> Result<bool, MetaStorageError> {
+            self.storage.update_blob_refs(id, blob_refs)
+        }
+
+        fn compare_and_update_blob_refs(
+            &self,
+            id: Uuid,
+            expected: &HashMap<String, Box<dyn BlobRef>>,
+            blob_refs: HashMap<String, Box<dyn BlobRef>>,
+        ) -> Result<bool, MetaStorageError> {
+            self.storage.compare_and_update_blob_refs(id, expected, blob_refs)
+        }
+
+        fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+            let done = meta.presets == Some(PresetsState::Done);
+            if done && self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| failures.checked_sub(1)).is_ok() {
+                return Err(MetaStorageError::PutError);
+            }
+            self.storage.update_meta(id, meta)
+        }
+
+        fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+            self.storage.list(after, limit)
+        }
+
+        fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
+            self.storage.delete(id)
+        }
+    }
+
+    fn queue() -> (JobQueue, UnboundedReceiver<Uuid>) {
+        let thumbnail = VariantParams { width: Some(50), ..Default::default() };
+        JobQueue::new(
+            vec![("thumbnail".to_string(), thumbnail.variant(&VariantLimits::default()).unwrap())],
+            3,
+            Duration::from_millis(1),
+        )
+    }
+
+    /// Waits for the job of the media to finish.
+    async fn finished(queue: &JobQueue, state: &StorageState, id: Uuid) -> JobStatus {
+        let mut status = queue.status(state, id).await.unwrap();
+        for _ in 0..100 {
+            if status.state == JobState::Done || status.state == JobState::Failed {
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+            status = queue.status(state, id).await.unwrap();
+        }
+        status
+    }
+
+    /// Storage state over in-memory blob storage and the given meta storage.
+    fn state(meta_storage: Arc<dyn MetaStorage>) -> web::Data<StorageState> {
+        let blob = MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap();
+        web::Data::new(StorageState::new(
+            "mem".to_string(),
+            Hash::Sha2_256,
+            Arc::new(BlockingBlobStorage::new(Arc::new(blob))),
+            Arc::new(BlockingMetaStorage::new(meta_storage)),
+            VariantCache::new(1024 * 1024, vec![]),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_job_queue() {
+        let state = state(Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap()));
+        let (queue, receiver) = queue();
+        let queue = web::Data::new(queue);
+        actix_rt::spawn(run(queue.clone(), state.clone(), receiver));
+
+        // never queued:
+        let id = state.store.store(vec![0, 42, 0]).await.unwrap().id;
+        assert!(queue.status(&state, id).await.is_err());
+
+        // not an image, the preset fails:
+        queue.enqueue(&state, id).await.unwrap();
+        assert_eq!(queue.status(&state, id).await.unwrap().state, JobState::Queued);
+        assert_eq!(state.store.load_meta(id).await.unwrap().presets, Some(PresetsState::Pending));
+
+        let status = finished(&queue, &state, id).await;
+        assert_eq!(status.state, JobState::Failed);
+        assert!(status.errors.contains_key("thumbnail"));
+        let meta = state.store.load_meta(id).await.unwrap();
+        assert!(meta.variant_errors.contains_key("thumbnail"));
+        assert_eq!(meta.presets, Some(PresetsState::Done));
+    }
+
+    #[actix_rt::test]
+    async fn test_job_queue_resume() {
+        let meta_storage = Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap());
+        let state = state(meta_storage.clone());
+
+        // the job is lost with the queue:
+        let id = state.store.store(vec![0, 42, 0]).await.unwrap().id;
+        queue().0.enqueue(&state, id).await.unwrap();
+        let (queue, receiver) = queue();
+        assert_eq!(queue.status(&state, id).await.unwrap().state, JobState::Queued);
+
+        assert_eq!(queue.resume(meta_storage.as_ref()).unwrap(), 1);
+        let queue = web::Data::new(queue);
+        actix_rt::spawn(run(queue.clone(), state.clone(), receiver));
+        assert_eq!(finished(&queue, &state, id).await.state, JobState::Failed);
+        assert_eq!(queue.resume(meta_storage.as_ref()).unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_job_queue_meta_error() {
+        let meta_storage = Arc::new(FlakyMetaStorage {
+            storage: MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap(),
+            failures: AtomicUsize::new(0),
+        });
+        let state = state(meta_storage.clone());
+        let (queue, receiver) = queue();
+        let queue = web::Data::new(queue);
+        actix_rt::spawn(run(queue.clone(), state.clone(), receiver));
+
+        // recording the result fails once, the job is retried:
+        let id = state.store.store(vec![0, 42, 0]).await.unwrap().id;
+        meta_storage.failures.store(1, Ordering::SeqCst);
+        queue.enqueue(&state, id).await.unwrap();
+        let status = finished(&queue, &state, id).await;
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(state.store.load_meta(id).await.unwrap().presets, Some(PresetsState::Done));
+
+        // failing every attempt, the presets stay pending:
+        let id = state.store.store(vec![0, 42, 0]).await.unwrap().id;
+        meta_storage.failures.store(100, Ordering::SeqCst);
+        queue.enqueue(&state, id).await.unwrap();
+        let status = finished(&queue, &state, id).await;
+        assert_eq!(status.state, JobState::Queued);
+        assert!(queue.lock_jobs().unwrap().get(&id).is_none());
+        meta_storage.failures.store(0, Ordering::SeqCst);
+        assert_eq!(queue.resume(meta_storage.as_ref()).unwrap(), 1);
+    }
+}
@@ -1,5 +1,8 @@
 // Rupee - Rust Image Service
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
+pub mod error;
 pub mod handler;
+pub mod jobs;
 pub mod response;
+pub mod state;
@@ -0,0 +1,29 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+extern crate serde;
+use serde::{Serialize};
+use std::collections::BTreeMap;
+use super::super::jobs::{JobState, JobStatus};
+
+
+#[derive(Serialize)]
+pub struct JobResponse {
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts: Option<usize>,
+    errors: BTreeMap<String, String>,
+}
+
+
+impl From<&JobStatus> for JobResponse {
+    fn from(status: &JobStatus) -> Self {
+        let state = match status.state {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        };
+        Self { state, attempts: status.attempts, errors: status.errors.clone() }
+    }
+}
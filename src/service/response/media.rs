@@ -0,0 +1,22 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+extern crate serde;
+use rupee::domain::meta::{BlobMeta, Checksum};
+use serde::{Serialize};
+use uuid::Uuid;
+
+
+#[derive(Serialize)]
+pub struct MediaResponse {
+    id: Uuid,
+    size: usize,
+    checksum: Option<Checksum>,
+}
+
+
+impl From<&BlobMeta> for MediaResponse {
+    fn from(meta: &BlobMeta) -> Self {
+        Self { id: meta.id, size: meta.size, checksum: meta.checksum.clone() }
+    }
+}
@@ -0,0 +1,110 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+use actix_web::web;
+use rupee::storage::blob::AsyncBlobStorage;
+use rupee::storage::blob::hashing::Hash;
+use rupee::storage::meta::AsyncMetaStorage;
+use rupee::storage::store::MediaStore;
+use rupee::storage::variant::VariantCache;
+use rupee::transcoder::variant::{Variant, VariantLimits};
+use rupee::transcoder::{describe, transcode, MediaDescription};
+use std::collections::HashMap;
+use std::sync::Arc;
+use super::error::ServiceError;
+use uuid::Uuid;
+
+/// Storage instances shared by all workers of the http service.
+pub struct StorageState {
+    pub store: MediaStore,
+    /// Image variants derived from the media in the store.
+    pub variants: VariantCache,
+}
+
+impl StorageState {
+    pub fn new(
+        blob_backend: String,
+        checksum: Hash,
+        blob: Arc<dyn AsyncBlobStorage>,
+        meta: Arc<dyn AsyncMetaStorage>,
+        variants: VariantCache,
+    ) -> Self {
+        Self {
+            store: MediaStore::new(blob_backend, checksum, blob, meta),
+            variants,
+        }
+    }
+
+    /// Transcodes the variant from the original media, returns its content type and
+    /// contents.
+    async fn derive_variant(&self, id: Uuid, variant: &Variant) -> Result<(String, Vec<u8>), ServiceError> {
+        let (meta, buffer) = self.store.load(id).await?;
+        // variants are only derived from originals
+        if meta.source.is_some() {
+            return Err(ServiceError::NotFound);
+        }
+
+        let source = describe(&buffer)?;
+        let target = match variant.format {
+            Some(format) => MediaDescription::Image(format),
+            None => source.clone(),
+        };
+        let content_type = target.mime().to_string();
+        let transcoder = transcode(source, target, variant.transforms.clone())?;
+        let buffer = web::block(move || transcoder.run(&buffer)).await?;
+
+        Ok((content_type, buffer))
+    }
+
+    /// Returns the content type and the contents of the variant of the media, from the
+    /// variant cache if possible. Failing to cache a derived variant is not an error.
+    pub async fn load_variant(&self, id: Uuid, variant: &Variant) -> Result<(String, Vec<u8>), ServiceError> {
+        let variant_id = VariantCache::variant_id(id, &variant.canonical());
+
+        if let Some(buffer) = self.variants.get(&self.store, variant_id).await? {
+            let content_type = match variant.format {
+                Some(format) => format.mime().to_string(),
+                None => describe(&buffer)?.mime().to_string(),
+            };
+            return Ok((content_type, buffer));
+        }
+
+        let (content_type, buffer) = self.derive_variant(id, variant).await?;
+        if let Err(err) = self.variants.put(&self.store, id, variant_id, buffer.clone()).await {
+            eprintln!("Service: error caching variant {}: {:?}", variant_id, err);
+        }
+
+        Ok((content_type, buffer))
+    }
+
+    /// Derives the variant of the media into the variant cache, unless it is cached
+    /// already.
+    pub async fn store_variant(&self, id: Uuid, variant: &Variant) -> Result<(), ServiceError> {
+        let variant_id = VariantCache::variant_id(id, &variant.canonical());
+        if self.variants.contains(variant_id)? {
+            return Ok(());
+        }
+
+        let (_, buffer) = self.derive_variant(id, variant).await?;
+        self.variants.put(&self.store, id, variant_id, buffer).await?;
+
+        Ok(())
+    }
+}
+
+/// Limits of the media uploaded to the http service.
+pub struct UploadState {
+    /// Maximum size of uploaded media in bytes, the upload is streamed so the limit is
+    /// checked as it is received.
+    pub max_size: usize,
+}
+
+/// Image variants offered by the http service.
+pub struct TranscodingState {
+    /// Named variants served at `/media/{id}/{preset}`.
+    pub presets: HashMap<String, Variant>,
+    /// Only the presets are served, variant parameters in the query are rejected.
+    pub strict: bool,
+    /// Limits of the variants requested with parameters in the query.
+    pub limits: VariantLimits,
+}
@@ -6,6 +6,33 @@
 //! This backend stores binary blobs in file buckets, large binary files
 //! containing multiple binary blobs concatenated with external metadata.
 //!
+//! Each blob is framed as a self-describing record, so bucket files can be
+//! scanned and checked without the external blob references:
+//!
+//! ```text
+//! header:  magic "RPB1" | blob uuid (16 bytes) | length (u64 le) | crc32 of the blob (u32 le)
+//!          | crc32 of the preceding header bytes (u32 le)
+//! blob:    <length> bytes
+//! trailer: magic "RPE1" | length (u64 le)
+//! ```
+//!
+//! Blob references point at the blob itself, right after the record header. A scan
+//! skips corrupted records by searching for the next header magic.
+//!
+//! Deleted blobs are recorded in a tombstone log next to each bucket file, the space
+//! is reclaimed by compaction, which rewrites buckets with too much garbage and reports
+//! the relocated blobs. Every rewrite starts a new generation of the bucket
+//! (`<bucket#num>.generation`), blob references carry the generation they were written
+//! in and the tombstone log is kept per generation (`<bucket#num>.<generation>.tombstones`),
+//! so deletes with references of a rewritten bucket never hit a relocated blob. Reads
+//! check the generation and the record header of the blob, references of a rewritten
+//! bucket fail until they are updated with the relocations, which are logged next to the
+//! bucket (`<bucket#num>.relocations`) before it is replaced.
+//!
+//! Writers hold a lock file per bucket (`<bucket#num>.lock`), recording the owner
+//! process and the length of the bucket known to be complete. Locks left behind by
+//! crashed processes are reclaimed in `init`, truncating the unfinished tail.
+//!
 use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
 use crate::{load_fixture};
@@ -21,10 +48,20 @@ use std::io::SeekFrom;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::usize;
 use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
 use typetag::serde;
+use uuid::Uuid;
+
+const RECORD_MAGIC: &[u8; 4] = b"RPB1";
+const RECORD_TRAILER_MAGIC: &[u8; 4] = b"RPE1";
+const RECORD_HEADER_SIZE: usize = 36;
+const RECORD_TRAILER_SIZE: usize = 12;
+
+/// An (offset, size) range of bytes in a bucket file.
+type Range = (usize, usize);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BucketBlobRef {
@@ -34,6 +71,9 @@ pub struct BucketBlobRef {
     pub offset: usize,
     /// The size of the binary blob stored.
     pub size: usize,
+    /// The generation of the bucket file the offset refers to, see `compact`.
+    #[serde(default)]
+    pub generation: u64,
 }
 
 #[typetag::serde]
@@ -44,8 +84,8 @@ impl BlobRef for BucketBlobRef {
 
     fn display(&self) -> String {
         format!(
-            "BucketBlobRef(#{}, offset={}, size={})",
-            self.bucket, self.offset, self.size
+            "BucketBlobRef(#{}, offset={}, size={}, generation={})",
+            self.bucket, self.offset, self.size, self.generation
         )
     }
 
@@ -60,6 +100,237 @@ pub struct BucketBlobStorageConfig {
     pub path: PathBuf,
     /// Maximum bucket size (in bytes).
     pub max_size: u64,
+    /// Ratio of deleted bytes in a bucket file (0.0 - 1.0) above which compaction rewrites it.
+    #[serde(default = "BucketBlobStorageConfig::default_garbage_ratio")]
+    pub garbage_ratio: f64,
+}
+
+impl BucketBlobStorageConfig {
+    fn default_garbage_ratio() -> f64 {
+        0.5
+    }
+}
+
+/// A blob record found by scanning a bucket file.
+#[derive(Clone, Debug)]
+pub struct BucketRecord {
+    /// Identifier of the blob meta the blob was stored with.
+    pub id: Uuid,
+    pub blob_ref: BucketBlobRef,
+}
+
+/// Header preceding every blob in a bucket file.
+struct RecordHeader {
+    id: Uuid,
+    length: u64,
+    checksum: u32,
+}
+
+impl RecordHeader {
+    fn encode(&self) -> [u8; RECORD_HEADER_SIZE] {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0..4].copy_from_slice(RECORD_MAGIC);
+        header[4..20].copy_from_slice(self.id.as_bytes());
+        header[20..28].copy_from_slice(&self.length.to_le_bytes());
+        header[28..32].copy_from_slice(&self.checksum.to_le_bytes());
+        let checksum = RecordHeader::header_checksum(&header);
+        header[32..36].copy_from_slice(&checksum.to_le_bytes());
+        header
+    }
+
+    fn decode(header: &[u8; RECORD_HEADER_SIZE]) -> Option<Self> {
+        if &header[0..4] != RECORD_MAGIC
+            || RecordHeader::header_checksum(header).to_le_bytes() != header[32..36]
+        {
+            return None;
+        }
+        Some(Self {
+            id: Uuid::from_slice(&header[4..20]).ok()?,
+            length: u64::from_le_bytes(header[20..28].try_into().unwrap()),
+            checksum: u32::from_le_bytes(header[28..32].try_into().unwrap()),
+        })
+    }
+
+    fn header_checksum(header: &[u8; RECORD_HEADER_SIZE]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[0..32]);
+        hasher.finalize()
+    }
+
+    fn encode_trailer(&self) -> [u8; RECORD_TRAILER_SIZE] {
+        let mut trailer = [0u8; RECORD_TRAILER_SIZE];
+        trailer[0..4].copy_from_slice(RECORD_TRAILER_MAGIC);
+        trailer[4..12].copy_from_slice(&self.length.to_le_bytes());
+        trailer
+    }
+}
+
+/// A blob that was moved to a new location within its bucket by compaction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BucketRelocation {
+    pub from: BucketBlobRef,
+    pub to: BucketBlobRef,
+}
+
+/// Result of a compaction pass over the bucket directory.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionReport {
+    /// Indices of the bucket files that were rewritten.
+    pub buckets: Vec<usize>,
+    /// Number of bytes reclaimed.
+    pub reclaimed: u64,
+    /// Blobs of the rewritten buckets, their references need to be updated in the meta storage.
+    pub relocations: Vec<BucketRelocation>,
+    /// Relocation logs of the rewritten buckets, removed by `commit`.
+    pub logs: Vec<PathBuf>,
+}
+
+impl CompactionReport {
+    /// Removes the relocation logs of the rewritten buckets, to be called once the references
+    /// of the relocated blobs are updated in the meta storage.
+    pub fn commit(&self) -> Result<(), BlobStorageError> {
+        for log in self.logs.iter() {
+            if log.is_file() {
+                fs::remove_file(log)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the new location of the referenced blob or None if its bucket was not rewritten.
+    pub fn relocate(&self, blob_ref: &BucketBlobRef) -> Option<BucketBlobRef> {
+        self.relocations.iter().find_map(|relocation| {
+            let from = &relocation.from;
+            if from.bucket == blob_ref.bucket
+                && from.generation == blob_ref.generation
+                && blob_ref.offset >= from.offset
+                && blob_ref.offset + blob_ref.size <= from.offset + from.size
+            {
+                Some(BucketBlobRef {
+                    bucket: relocation.to.bucket,
+                    offset: relocation.to.offset + (blob_ref.offset - from.offset),
+                    size: blob_ref.size,
+                    generation: relocation.to.generation,
+                })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Contents of the relocation log of a bucket (`<bucket#num>.relocations`), written before
+/// the compacted copy replaces the bucket file and kept until the blob references are updated.
+#[derive(Debug, Serialize, Deserialize)]
+struct RelocationLog {
+    /// the generation of the bucket file that was rewritten
+    generation: u64,
+    relocations: Vec<BucketRelocation>,
+}
+
+impl RelocationLog {
+    fn read(filename: &Path) -> Result<Option<Self>, BlobStorageError> {
+        if !filename.is_file() {
+            return Ok(None);
+        }
+        serde_json::from_slice(&fs::read(filename)?).map(Some).map_err(|err| {
+            eprintln!("Bucket: invalid relocation log! err={:?} filename={:?}", err, filename);
+            BlobStorageError::ReadStorageError
+        })
+    }
+
+    fn write(&self, filename: &Path) -> Result<(), BlobStorageError> {
+        let encoded = serde_json::to_vec(self).map_err(|_| BlobStorageError::WriteError)?;
+        let temporary = filename.with_extension("relocations.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        Ok(fs::rename(temporary, filename)?)
+    }
+}
+
+/// Contents of a bucket lock file, identifies the owner of the lock.
+#[derive(Debug, Serialize, Deserialize)]
+struct BucketLock {
+    /// process id of the owner
+    pid: u32,
+    /// hostname of the machine the owner runs on
+    hostname: String,
+    /// time the lock was acquired (seconds since the unix epoch)
+    started: u64,
+    /// start time of the owner process (clock ticks since boot, see proc(5)), tells the
+    /// owner apart from a later process reusing its pid
+    #[serde(default)]
+    process_started: Option<u64>,
+    /// length of the bucket file known to contain only completely written blobs
+    committed: u64,
+}
+
+impl BucketLock {
+    fn new(committed: u64) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        Self {
+            pid: std::process::id(),
+            hostname: BucketLock::hostname(),
+            started,
+            process_started: BucketLock::process_started(std::process::id()),
+            committed,
+        }
+    }
+
+    fn read(lockfile: &Path) -> Option<Self> {
+        let contents = fs::read(lockfile).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    fn write(&self, file: &mut File) -> io::Result<()> {
+        let contents = serde_json::to_vec(self)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        file.write_all(&contents)
+    }
+
+    fn hostname() -> String {
+        let mut buffer = [0u8; 256];
+        let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+        if result != 0 {
+            return String::new();
+        }
+        let length = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        String::from_utf8_lossy(&buffer[..length]).into_owned()
+    }
+
+    /// Returns the start time of the process in clock ticks since boot, the 22nd field
+    /// of `/proc/<pid>/stat`.
+    fn process_started(pid: u32) -> Option<u64> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // the command name in the second field may contain spaces and parentheses
+        let fields = &stat[stat.rfind(')')? + 1..];
+        fields.split_whitespace().nth(22 - 3)?.parse().ok()
+    }
+
+    /// Returns true if the owner of the lock is definitely gone, that is it ran on this
+    /// machine and there is no process with its pid or the pid was reused by a process
+    /// with a different start time.
+    fn is_stale(&self) -> bool {
+        if self.hostname != BucketLock::hostname() || self.pid == std::process::id() {
+            return false;
+        }
+
+        let alive = unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        if !alive {
+            return true;
+        }
+
+        match (self.process_started, BucketLock::process_started(self.pid)) {
+            (Some(owner), Some(current)) => owner != current,
+            _ => false,
+        }
+    }
 }
 
 struct BucketFile {
@@ -67,13 +338,128 @@ struct BucketFile {
     path: PathBuf,
     /// the index of the bucket file
     index: usize,
+    /// the generation of the bucket file, incremented by every compaction
+    generation: u64,
     descriptor: File,
+    /// the lock file and its contents, only writable bucket files hold a lock
+    lock: Option<(File, BucketLock)>,
 }
 impl BucketFile {
     fn format_bucket(path: &Path, bucket: usize) -> PathBuf {
         path.join(format!("{:08}", bucket))
     }
 
+    fn format_tombstones(path: &Path, bucket: usize, generation: u64) -> PathBuf {
+        BucketFile::format_bucket(path, bucket).with_extension(format!("{}.tombstones", generation))
+    }
+
+    fn format_generation(path: &Path, bucket: usize) -> PathBuf {
+        BucketFile::format_bucket(path, bucket).with_extension("generation")
+    }
+
+    fn format_relocations(path: &Path, bucket: usize) -> PathBuf {
+        BucketFile::format_bucket(path, bucket).with_extension("relocations")
+    }
+
+    /// Reads the generation of the bucket file, buckets never compacted are at generation 0.
+    fn read_generation(path: &Path, bucket: usize) -> Result<u64, BlobStorageError> {
+        let filename = BucketFile::format_generation(path, bucket);
+        if !filename.is_file() {
+            return Ok(0);
+        }
+        fs::read_to_string(filename)?
+            .trim()
+            .parse()
+            .map_err(|_| BlobStorageError::ReadStorageError)
+    }
+
+    /// Replaces the generation of the bucket file.
+    fn write_generation(path: &Path, bucket: usize, generation: u64) -> Result<(), BlobStorageError> {
+        let filename = BucketFile::format_generation(path, bucket);
+        let temporary = filename.with_extension("generation.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(generation.to_string().as_bytes())?;
+        file.sync_all()?;
+        Ok(fs::rename(temporary, filename)?)
+    }
+
+    /// Returns the indices of all bucket files in the bucket directory, in ascending order.
+    fn list_buckets(path: &Path) -> Result<Vec<usize>, BlobStorageError> {
+        let mut buckets = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            if let Some(name) = name.to_str() {
+                if name.len() == 8 && name.bytes().all(|c| c.is_ascii_digit()) {
+                    buckets.push(name.parse().unwrap());
+                }
+            }
+        }
+        buckets.sort_unstable();
+        Ok(buckets)
+    }
+
+    /// Records the deletion of a blob in the tombstone log of the bucket generation.
+    /// The log is append-only, each entry holds the offset and size of the deleted blob.
+    fn append_tombstone(
+        path: &Path,
+        bucket: usize,
+        generation: u64,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), BlobStorageError> {
+        let mut entry = Vec::with_capacity(16);
+        entry.extend_from_slice(&(offset as u64).to_le_bytes());
+        entry.extend_from_slice(&(size as u64).to_le_bytes());
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(BucketFile::format_tombstones(path, bucket, generation))
+            .map_err(|_| BlobStorageError::DeleteError)?;
+        // a single write of the entry, so concurrent appends don't interleave
+        file.write_all(&entry).map_err(|_| BlobStorageError::DeleteError)
+    }
+
+    /// Reads the tombstone log of the bucket generation, returns the (offset, size) entries
+    /// in the order they were appended.
+    fn read_tombstones(path: &Path, bucket: usize, generation: u64) -> Result<Vec<(usize, usize)>, BlobStorageError> {
+        BucketFile::read_tombstone_log(&BucketFile::format_tombstones(path, bucket, generation))
+    }
+
+    fn read_tombstone_log(filename: &Path) -> Result<Vec<(usize, usize)>, BlobStorageError> {
+        if !filename.is_file() {
+            return Ok(Vec::new());
+        }
+        let log = fs::read(filename)?;
+
+        Ok(log
+            .chunks_exact(16)
+            .map(|entry| {
+                let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize;
+                let size = u64::from_le_bytes(entry[8..16].try_into().unwrap()) as usize;
+                (offset, size)
+            })
+            .collect())
+    }
+
+    /// Returns the deleted (offset, size) ranges of the tombstones sorted and merged, so blobs
+    /// deleted more than once are only counted once.
+    fn merge_tombstones(tombstones: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut ranges = tombstones.to_vec();
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+        for (offset, size) in ranges {
+            match merged.last_mut() {
+                Some(last) if offset <= last.0 + last.1 => {
+                    last.1 = usize::max(last.0 + last.1, offset + size) - last.0;
+                }
+                _ => merged.push((offset, size)),
+            }
+        }
+        merged
+    }
+
     /// Attempts to create and lock a new or existing bucket file.
     /// Returns None if the bucket file is locked or larger than the max bucket size.
     fn new(
@@ -104,55 +490,47 @@ impl BucketFile {
         // if the file cannot be created because it already exists return None
         // NOTE this must be atomic / thread/process-safe
         let lockfile = filename.with_extension("lock");
-        if let Err(err) = OpenOptions::new()
+        match OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
             .open(&lockfile)
         {
-            eprintln!(
-                "Bucket: error gaining the file lock! err={:?} filename={:?}",
-                err, &lockfile
-            );
-            Ok(None)
-        } else {
-            match OpenOptions::new()
-                .write(true)
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(filename)
-            {
-                Ok(file) => Ok(Some(BucketFile {
-                    path: path.to_path_buf(),
-                    index,
-                    descriptor: file,
-                })),
-                Err(x) => Err(BlobStorageError::CreateStorageError(
-                    "Error opening bucket file!",
-                )),
+            Err(err) => {
+                eprintln!(
+                    "Bucket: error gaining the file lock! err={:?} filename={:?}",
+                    err, &lockfile
+                );
+                Ok(None)
+            }
+            Ok(mut lock) => {
+                let opened = OpenOptions::new()
+                    .write(true)
+                    .read(true)
+                    .create(true)
+                    .open(filename)
+                    .and_then(|file| {
+                        let record = BucketLock::new(file.metadata()?.len());
+                        record.write(&mut lock)?;
+                        Ok((file, record))
+                    });
+                let generation = BucketFile::read_generation(path, index);
+                match (opened, generation) {
+                    (Ok((file, record)), Ok(generation)) => Ok(Some(BucketFile {
+                        path: path.to_path_buf(),
+                        index,
+                        generation,
+                        descriptor: file,
+                        lock: Some((lock, record)),
+                    })),
+                    _ => {
+                        let _ = fs::remove_file(&lockfile);
+                        Err(BlobStorageError::CreateStorageError(
+                            "Error opening bucket file!",
+                        ))
+                    }
+                }
             }
-        }
-    }
-
-    fn new_readonly(
-        path: &Path,
-        index: usize,
-    ) -> Result<BucketFile, BlobStorageError> {
-        let filename = BucketFile::format_bucket(path, index);
-        match OpenOptions::new()
-            .write(false)
-            .read(true)
-            .open(filename)
-        {
-            Ok(file) => Ok(BucketFile {
-                path: path.to_path_buf(),
-                index,
-                descriptor: file,
-            }),
-            Err(x) => Err(BlobStorageError::CreateStorageError(
-                "Error opening bucket file!",
-            ))
         }
     }
 
@@ -177,24 +555,23 @@ impl BucketFile {
         }
     }
 
-    /// Check if there are any existing lockfiles in the bucket directory.
+    /// Returns the bucket indices of all existing lockfiles in the bucket directory.
     /// This is indicating either a previous crash of the application or another instance is
     /// running on the same directory.
-    fn find_locks(path: &Path) -> Result<bool, BlobStorageError> {
+    fn find_locks(path: &Path) -> Result<Vec<usize>, BlobStorageError> {
         match fs::read_dir(&path) {
             Ok(files) => {
-                // eprintln!("{:?}", files);
-                for file in files {
-                    // this is silly:
-                    if let Ok(file) = file {
-                        if let Ok(file) = file.file_name().into_string() {
-                            if file.ends_with(".lock") {
-                                return Ok(true);
+                let mut locks = Vec::new();
+                for file in files.flatten() {
+                    if let Ok(file) = file.file_name().into_string() {
+                        if let Some(index) = file.strip_suffix(".lock") {
+                            if let Ok(index) = index.parse() {
+                                locks.push(index);
                             }
                         }
                     }
                 }
-                Ok(false)
+                Ok(locks)
             }
             Err(_) => Err(BlobStorageError::CreateStorageError(
                 "Error finding existing lock files!",
@@ -202,6 +579,182 @@ impl BucketFile {
         }
     }
 
+    /// Reclaims the lock of a bucket left behind by a crashed process.
+    /// Anything written after the last committed length is an unfinished write, complete
+    /// records are kept and the bucket file is truncated after the last one before the
+    /// lock is removed.
+    /// Returns false if the lock is held by a live (or unknown) owner.
+    fn recover_lock(path: &Path, index: usize) -> Result<bool, BlobStorageError> {
+        let filename = BucketFile::format_bucket(path, index);
+        let lockfile = filename.with_extension("lock");
+
+        let lock = match BucketLock::read(&lockfile) {
+            Some(lock) if lock.is_stale() => lock,
+            _ => return Ok(false),
+        };
+        eprintln!("Bucket: reclaiming stale lock file! lock={:?} filename={:?}", lock, &lockfile);
+
+        if filename.is_file() {
+            let file = OpenOptions::new().write(true).open(&filename)?;
+            let length = file.metadata()?.len();
+            if length > lock.committed {
+                // only an invalid range reaching the end of the file is an unfinished write
+                let (_, skipped) = BucketFile::scan(path, index, lock.committed as usize)?;
+                if let Some((valid, _)) = skipped.last().filter(|(offset, size)| (offset + size) as u64 == length) {
+                    eprintln!(
+                        "Bucket: truncating unfinished tail of bucket file! length={} committed={} valid={}",
+                        length, lock.committed, valid
+                    );
+                    file.set_len(*valid as u64)?;
+                    file.sync_all()?;
+                }
+            } else if length < lock.committed {
+                eprintln!(
+                    "Bucket: bucket file is shorter than committed! length={} committed={}",
+                    length, lock.committed
+                );
+            }
+        }
+
+        // a compaction interrupted before the rename leaves its copy behind, the generation
+        // bumped for the copy and its relocation log are rolled back, one interrupted after
+        // the swap leaves its retired tombstone log
+        let compacted = filename.with_extension("compact");
+        if compacted.is_file() {
+            fs::remove_file(compacted)?;
+            let relocations = BucketFile::format_relocations(path, index);
+            if let Some(log) = RelocationLog::read(&relocations)? {
+                if BucketFile::read_generation(path, index)? == log.generation + 1 {
+                    BucketFile::write_generation(path, index, log.generation)?;
+                }
+                fs::remove_file(relocations)?;
+            }
+        }
+        let generation = BucketFile::read_generation(path, index)?;
+        if generation > 0 {
+            let retired = BucketFile::format_tombstones(path, index, generation - 1).with_extension("retired");
+            if retired.is_file() {
+                fs::remove_file(retired)?;
+            }
+        }
+
+        fs::remove_file(lockfile)?;
+        Ok(true)
+    }
+
+    /// Scans the records of the bucket file starting at the given offset.
+    /// Returns the complete records with a valid checksum and the (offset, size) ranges of
+    /// the incomplete or corrupted records skipped, the scan resumes at the next header magic.
+    fn scan(
+        path: &Path,
+        index: usize,
+        offset: usize,
+    ) -> Result<(Vec<BucketRecord>, Vec<Range>), BlobStorageError> {
+        let file = File::open(BucketFile::format_bucket(path, index))?;
+        let length = file.metadata()?.len() as usize;
+        let generation = BucketFile::read_generation(path, index)?;
+        let mut reader = io::BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset as u64))?;
+
+        let mut records = Vec::new();
+        let mut skipped: Vec<Range> = Vec::new();
+        let mut position = offset;
+        while position < length {
+            if let Some(record) = BucketFile::read_record(&mut reader, position, length)? {
+                let size = record.length as usize;
+                records.push(BucketRecord {
+                    id: record.id,
+                    blob_ref: BucketBlobRef {
+                        bucket: index,
+                        offset: position + RECORD_HEADER_SIZE,
+                        size,
+                        generation,
+                    },
+                });
+                position += RECORD_HEADER_SIZE + size + RECORD_TRAILER_SIZE;
+                continue;
+            }
+
+            let next = BucketFile::find_magic(&mut reader, position + 1)?.unwrap_or(length);
+            match skipped.last_mut() {
+                Some(last) if last.0 + last.1 == position => last.1 += next - position,
+                _ => skipped.push((position, next - position)),
+            }
+            position = next;
+            reader.seek(SeekFrom::Start(position as u64))?;
+        }
+
+        Ok((records, skipped))
+    }
+
+    /// Reads the record at the current position of the reader, returns None if it is
+    /// incomplete or corrupted.
+    fn read_record(reader: &mut impl Read, position: usize, length: usize) -> io::Result<Option<RecordHeader>> {
+        if position + RECORD_HEADER_SIZE + RECORD_TRAILER_SIZE > length {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let record = match RecordHeader::decode(&header) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        if position + RECORD_HEADER_SIZE + record.length as usize + RECORD_TRAILER_SIZE > length {
+            return Ok(None);
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut blob = reader.take(record.length);
+        let mut chunk = [0u8; 8192];
+        loop {
+            let read = blob.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+        }
+        let mut trailer = [0u8; RECORD_TRAILER_SIZE];
+        reader.read_exact(&mut trailer)?;
+        if hasher.finalize() != record.checksum || trailer != record.encode_trailer() {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Returns the offset of the next record header magic at or after the given offset.
+    fn find_magic(reader: &mut (impl Read + Seek), from: usize) -> io::Result<Option<usize>> {
+        reader.seek(SeekFrom::Start(from as u64))?;
+        let mut window: Vec<u8> = Vec::new();
+        let mut base = from;
+        let mut chunk = [0u8; 65536];
+        loop {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            window.extend_from_slice(&chunk[..read]);
+            if let Some(found) = window.windows(RECORD_MAGIC.len()).position(|bytes| bytes == RECORD_MAGIC) {
+                return Ok(Some(base + found));
+            }
+            // keep the bytes a magic split across chunks may start with
+            let keep = window.len().min(RECORD_MAGIC.len() - 1);
+            base += window.len() - keep;
+            window.drain(..window.len() - keep);
+        }
+    }
+
+    /// Records the new committed length of the bucket file in the lock file, the owner
+    /// recorded when the lock was acquired is kept.
+    fn commit(&mut self, length: usize) -> Result<(), BlobStorageError> {
+        match self.lock.as_mut() {
+            Some((lock, record)) => {
+                record.committed = length as u64;
+                Ok(record.write(lock)?)
+            }
+            None => Err(BlobStorageError::WriteError),
+        }
+    }
+
     /// Returns the bucket index.
     fn index(&self) -> usize {
         self.index
@@ -220,33 +773,90 @@ impl BucketFile {
         }
     }
 
-    /// Write some new data into the bucket file, returns the offset into the file where the buffer was
-    /// written at.
-    fn write(&mut self, buffer: &[u8]) -> Result<usize, BlobStorageError> {
+    /// Write some new data framed as a record into the bucket file, returns the offset into
+    /// the file where the buffer was written at.
+    fn write(&mut self, id: &Uuid, buffer: &[u8]) -> Result<usize, BlobStorageError> {
         // seek to the end of the file and return the file location
         let offset = self.seek(SeekFrom::End(0))?;
 
         // this should not be needed:
         // self.descriptor.sync_all()?;
 
-        match self.descriptor.write_all(buffer) {
-            Ok(_) => Ok(offset),
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(buffer);
+        let record = RecordHeader {
+            id: *id,
+            length: buffer.len() as u64,
+            checksum: hasher.finalize(),
+        };
+        let result = self.descriptor.write_all(&record.encode())
+            .and_then(|_| self.descriptor.write_all(buffer))
+            .and_then(|_| self.descriptor.write_all(&record.encode_trailer()));
+
+        match result {
+            Ok(_) => {
+                self.commit(offset + RECORD_HEADER_SIZE + buffer.len() + RECORD_TRAILER_SIZE)?;
+                Ok(offset + RECORD_HEADER_SIZE)
+            }
             Err(_) => Err(BlobStorageError::WriteError),
         }
     }
 
-    /// Read a single blob out of the bucket.
-    fn read(&mut self, offset: usize, size: usize) -> Result<Vec<u8>, BlobStorageError> {
-        self.seek(SeekFrom::Start(offset.try_into().unwrap()))?;
+    /// Write the blob read from the reader framed as a record into the bucket file, returns the
+    /// offset into the file where the blob was written at.
+    /// The checksum is computed while the blob is copied and the record header is rewritten
+    /// once it is known. If the reader fails or does not yield exactly `size` bytes, the
+    /// partial record is truncated again.
+    fn write_stream(&mut self, id: &Uuid, reader: &mut dyn Read, size: usize) -> Result<usize, BlobStorageError> {
+        let offset = self.seek(SeekFrom::End(0))?;
 
-        let mut buffer = vec![0; size as usize];
-        self.descriptor.read_exact(&mut buffer)?;
+        let mut record = RecordHeader {
+            id: *id,
+            length: size as u64,
+            checksum: 0,
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written: usize = 0;
+        let result = self.descriptor.write_all(&record.encode()).and_then(|_| {
+            let mut reader = reader.take(size as u64 + 1);
+            let mut chunk = [0u8; 65536];
+            loop {
+                let read = reader.read(&mut chunk)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                hasher.update(&chunk[..read]);
+                self.descriptor.write_all(&chunk[..read])?;
+                written += read;
+            }
+        });
 
-        Ok(buffer)
+        if result.is_err() || written != size {
+            eprintln!("Bucket: error writing blob stream! written={} size={}", written, size);
+            self.descriptor.set_len(offset as u64)?;
+            return Err(BlobStorageError::WriteError);
+        }
+
+        record.checksum = hasher.finalize();
+        let result = self.descriptor.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.descriptor.write_all(&record.encode()))
+            .and_then(|_| self.descriptor.seek(SeekFrom::End(0)))
+            .and_then(|_| self.descriptor.write_all(&record.encode_trailer()));
+
+        match result {
+            Ok(_) => {
+                self.commit(offset + RECORD_HEADER_SIZE + size + RECORD_TRAILER_SIZE)?;
+                Ok(offset + RECORD_HEADER_SIZE)
+            }
+            Err(_) => Err(BlobStorageError::WriteError),
+        }
     }
 }
 impl Drop for BucketFile {
     fn drop(&mut self) {
+        if self.lock.is_none() {
+            return;
+        }
         let lockfile = BucketFile::format_bucket(&self.path, self.index).with_extension("lock");
         //println!("Bucket: release lock file: {:?}", lockfile);
         if let Err(_) = fs::remove_file(lockfile) {
@@ -256,8 +866,8 @@ impl Drop for BucketFile {
 }
 
 pub struct BucketBlobStorage {
-    /// Current bucket file.
-    bucket: Box<BucketFile>,
+    /// Current bucket file, the lock serializes the writers of this instance.
+    bucket: Mutex<Box<BucketFile>>,
     /// Bucket configuration.
     config: Box<BucketBlobStorageConfig>,
 }
@@ -276,27 +886,26 @@ impl BucketBlobStorage {
             }
         }
 
-        // check for any existing lock files
-        match BucketFile::find_locks(&config.path) {
-            Ok(true) => {
+        // check for any existing lock files, reclaim the ones of crashed processes
+        for index in BucketFile::find_locks(&config.path)? {
+            if !BucketFile::recover_lock(&config.path, index)? {
                 eprintln!("Error creating the bucket blob storage backend!");
                 eprintln!(
                     "Found existing lock files in storage directory: {:?}",
                     &config.path
                 );
                 eprintln!("Check existing running instances and delete lock files.");
-                Err(BlobStorageError::CreateStorageError(
+                return Err(BlobStorageError::CreateStorageError(
                     "Error found existing locks in bucket storage directory!",
-                ))
+                ));
             }
-            Ok(false) => Ok(()),
-            Err(err) => Err(err),
         }
+        Ok(())
     }
 
     /// Create a new Bucket Blob Storage instance.
     /// It automatically choses a bucket file to use based on existing files and their sizes.
-    /// This thread is thread-safe but ideally it should be wrapped in a mutex.
+    /// Writes to the current bucket file are serialized, reads open their own file handles.
     pub fn new(config: BucketBlobStorageConfig) -> Result<Self, BlobStorageError> {
         if !config.path.is_dir() {
             return Err(BlobStorageError::CreateStorageError(
@@ -307,17 +916,228 @@ impl BucketBlobStorage {
         // create a new bucket file this instance is going to work with, the bucketfile
         // holds a lock file in the storage path that is deleted when its deconstructed,
         // this way the lock file is bound to the lifetime of the (immutable) bucketfile.
-        let bucket = Box::new(BucketFile::find_available(&config.path, config.max_size)?);
+        let bucket = Mutex::new(Box::new(BucketFile::find_available(&config.path, config.max_size)?));
         let config = Box::new(config);
 
         Ok(Self { bucket, config })
     }
 
-    fn get_current_bucket(&mut self) -> Result<&mut BucketFile, BlobStorageError> {
-        if self.bucket.tell()? >= self.config.max_size as usize {
-            self.bucket = Box::new(BucketFile::find_available(&self.config.path, self.config.max_size)?);
+    /// Scans all bucket files in the storage directory, returns the records of all blobs that
+    /// are not deleted. Incomplete or corrupted records are skipped, this way the bucket
+    /// directory can be used to rebuild the blob references.
+    pub fn scan(config: &BucketBlobStorageConfig) -> Result<Vec<BucketRecord>, BlobStorageError> {
+        let mut records = Vec::new();
+        for index in BucketFile::list_buckets(&config.path)? {
+            let generation = BucketFile::read_generation(&config.path, index)?;
+            let tombstones = BucketFile::merge_tombstones(&BucketFile::read_tombstones(&config.path, index, generation)?);
+            let (bucket_records, skipped) = BucketFile::scan(&config.path, index, 0)?;
+            for (offset, size) in skipped {
+                eprintln!("Bucket: scan skipped invalid records in bucket file @ index={} offset={} size={}", index, offset, size);
+            }
+            records.extend(bucket_records.into_iter().filter(|record| {
+                !tombstones.iter().any(|(offset, size)| {
+                    record.blob_ref.offset >= *offset && record.blob_ref.offset < offset + size
+                })
+            }));
+        }
+        Ok(records)
+    }
+
+    /// Rewrites all bucket files with a ratio of deleted bytes above the configured
+    /// `garbage_ratio`, dropping the deleted blobs.
+    ///
+    /// Buckets locked by a writer (including the current bucket of this instance) are skipped,
+    /// the lock of a bucket is held while it is rewritten. A rewritten bucket starts a new
+    /// generation: deletes recorded while it was copied are carried over into the tombstone
+    /// log of the new generation, deletes with references of the previous generation arriving
+    /// after the rewrite are ignored and leave the blob to the garbage collection.
+    ///
+    /// The returned report lists the new references of all blobs in the rewritten buckets,
+    /// they need to be updated in the meta storage (see `storage::compaction`), until then
+    /// reads with the previous references fail. The relocations are logged next to each
+    /// bucket before it is replaced, a bucket with a log left behind is not rewritten again,
+    /// its logged relocations are reported until the report is committed.
+    pub fn compact(&self) -> Result<CompactionReport, BlobStorageError> {
+        let mut report = CompactionReport::default();
+
+        for index in BucketFile::list_buckets(&self.config.path)? {
+            if self.replay_relocations(index, &mut report)? {
+                continue;
+            }
+            if index == self.lock_bucket()?.index() {
+                continue;
+            }
+
+            // hold the lock of the bucket while it is rewritten, skip it if its in use
+            let mut bucket = match BucketFile::new(&self.config.path, index, u64::MAX)? {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            let generation = bucket.generation;
+
+            // a log of the previous generation only holds deletes of references that were
+            // already stale when it was rewritten
+            if generation > 0 {
+                let stale = BucketFile::format_tombstones(&self.config.path, index, generation - 1);
+                if stale.is_file() {
+                    fs::remove_file(stale)?;
+                }
+            }
+
+            let logged = BucketFile::read_tombstones(&self.config.path, index, generation)?;
+            let tombstones = BucketFile::merge_tombstones(&logged);
+            if tombstones.is_empty() {
+                continue;
+            }
+
+            let filename = BucketFile::format_bucket(&self.config.path, index);
+            let length = fs::metadata(&filename)?.len() as usize;
+            let garbage: usize = tombstones.iter().map(|(_, size)| size).sum();
+            if (garbage as f64) < (length as f64) * self.config.garbage_ratio {
+                continue;
+            }
+
+            // only complete buckets are rewritten, otherwise data after a corrupted record is lost
+            let (records, skipped) = BucketFile::scan(&self.config.path, index, 0)?;
+            if !skipped.is_empty() {
+                eprintln!("Bucket: skip compaction of corrupted bucket file @ index={} skipped={:?}", index, skipped);
+                continue;
+            }
+
+            eprintln!("Bucket: compacting bucket file @ index={} garbage={}/{}", index, garbage, length);
+            let compacted = filename.with_extension("compact");
+            let mut source = File::open(&filename)?;
+            let mut target = File::create(&compacted)?;
+
+            // copy the records of all blobs not deleted
+            let mut relocations = Vec::new();
+            let mut written: usize = 0;
+            for record in records {
+                let from = record.blob_ref;
+                let deleted = tombstones
+                    .iter()
+                    .any(|(offset, size)| from.offset >= *offset && from.offset < offset + size);
+                if deleted {
+                    continue;
+                }
+
+                let start = from.offset - RECORD_HEADER_SIZE;
+                let size = RECORD_HEADER_SIZE + from.size + RECORD_TRAILER_SIZE;
+                source.seek(SeekFrom::Start(start as u64))?;
+                io::copy(&mut (&mut source).take(size as u64), &mut target)?;
+                let to = BucketBlobRef {
+                    bucket: index,
+                    offset: written + RECORD_HEADER_SIZE,
+                    size: from.size,
+                    generation: generation + 1,
+                };
+                relocations.push(BucketRelocation { from, to });
+                written += size;
+            }
+            target.sync_all()?;
+
+            // the relocations are logged before the swap, so they are not lost if the blob
+            // references are not updated, the generation is bumped before the swap as well,
+            // an interruption in between is rolled back by `recover_lock`
+            let logfile = BucketFile::format_relocations(&self.config.path, index);
+            let log = RelocationLog { generation, relocations };
+            log.write(&logfile)?;
+            BucketFile::write_generation(&self.config.path, index, generation + 1)?;
+            fs::rename(&compacted, &filename)?;
+            bucket.commit(written)?;
+            bucket.generation = generation + 1;
+
+            // carry over the deletes recorded since the tombstones were read, the log is moved
+            // aside first so no append gets lost in between
+            let rewritten = CompactionReport { relocations: log.relocations, ..CompactionReport::default() };
+            let tombstones = BucketFile::format_tombstones(&self.config.path, index, generation);
+            let retired = tombstones.with_extension("retired");
+            fs::rename(&tombstones, &retired)?;
+            let late = BucketFile::read_tombstone_log(&retired)?;
+            fs::remove_file(&retired)?;
+            for (offset, size) in late.into_iter().skip(logged.len()) {
+                let deleted = BucketBlobRef { bucket: index, offset, size, generation };
+                if let Some(relocated) = rewritten.relocate(&deleted) {
+                    BucketFile::append_tombstone(&self.config.path, index, relocated.generation, relocated.offset, size)?;
+                }
+            }
+            drop(bucket);
+
+            report.buckets.push(index);
+            report.reclaimed += (length - written) as u64;
+            report.relocations.extend(rewritten.relocations);
+            report.logs.push(logfile);
+        }
+
+        Ok(report)
+    }
+
+    /// Adds the relocations logged by a previous compaction of the bucket to the report.
+    /// Returns false if there is no log of a completed compaction of the bucket.
+    fn replay_relocations(&self, index: usize, report: &mut CompactionReport) -> Result<bool, BlobStorageError> {
+        let filename = BucketFile::format_relocations(&self.config.path, index);
+        let log = match RelocationLog::read(&filename)? {
+            Some(log) => log,
+            None => return Ok(false),
+        };
+        // an unfinished compaction is rolled back by `recover_lock`
+        if BucketFile::format_bucket(&self.config.path, index).with_extension("compact").is_file() {
+            return Ok(true);
+        }
+        if BucketFile::read_generation(&self.config.path, index)? != log.generation + 1 {
+            eprintln!("Bucket: removing stale relocation log! filename={:?} generation={}", &filename, log.generation);
+            fs::remove_file(&filename)?;
+            return Ok(false);
+        }
+
+        eprintln!("Bucket: replaying relocation log of bucket file @ index={}", index);
+        report.buckets.push(index);
+        report.relocations.extend(log.relocations);
+        report.logs.push(filename);
+        Ok(true)
+    }
+
+    /// Opens the bucket file positioned at the referenced blob. The reference needs to be of
+    /// the current generation of the bucket and the record header needs to match the blob meta,
+    /// references of a rewritten bucket are never resolved to another blob.
+    fn open_blob(&self, meta: &BlobMeta, blob_ref: &BucketBlobRef) -> Result<File, BlobStorageError> {
+        if blob_ref.size != meta.size || blob_ref.offset < RECORD_HEADER_SIZE {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        let generation = BucketFile::read_generation(&self.config.path, blob_ref.bucket)?;
+        if blob_ref.generation != generation {
+            eprintln!("Bucket: blob reference of a previous generation! blob_ref={:?} generation={}", blob_ref, generation);
+            return Err(BlobStorageError::ReadStorageError);
+        }
+
+        let mut file = File::open(BucketFile::format_bucket(&self.config.path, blob_ref.bucket))
+            .map_err(|_| BlobStorageError::ReadStorageError)?;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        file.seek(SeekFrom::Start((blob_ref.offset - RECORD_HEADER_SIZE) as u64))?;
+        file.read_exact(&mut header).map_err(|_| BlobStorageError::ReadStorageError)?;
+        match RecordHeader::decode(&header) {
+            Some(record) if record.id == meta.id && record.length == blob_ref.size as u64 => Ok(file),
+            _ => {
+                eprintln!("Bucket: record header does not match the blob! id={} blob_ref={:?}", meta.id, blob_ref);
+                Err(BlobStorageError::ReadStorageError)
+            }
         }
-        Ok(&mut self.bucket)
+    }
+
+    fn lock_bucket(&self) -> Result<MutexGuard<'_, Box<BucketFile>>, BlobStorageError> {
+        self.bucket.lock().map_err(|_| {
+            eprintln!("Bucket: current bucket lock is poisoned!");
+            BlobStorageError::WriteError
+        })
+    }
+
+    /// Locks the current bucket file, switches to a new one once it reached the max size.
+    fn get_current_bucket(&self) -> Result<MutexGuard<'_, Box<BucketFile>>, BlobStorageError> {
+        let mut bucket = self.lock_bucket()?;
+        if bucket.tell()? >= self.config.max_size as usize {
+            *bucket = Box::new(BucketFile::find_available(&self.config.path, self.config.max_size)?);
+        }
+        Ok(bucket)
     }
 }
 
@@ -331,46 +1151,111 @@ impl BlobStorage for BucketBlobStorage {
     ///     bytes or error (of type BlobStorageError)
     fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
         if let Some(blob_ref) = blob_ref.any().downcast_ref::<BucketBlobRef>() {
-            if blob_ref.size != meta.size {
-                Err(BlobStorageError::ReadStorageError)
-            }
-            else {
-                println!("blob_ref.offset => {:?}", blob_ref.offset);
-                println!("blob_ref.size => {:?}", blob_ref.size);
-                match BucketFile::new_readonly(&self.config.path, blob_ref.bucket) {
-                    Ok(mut bucket) => bucket.read(blob_ref.offset, blob_ref.size),
-                    Err(x) => Err(BlobStorageError::ReadStorageError)
-                }
+            let mut file = self.open_blob(meta, blob_ref)?;
+            let mut buffer = vec![0; blob_ref.size];
+            file.read_exact(&mut buffer)?;
+            Ok(buffer)
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    /// Read a range of the blob back from storage, only the range is read from the bucket.
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<BucketBlobRef>() {
+            if offset + len > blob_ref.size {
+                return Err(BlobStorageError::ReadStorageError);
             }
+            let mut file = self.open_blob(meta, blob_ref)?;
+            file.seek(SeekFrom::Current(offset as i64))?;
+            let mut buffer = vec![0; len];
+            file.read_exact(&mut buffer)?;
+            Ok(buffer)
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    /// Returns a reader of the blob, limited to the blob inside of the bucket file.
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<BucketBlobRef>() {
+            let file = self.open_blob(meta, blob_ref)?;
+            Ok(Box::new(file.take(blob_ref.size as u64)))
         } else {
             Err(BlobStorageError::ReadBlobRefMismatch)
         }
     }
 
     fn put(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         buffer: Vec<u8>,
     ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
-        let bucket = self.get_current_bucket()?;
+        let mut bucket = self.get_current_bucket()?;
 
         // store in current bucket
-        let offset = bucket.write(&buffer)?;
+        let offset = bucket.write(&meta.id, &buffer)?;
         println!("offset => {:?}", offset);
         let blob_ref = BucketBlobRef {
             bucket: bucket.index(),
             offset,
             size: buffer.len(),
+            generation: bucket.generation,
         };
         Ok(Box::new(blob_ref))
     }
 
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut bucket = self.get_current_bucket()?;
+
+        let offset = bucket.write_stream(&meta.id, reader, meta.size)?;
+        let blob_ref = BucketBlobRef {
+            bucket: bucket.index(),
+            offset,
+            size: meta.size,
+            generation: bucket.generation,
+        };
+        Ok(Box::new(blob_ref))
+    }
+
+    /// Marks the blob as deleted in the tombstone log of its bucket.
+    /// The space is reclaimed once the bucket gets compacted.
     fn delete(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         blob_ref: &Box<dyn BlobRef>,
     ) -> Result<(), BlobStorageError> {
-        Ok(())
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<BucketBlobRef>() {
+            if blob_ref.size != meta.size
+                || !BucketFile::format_bucket(&self.config.path, blob_ref.bucket).is_file()
+            {
+                Err(BlobStorageError::DeleteError)
+            } else {
+                BucketFile::append_tombstone(
+                    &self.config.path,
+                    blob_ref.bucket,
+                    blob_ref.generation,
+                    blob_ref.offset,
+                    blob_ref.size,
+                )
+            }
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
     }
 }
 
@@ -394,6 +1279,7 @@ mod tests {
         let config = BucketBlobStorageConfig {
             path: dir.path().to_path_buf(),
             max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
         };
 
         BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
@@ -403,7 +1289,7 @@ mod tests {
         for n in 0..15 {
             let config = config.clone();
             let handle = thread::spawn(|| {
-                let mut storage =
+                let storage =
                     BucketBlobStorage::new(config).expect("error creating the blob storage");
                 thread::sleep(Duration::from_millis(100));
 
@@ -428,6 +1314,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_blob_shared_writers() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = Arc::new(BucketBlobStorage::new(config.clone()).expect("error creating the blob storage"));
+
+        // all threads write to the current bucket of the same instance:
+        let threads: Vec<_> = (0..8u8)
+            .map(|n| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    let buffer: Vec<u8> = vec![n; 64 + n as usize];
+                    let meta = BlobMeta::new(buffer.len());
+                    let reference = storage.put(&meta, buffer.to_vec()).expect("put blob in bucket storage failed!");
+                    (meta, reference, buffer)
+                })
+            })
+            .collect();
+
+        for handle in threads {
+            let (meta, reference, buffer) = handle.join().expect("error, expecting to join thread!");
+            assert_eq!(storage.get(&meta, &reference).unwrap(), buffer);
+        }
+        assert_eq!(BucketBlobStorage::scan(&config).unwrap().len(), 8);
+    }
+
     #[test]
     fn test_blob_bucket() {
         // load test fixture files:
@@ -444,9 +1361,10 @@ mod tests {
         let config = BucketBlobStorageConfig {
             path: dir.path().to_path_buf(),
             max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
         };
         BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
-        let mut storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
+        let storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
 
         // put images into bucket:
         let reference_1 = storage.put(&image_1_meta, image_1.to_vec()).expect("put blob failed!");
@@ -465,6 +1383,13 @@ mod tests {
         assert_eq!(image_1, image_1_res);
         assert_eq!(image_2, image_2_res);
         assert_eq!(image_3, image_3_res);
+
+        // read parts of a blob:
+        let range = storage.get_range(&image_2_meta, &reference_2, 0, 8).expect("get blob range failed!");
+        assert_eq!(range, &image_2[..8]);
+        let range = storage.get_range(&image_2_meta, &reference_2, 100, 1000).expect("get blob range failed!");
+        assert_eq!(range, &image_2[100..1100]);
+        assert!(storage.get_range(&image_2_meta, &reference_2, image_2.len(), 1).is_err());
     }
 
     #[test]
@@ -484,9 +1409,10 @@ mod tests {
         let config = BucketBlobStorageConfig {
             path: dir.path().to_path_buf(),
             max_size: 1024,  // small size, each image gets placed in different bucket
+            garbage_ratio: 0.5,
         };
         BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
-        let mut storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
+        let storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
 
         // put images into bucket:
         let reference_1 = storage.put(&image_1_meta, image_1.to_vec()).expect("put blob failed!");
@@ -510,4 +1436,240 @@ mod tests {
         assert_eq!(image_2, image_2_res);
         assert_eq!(image_3, image_3_res);
     }
+
+    #[test]
+    fn test_blob_bucket_compaction() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1000,  // the first three blobs fill the first bucket
+            garbage_ratio: 0.25,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
+
+        let buffers: Vec<Vec<u8>> = (1..=4).map(|n| vec![n as u8; 400]).collect();
+        let metas: Vec<BlobMeta> = buffers.iter().map(|buffer| BlobMeta::new(buffer.len())).collect();
+        let references: Vec<Box<dyn BlobRef>> = buffers
+            .iter()
+            .zip(metas.iter())
+            .map(|(buffer, meta)| storage.put(meta, buffer.to_vec()).expect("put blob failed!"))
+            .collect();
+        let bucket_ref = |n: usize| references[n].any().downcast_ref::<BucketBlobRef>().unwrap().clone();
+        assert_eq!(bucket_ref(2).bucket, 1);
+        assert_eq!(bucket_ref(3).bucket, 2);
+
+        // deleting a blob twice counts once, the current bucket (#2) is never compacted:
+        storage.delete(&metas[1], &references[1]).expect("delete blob failed!");
+        storage.delete(&metas[1], &references[1]).expect("delete blob failed!");
+        storage.delete(&metas[3], &references[3]).expect("delete blob failed!");
+        let report = storage.compact().expect("compaction failed!");
+        assert_eq!(report.buckets, vec![1]);
+        assert_eq!(report.reclaimed, (RECORD_HEADER_SIZE + 400 + RECORD_TRAILER_SIZE) as u64);
+
+        // the first blob stays in place, the third blob moves into the place of the second,
+        // both in the next generation of the bucket:
+        let kept = report.relocate(&bucket_ref(0)).expect("expected blob to be relocated");
+        assert_eq!((kept.offset, kept.generation), (bucket_ref(0).offset, 1));
+        let relocated = report.relocate(&bucket_ref(2)).expect("expected blob to be relocated");
+        assert_eq!((relocated.offset, relocated.generation), (bucket_ref(1).offset, 1));
+        assert!(report.relocate(&bucket_ref(3)).is_none());
+
+        let kept: Box<dyn BlobRef> = Box::new(kept);
+        let relocated: Box<dyn BlobRef> = Box::new(relocated);
+        assert_eq!(storage.get(&metas[0], &kept).expect("get blob failed!"), buffers[0]);
+        assert_eq!(storage.get(&metas[2], &relocated).expect("get blob failed!"), buffers[2]);
+
+        // the previous references are not resolved, not even with the new generation:
+        match storage.get(&metas[0], &references[0]) {
+            Err(BlobStorageError::ReadStorageError) => {}
+            _ => panic!("expected read of a previous generation to fail!"),
+        }
+        let stale: Box<dyn BlobRef> = Box::new(BucketBlobRef { generation: 1, ..bucket_ref(1) });
+        match storage.get_stream(&metas[1], &stale) {
+            Err(BlobStorageError::ReadStorageError) => {}
+            _ => panic!("expected read of a relocated blob to fail!"),
+        }
+
+        // the relocations are reported again until the report is committed:
+        let replayed = storage.compact().expect("compaction failed!");
+        assert_eq!(replayed.buckets, vec![1]);
+        assert_eq!(replayed.relocate(&bucket_ref(2)).map(|blob_ref| blob_ref.offset), Some(bucket_ref(1).offset));
+        replayed.commit().expect("commit failed!");
+
+        // a delete with the stale reference of the second blob doesn't hit the relocated blob:
+        storage.delete(&metas[1], &references[1]).expect("delete blob failed!");
+        let report = storage.compact().expect("compaction failed!");
+        assert!(report.buckets.is_empty());
+        assert_eq!(storage.get(&metas[2], &relocated).expect("get blob failed!"), buffers[2]);
+
+        // a delete of the new generation is compacted again:
+        storage.delete(&metas[2], &relocated).expect("delete blob failed!");
+        let report = storage.compact().expect("compaction failed!");
+        assert_eq!(report.buckets, vec![1]);
+        let kept = report.relocate(kept.any().downcast_ref::<BucketBlobRef>().unwrap()).unwrap();
+        assert_eq!((kept.offset, kept.generation), (bucket_ref(0).offset, 2));
+        assert!(report.relocate(relocated.any().downcast_ref::<BucketBlobRef>().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_blob_bucket_lock_recovery() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new(buffer.len());
+        let storage = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+        let reference = storage.put(&meta, buffer.to_vec()).expect("put blob failed!");
+
+        // simulate a crash of the owner: the lock is left behind
+        std::mem::forget(storage);
+        let filename = BucketFile::format_bucket(&config.path, 1);
+        let lockfile = filename.with_extension("lock");
+
+        // the lock of a live process is respected:
+        assert!(BucketBlobStorage::init(&config).is_err());
+
+        // hand the lock to a process that is gone, with an unfinished write and compaction in the bucket:
+        let mut child = std::process::Command::new("true").spawn().expect("error spawning process");
+        child.wait().expect("error waiting for process");
+        let mut lock = BucketLock::read(&lockfile).expect("expected readable lock file");
+        let committed = lock.committed;
+        lock.pid = child.id();
+        lock.write(&mut File::create(&lockfile).unwrap()).unwrap();
+        OpenOptions::new().append(true).open(&filename).unwrap().write_all(&[1, 2, 3]).unwrap();
+        fs::write(filename.with_extension("compact"), &[1, 2, 3]).unwrap();
+        let relocations = BucketFile::format_relocations(&config.path, 1);
+        RelocationLog { generation: 0, relocations: Vec::new() }.write(&relocations).unwrap();
+        BucketFile::write_generation(&config.path, 1, 1).unwrap();
+
+        BucketBlobStorage::init(&config).expect("expected stale lock to be reclaimed!");
+        assert!(!lockfile.exists());
+        assert_eq!(fs::metadata(&filename).unwrap().len(), committed);
+        assert!(!relocations.exists());
+        assert_eq!(BucketFile::read_generation(&config.path, 1).unwrap(), 0);
+
+        let storage = BucketBlobStorage::new(config).expect("error creating the blob storage");
+        assert_eq!(storage.get(&meta, &reference).expect("get blob failed!"), buffer);
+    }
+
+    #[test]
+    fn test_blob_bucket_lock_owner() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+        let lockfile = BucketFile::format_bucket(&config.path, 1).with_extension("lock");
+        let mut lock = BucketLock::read(&lockfile).expect("expected readable lock file");
+
+        // writes keep the owner recorded when the lock was acquired:
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        storage.put(&BlobMeta::new(5), vec![0, 42, 0, 42, 0]).expect("put blob failed!");
+        let committed = BucketLock::read(&lockfile).expect("expected readable lock file");
+        assert_eq!((committed.started, committed.process_started), (lock.started, lock.process_started));
+        assert!(committed.committed > lock.committed);
+
+        // a live process is only the owner if it started at the recorded time:
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().expect("error spawning process");
+        lock.pid = child.id();
+        lock.process_started = BucketLock::process_started(child.id());
+        assert!(lock.process_started.is_some() && !lock.is_stale());
+        lock.process_started = lock.process_started.map(|started| started + 1);
+        assert!(lock.is_stale());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_blob_bucket_scan() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+
+        let metas: Vec<BlobMeta> = (0..3).map(|_| BlobMeta::new(5)).collect();
+        let references: Vec<Box<dyn BlobRef>> = metas
+            .iter()
+            .map(|meta| storage.put(meta, vec![0, 42, 0, 42, 0]).expect("put blob failed!"))
+            .collect();
+        storage.delete(&metas[0], &references[0]).expect("delete blob failed!");
+
+        let records = BucketBlobStorage::scan(&config).expect("scan failed!");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, metas[1].id);
+        assert_eq!(records[1].id, metas[2].id);
+        assert_eq!(records[1].blob_ref.offset, references[2].any().downcast_ref::<BucketBlobRef>().unwrap().offset);
+
+        // corrupt the last blob, the scan stops in front of it:
+        let filename = BucketFile::format_bucket(&config.path, 1);
+        let mut contents = fs::read(&filename).unwrap();
+        let last = records[1].blob_ref.offset;
+        contents[last] = 1;
+        fs::write(&filename, contents).unwrap();
+
+        let records = BucketBlobStorage::scan(&config).expect("scan failed!");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, metas[1].id);
+
+        // corrupt the length in the header of the first blob, the scan resumes at the second:
+        let mut contents = fs::read(&filename).unwrap();
+        contents[20] = 0xff;
+        fs::write(&filename, contents).unwrap();
+
+        let (records, skipped) = BucketFile::scan(&config.path, 1, 0).expect("scan failed!");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, metas[1].id);
+        let record_size = RECORD_HEADER_SIZE + 5 + RECORD_TRAILER_SIZE;
+        assert_eq!(skipped, vec![(0, record_size), (2 * record_size, record_size)]);
+    }
+
+    #[test]
+    fn test_blob_bucket_stream() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+
+        let image_1 = load_fixture(Path::new("images").join("rgb.jpeg"));
+        let image_1_meta = BlobMeta::new(image_1.len());
+        let image_2 = load_fixture(Path::new("images").join("rgb.png"));
+        let image_2_meta = BlobMeta::new(image_2.len());
+
+        let reference_1 = storage.put_stream(&image_1_meta, &mut &image_1[..]).expect("put blob failed!");
+
+        // a short stream is rolled back without leaving a record behind:
+        let length = fs::metadata(BucketFile::format_bucket(&config.path, 1)).unwrap().len();
+        assert!(storage.put_stream(&image_2_meta, &mut &image_2[..100]).is_err());
+        assert_eq!(fs::metadata(BucketFile::format_bucket(&config.path, 1)).unwrap().len(), length);
+
+        let reference_2 = storage.put_stream(&image_2_meta, &mut &image_2[..]).expect("put blob failed!");
+
+        let mut image_1_res = Vec::new();
+        storage.get_stream(&image_1_meta, &reference_1).expect("get blob failed!")
+            .read_to_end(&mut image_1_res).unwrap();
+        assert_eq!(image_1, image_1_res);
+        assert_eq!(image_2, storage.get(&image_2_meta, &reference_2).expect("get blob failed!"));
+
+        // the streamed records are framed like any other:
+        let records = BucketBlobStorage::scan(&config).expect("scan failed!");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].id, image_2_meta.id);
+    }
 }
@@ -0,0 +1,329 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Filesystem Blob Storage Backend Implementation
+//!
+//! This backend stores each binary blob as its own file, so blobs are individually
+//! visible to backup tools, rsync and the like.
+//!
+//! Blob files are named after the blob uuid and fanned out into a directory tree
+//! by the leading hex digits of the uuid, to keep the number of files per directory
+//! small, e.g. with the default fan out of 2 levels:
+//!
+//! ```text
+//! <path>/9f/2c/9f2c7e1a-53d4-4b8e-a0c1-0d6e1f3b2a77
+//! ```
+//!
+//! Blobs are written to a temporary file next to their destination first and
+//! renamed into place once complete, readers never see partially written blobs.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use typetag::serde;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FsBlobRef {
+    /// Path of the blob file, relative to the storage directory.
+    pub path: PathBuf,
+    pub size: usize,
+}
+
+#[typetag::serde]
+impl BlobRef for FsBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("FsBlobRef({}, {})", self.path.display(), self.size)
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsBlobStorageConfig {
+    /// The storage directory.
+    pub path: PathBuf,
+    /// Number of directory levels the blob files are fanned out into, each level is
+    /// named after the next two hex digits of the blob uuid.
+    #[serde(default = "FsBlobStorageConfig::default_fan_out")]
+    pub fan_out: usize,
+}
+
+impl FsBlobStorageConfig {
+    fn default_fan_out() -> usize {
+        2
+    }
+}
+
+/// A blob file found in the storage directory.
+#[derive(Debug, Clone)]
+pub struct FsRecord {
+    /// Identifier of the blob meta the blob was stored with.
+    pub id: Uuid,
+    pub blob_ref: FsBlobRef,
+}
+
+pub struct FsBlobStorage {
+    config: FsBlobStorageConfig,
+}
+
+impl FsBlobStorage {
+    /// Creates the storage directory if it does not exist yet.
+    pub fn init(config: &FsBlobStorageConfig) -> Result<(), BlobStorageError> {
+        if !config.path.is_dir() {
+            if let Err(_) = fs::create_dir_all(&config.path) {
+                return Err(BlobStorageError::CreateStorageError(
+                    "Error creating the blob storage directory!",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new(config: FsBlobStorageConfig) -> Result<Self, BlobStorageError> {
+        if !config.path.is_dir() {
+            return Err(BlobStorageError::CreateStorageError(
+                "Storage directory not found!",
+            ));
+        }
+        // at most 16 levels, the uuid has 32 hex digits
+        if config.fan_out > 16 {
+            return Err(BlobStorageError::StorageConfigError);
+        }
+        Ok(Self { config })
+    }
+
+    /// Lists all blob files in the storage directory, files not named after a
+    /// blob uuid (like left over temporary files) are skipped.
+    pub fn scan(config: &FsBlobStorageConfig) -> Result<Vec<FsRecord>, BlobStorageError> {
+        let mut records = Vec::new();
+        let mut directories = vec![(PathBuf::new(), 0)];
+        while let Some((directory, level)) = directories.pop() {
+            for entry in fs::read_dir(config.path.join(&directory))? {
+                let entry = entry?;
+                let path = directory.join(entry.file_name());
+                let file_type = entry.file_type()?;
+                if level < config.fan_out && file_type.is_dir() {
+                    directories.push((path, level + 1));
+                } else if level == config.fan_out && file_type.is_file() {
+                    let id = match entry.file_name().to_str().map(Uuid::parse_str) {
+                        Some(Ok(id)) => id,
+                        _ => continue,
+                    };
+                    let size = entry.metadata()?.len() as usize;
+                    records.push(FsRecord { id, blob_ref: FsBlobRef { path, size } });
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Returns the path of the blob file relative to the storage directory.
+    fn format_blob(&self, meta: &BlobMeta) -> PathBuf {
+        let name = meta.id.to_simple().to_string();
+        let mut path = PathBuf::new();
+        for level in 0..self.config.fan_out {
+            path.push(&name[level * 2..level * 2 + 2]);
+        }
+        path.push(meta.id.to_hyphenated().to_string());
+        path
+    }
+
+    /// Opens the blob file of the reference, checking it belongs to the blob meta.
+    fn open(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<(File, usize), BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<FsBlobRef>() {
+            if blob_ref.size != meta.size {
+                return Err(BlobStorageError::ReadStorageError);
+            }
+            match File::open(self.config.path.join(&blob_ref.path)) {
+                Ok(file) => Ok((file, blob_ref.size)),
+                Err(_) => Err(BlobStorageError::ReadStorageError),
+            }
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    /// Writes the blob read from the reader to a temporary file and renames it into place.
+    /// Fails if the reader does not yield exactly the size of the blob meta.
+    fn write(&self, meta: &BlobMeta, reader: &mut dyn Read) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let path = self.format_blob(meta);
+        let filename = self.config.path.join(&path);
+        let directory = filename.parent().unwrap_or(&self.config.path);
+        fs::create_dir_all(directory)?;
+
+        // the temporary file is removed again when dropped without being persisted
+        let mut file = NamedTempFile::new_in(directory)?;
+        let written = io::copy(&mut reader.take(meta.size as u64 + 1), &mut file)?;
+        if written != meta.size as u64 {
+            eprintln!("Fs: error writing blob! written={} size={}", written, meta.size);
+            return Err(BlobStorageError::WriteError);
+        }
+        file.as_file().sync_all()?;
+
+        if let Err(err) = file.persist_noclobber(&filename) {
+            eprintln!("Fs: error moving blob file into place! err={:?} filename={:?}", err.error, &filename);
+            return Err(BlobStorageError::PutError);
+        }
+
+        Ok(Box::new(FsBlobRef { path, size: meta.size }))
+    }
+}
+
+impl BlobStorage for FsBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        let (mut file, size) = self.open(meta, blob_ref)?;
+        let mut buffer = Vec::with_capacity(size);
+        file.read_to_end(&mut buffer)?;
+        if buffer.len() != size {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        Ok(buffer)
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        let (mut file, size) = self.open(meta, blob_ref)?;
+        if offset + len > size {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut buffer = vec![0; len];
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        let (file, size) = self.open(meta, blob_ref)?;
+        Ok(Box::new(file.take(size as u64)))
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.write(meta, &mut &buffer[..])
+    }
+
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.write(meta, reader)
+    }
+
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<FsBlobRef>() {
+            if blob_ref.size != meta.size {
+                return Err(BlobStorageError::DeleteError);
+            }
+            fs::remove_file(self.config.path.join(&blob_ref.path)).map_err(|err| {
+                eprintln!("Fs: error removing blob file! err={:?} path={:?}", err, &blob_ref.path);
+                BlobStorageError::DeleteError
+            })
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::{load_fixture};
+    use crate::storage::blob::BlobStorage;
+    use crate::storage::blob::backend::fs::{FsBlobRef, FsBlobStorage, FsBlobStorageConfig};
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_blob_fs() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = FsBlobStorageConfig {
+            path: dir.path().join("blobs"),
+            fan_out: 2,
+        };
+        FsBlobStorage::init(&config).expect("Error in init of fs storage!");
+        let storage = FsBlobStorage::new(config.clone()).expect("error creating the blob storage");
+
+        let image_1 = load_fixture(Path::new("images").join("rgb.jpeg"));
+        let image_1_meta = BlobMeta::new(image_1.len());
+        let image_2 = load_fixture(Path::new("images").join("rgb.png"));
+        let image_2_meta = BlobMeta::new(image_2.len());
+
+        let reference_1 = storage.put(&image_1_meta, image_1.to_vec()).expect("put blob failed!");
+        let reference_2 = storage.put_stream(&image_2_meta, &mut &image_2[..]).expect("put blob failed!");
+
+        // each blob is a file in the fanned out directory tree:
+        let blob_ref = reference_1.any().downcast_ref::<FsBlobRef>().unwrap();
+        let name = image_1_meta.id.to_simple().to_string();
+        assert_eq!(
+            blob_ref.path,
+            Path::new(&name[0..2]).join(&name[2..4]).join(image_1_meta.id.to_hyphenated().to_string())
+        );
+        assert_eq!(fs::read(config.path.join(&blob_ref.path)).unwrap(), image_1);
+
+        assert_eq!(storage.get(&image_1_meta, &reference_1).expect("get blob failed!"), image_1);
+        assert_eq!(
+            storage.get_range(&image_2_meta, &reference_2, 10, 20).expect("get blob range failed!"),
+            &image_2[10..30]
+        );
+        let mut image_2_res = Vec::new();
+        storage.get_stream(&image_2_meta, &reference_2).expect("get blob failed!")
+            .read_to_end(&mut image_2_res).unwrap();
+        assert_eq!(image_2_res, image_2);
+
+        // short streams leave nothing behind:
+        let image_3_meta = BlobMeta::new(image_2.len());
+        assert!(storage.put_stream(&image_3_meta, &mut &image_2[..100]).is_err());
+        let filename = config.path.join(storage.format_blob(&image_3_meta));
+        assert!(!filename.exists());
+        assert!(fs::read_dir(filename.parent().unwrap())
+            .unwrap()
+            .flatten()
+            .all(|entry| !entry.file_name().to_string_lossy().starts_with(".tmp")));
+
+        // the scan finds the blob files of both blobs:
+        let mut records = FsBlobStorage::scan(&config).expect("scan failed!");
+        records.sort_by_key(|record| record.id != image_1_meta.id);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, image_1_meta.id);
+        assert_eq!(records[0].blob_ref.path, blob_ref.path);
+        assert_eq!(records[0].blob_ref.size, image_1.len());
+
+        storage.delete(&image_1_meta, &reference_1).expect("delete blob failed!");
+        assert!(storage.get(&image_1_meta, &reference_1).is_err());
+        assert!(storage.delete(&image_1_meta, &reference_1).is_err());
+    }
+}
@@ -10,7 +10,8 @@ use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::usize;
 use serde::{Serialize, Deserialize};
 use typetag::serde;
@@ -36,11 +37,11 @@ impl BlobRef for MemoryBlobRef {
 }
 
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct MemoryBlobStorageConfig {}
 
 pub struct MemoryBlobStorage {
-    store: Vec<Vec<u8>>,
+    store: RwLock<Vec<Vec<u8>>>,
 }
 
 impl MemoryBlobStorage {
@@ -49,7 +50,11 @@ impl MemoryBlobStorage {
     }
 
     pub fn new(config: MemoryBlobStorageConfig) -> Result<Self, BlobStorageError> {
-        Ok(Self { store: Vec::new() })
+        Ok(Self { store: RwLock::new(Vec::new()) })
+    }
+
+    fn read_store(&self) -> Result<RwLockReadGuard<'_, Vec<Vec<u8>>>, BlobStorageError> {
+        self.store.read().map_err(|_| BlobStorageError::ReadStorageError)
     }
 }
 
@@ -57,29 +62,69 @@ impl BlobStorage for MemoryBlobStorage {
     fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
         if let Some(blob_ref) = blob_ref.any().downcast_ref::<MemoryBlobRef>() {
             let index = blob_ref.index;
-            if index >= self.store.len() {
+            let store = self.read_store()?;
+            if index >= store.len() {
                 Err(BlobStorageError::ReadStorageError)
             } else {
-                Ok(self.store[index].clone())
+                Ok(store[index].clone())
+            }
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<MemoryBlobRef>() {
+            match self.read_store()?.get(blob_ref.index) {
+                Some(blob) if offset + len <= blob.len() => Ok(blob[offset..offset + len].to_vec()),
+                _ => Err(BlobStorageError::ReadStorageError),
             }
         } else {
             Err(BlobStorageError::ReadBlobRefMismatch)
         }
     }
 
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        Ok(Box::new(Cursor::new(self.get(meta, blob_ref)?)))
+    }
+
     fn put(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         buffer: Vec<u8>,
     ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
-        let index: usize = self.store.len();
+        let mut store = self.store.write().map_err(|_| BlobStorageError::PutError)?;
+        let index: usize = store.len();
         let blob_ref = MemoryBlobRef { index };
-        self.store.push(buffer);
+        store.push(buffer);
         Ok(Box::new(blob_ref))
     }
 
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut buffer = Vec::with_capacity(meta.size);
+        reader.take(meta.size as u64 + 1).read_to_end(&mut buffer)?;
+        if buffer.len() != meta.size {
+            return Err(BlobStorageError::PutError);
+        }
+        self.put(meta, buffer)
+    }
+
     fn delete(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         blob_ref: &Box<dyn BlobRef>,
     ) -> Result<(), BlobStorageError> {
@@ -93,11 +138,12 @@ mod tests {
     use crate::domain::meta::BlobMeta;
     use crate::{load_fixture};
     use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use std::io::Read;
     use std::path::{Path, PathBuf};
 
     #[test]
     fn test_blob_mem() {
-        let mut storage = MemoryBlobStorage::new(MemoryBlobStorageConfig {})
+        let storage = MemoryBlobStorage::new(MemoryBlobStorageConfig {})
             .expect("mem blob backend can't be created!");
 
         let buffer = load_fixture(Path::new("images").join("rgb.jpeg"));
@@ -119,5 +165,35 @@ mod tests {
 
         assert_eq!(buf.len(), buffer.len());
         assert_eq!(buf, buffer);
+
+        let range = storage
+            .get_range(&meta, &reference, 42, 100)
+            .expect("getting blob range from the mem storage failed!");
+        assert_eq!(range, &buffer[42..142]);
+        assert!(storage.get_range(&meta, &reference, buffer.len() - 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_blob_mem_stream() {
+        let storage = MemoryBlobStorage::new(MemoryBlobStorageConfig {})
+            .expect("mem blob backend can't be created!");
+
+        let buffer = load_fixture(Path::new("images").join("rgb.jpeg"));
+        let meta = BlobMeta::new(buffer.len());
+
+        let reference = storage
+            .put_stream(&meta, &mut &buffer[..])
+            .expect("put blob in mem storage failed!");
+
+        let mut buf = Vec::new();
+        storage
+            .get_stream(&meta, &reference)
+            .expect("getting blob from the mem storage failed!")
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, buffer);
+
+        // the stream needs to match the size of the meta:
+        assert!(storage.put_stream(&meta, &mut &buffer[1..]).is_err());
     }
 }
@@ -2,6 +2,7 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 pub mod bucket;
+pub mod fs;
 pub mod mem;
-// file
+pub mod s3;
 // lmdb
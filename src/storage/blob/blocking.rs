@@ -0,0 +1,250 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Blocking Pool Adapter
+//!
+//! Implements `AsyncBlobStorage` for any `BlobStorage`, every call runs on the
+//! blocking thread pool, so file and network I/O of the backends never stalls the
+//! executor of the http service.
+//!
+//! Streams are passed in chunks through a bounded channel between the executor and a
+//! thread of the pool, which reads or writes the blob with blocking I/O. At most
+//! `BUFFERED_CHUNKS` chunks of a blob are held in memory.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::{AsyncBlobStorage, BlobRef, BlobStorage, BlobStorageError, BlobStream};
+use actix_threadpool::BlockingError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::mpsc::{channel, Receiver};
+use futures::executor::block_on;
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Size of the chunks read from the blob storage.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered between the executor and the blocking thread pool.
+const BUFFERED_CHUNKS: usize = 4;
+
+/// Runs the storage operation on the blocking thread pool.
+async fn run<F, T>(operation: F) -> Result<T, BlobStorageError>
+where
+    F: FnOnce() -> Result<T, BlobStorageError> + Send + 'static,
+    T: Send + 'static,
+{
+    actix_threadpool::run(operation).await.map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => {
+            eprintln!("Blocking: blob storage operation was canceled!");
+            BlobStorageError::IOError
+        }
+    })
+}
+
+/// Streams the reader in chunks, it is read on the blocking thread pool as the stream is
+/// consumed. Dropping the stream stops the reading.
+pub fn read_stream(mut reader: Box<dyn Read + Send>) -> BlobStream {
+    let (mut sender, receiver) = channel(BUFFERED_CHUNKS);
+    actix_rt::spawn(async move {
+        let pump = run(move || {
+            loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let item = match reader.read(&mut chunk) {
+                    Ok(0) => return Ok(()),
+                    Ok(read) => {
+                        chunk.truncate(read);
+                        Ok(Bytes::from(chunk))
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        eprintln!("Blocking: error reading blob stream: {:?}", err);
+                        Err(BlobStorageError::from(err))
+                    }
+                };
+                let failed = item.is_err();
+                // the stream was dropped, or the error ends it
+                if block_on(sender.send(item)).is_err() || failed {
+                    return Ok(());
+                }
+            }
+        });
+        if let Err(err) = pump.await {
+            eprintln!("Blocking: error streaming blob: {:?}", err);
+        }
+    });
+    Box::pin(receiver)
+}
+
+/// Reader of the chunks sent by the executor, blocks until the next chunk arrives.
+struct ChunkReader {
+    receiver: Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match block_on(self.receiver.next()) {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        let rest = self.chunk.split_off(len);
+        buf[..len].copy_from_slice(&self.chunk);
+        self.chunk = rest;
+        Ok(len)
+    }
+}
+
+/// Passes the chunks of the stream to the consumer as a reader, the consumer runs on the
+/// blocking thread pool. An error of the stream is returned instead of the result of the
+/// consumer.
+pub async fn consume_stream<F, T>(mut stream: BlobStream, consumer: F) -> Result<T, BlobStorageError>
+where
+    F: FnOnce(&mut dyn Read) -> Result<T, BlobStorageError> + Send + 'static,
+    T: Send + 'static,
+{
+    let (mut sender, receiver) = channel(BUFFERED_CHUNKS);
+    let consume = run(move || consumer(&mut ChunkReader { receiver, chunk: Bytes::new() }));
+    let forward = async move {
+        while let Some(chunk) = stream.next().await {
+            let (chunk, failed) = match chunk {
+                Ok(chunk) => (Ok(chunk), None),
+                Err(err) => (Err(io::Error::new(io::ErrorKind::BrokenPipe, "error in blob stream")), Some(err)),
+            };
+            // the consumer stopped reading, its error is returned
+            if sender.send(chunk).await.is_err() {
+                return Ok(());
+            }
+            if let Some(err) = failed {
+                return Err(err);
+            }
+        }
+        Ok(())
+    };
+
+    let (result, forwarded) = futures::join!(consume, forward);
+    forwarded?;
+    result
+}
+
+pub struct BlockingBlobStorage {
+    storage: Arc<dyn BlobStorage>,
+}
+
+impl BlockingBlobStorage {
+    pub fn new(storage: Arc<dyn BlobStorage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl AsyncBlobStorage for BlockingBlobStorage {
+    async fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        let (storage, meta, blob_ref) = (self.storage.clone(), meta.clone(), blob_ref.clone());
+        run(move || storage.get(&meta, &blob_ref)).await
+    }
+
+    async fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        let (storage, meta, blob_ref) = (self.storage.clone(), meta.clone(), blob_ref.clone());
+        run(move || storage.get_range(&meta, &blob_ref, offset, len)).await
+    }
+
+    async fn get_stream(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<BlobStream, BlobStorageError> {
+        let (storage, meta, blob_ref) = (self.storage.clone(), meta.clone(), blob_ref.clone());
+        let reader = run(move || storage.get_stream(&meta, &blob_ref)).await?;
+        Ok(read_stream(reader))
+    }
+
+    async fn put(&self, meta: &BlobMeta, buffer: Vec<u8>) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let (storage, meta) = (self.storage.clone(), meta.clone());
+        run(move || storage.put(&meta, buffer)).await
+    }
+
+    async fn put_stream(&self, meta: &BlobMeta, stream: BlobStream) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let (storage, meta) = (self.storage.clone(), meta.clone());
+        consume_stream(stream, move |reader| storage.put_stream(&meta, reader)).await
+    }
+
+    async fn delete(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<(), BlobStorageError> {
+        let (storage, meta, blob_ref) = (self.storage.clone(), meta.clone(), blob_ref.clone());
+        run(move || storage.delete(&meta, &blob_ref)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::{AsyncBlobStorage, BlobStream};
+    use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::blocking::BlockingBlobStorage;
+    use bytes::Bytes;
+    use futures::{stream, StreamExt, TryStreamExt};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[actix_rt::test]
+    async fn test_blocking_blob_storage() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BlockingBlobStorage::new(Arc::new(
+            BucketBlobStorage::new(config.clone()).expect("error creating the blob storage"),
+        ));
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new(buffer.len());
+        let reference = storage.put(&meta, buffer.to_vec()).await.expect("put failed!");
+        assert_eq!(storage.get(&meta, &reference).await.unwrap(), buffer);
+        assert_eq!(storage.get_range(&meta, &reference, 1, 3).await.unwrap(), &buffer[1..4]);
+
+        storage.delete(&meta, &reference).await.expect("delete failed!");
+        assert!(BucketBlobStorage::scan(&config).unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_blocking_blob_storage_stream() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BlockingBlobStorage::new(Arc::new(
+            BucketBlobStorage::new(config).expect("error creating the blob storage"),
+        ));
+
+        // spans several chunks:
+        let buffer: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        let meta = BlobMeta::new(buffer.len());
+        let chunks: Vec<_> = buffer.chunks(50 * 1024).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        let reference = storage.put_stream(&meta, Box::pin(stream::iter(chunks))).await.expect("put failed!");
+
+        let chunks: Vec<Bytes> = storage.get_stream(&meta, &reference).await.unwrap().try_collect().await.unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), buffer);
+
+        // shorter than the size of the meta:
+        let short: BlobStream = Box::pin(stream::iter(vec![Ok(Bytes::from(buffer.clone()))]));
+        assert!(storage.put_stream(&BlobMeta::new(buffer.len() + 1), short).await.is_err());
+
+        // dropped before it is read to the end:
+        let mut partial = storage.get_stream(&meta, &reference).await.unwrap();
+        assert!(partial.next().await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,246 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Checksum Verification
+//!
+//! Wraps any blob storage backend and verifies the blob contents against the
+//! checksum of the blob meta, on put and on get. Blobs without a checksum in
+//! their meta data are passed through unchecked.
+//!
+//! Ranges of a blob are passed through unchecked, the checksum covers the whole blob.
+//!
+//! Streams are hashed incrementally while they pass through, a streamed read
+//! fails with an `InvalidData` error at the end of a corrupted blob.
+//!
+use crate::domain::meta::{BlobMeta, Checksum};
+use crate::storage::blob::hashing::{Hash, HashingReader};
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use std::io::{self, Read};
+use std::str::FromStr;
+
+pub struct ChecksumBlobStorage {
+    /// The wrapped storage holding the blob contents.
+    storage: Box<dyn BlobStorage>,
+}
+
+impl ChecksumBlobStorage {
+    pub fn new(storage: Box<dyn BlobStorage>) -> Self {
+        Self { storage }
+    }
+
+    fn verify(meta: &BlobMeta, buffer: &[u8]) -> Result<(), BlobStorageError> {
+        match meta.checksum {
+            Some(ref checksum) => match checksum.verify(buffer) {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    eprintln!("Checksum: blob contents of {} do not match {:?}", meta, checksum);
+                    Err(BlobStorageError::IntegrityError)
+                }
+                Err(_) => {
+                    eprintln!("Checksum: unknown checksum algorithm of {}: {:?}", meta, checksum);
+                    Err(BlobStorageError::IntegrityError)
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the hash algorithm of the checksum of the blob meta, if it has one.
+    fn checksum_hash(meta: &BlobMeta) -> Result<Option<(Hash, &Checksum)>, BlobStorageError> {
+        match meta.checksum {
+            Some(ref checksum) => match Hash::from_str(&checksum.algorithm) {
+                Ok(hash) => Ok(Some((hash, checksum))),
+                Err(_) => {
+                    eprintln!("Checksum: unknown checksum algorithm of {}: {:?}", meta, checksum);
+                    Err(BlobStorageError::IntegrityError)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reader verifying the checksum once the end of the blob is reached.
+struct ChecksumReader {
+    reader: Option<HashingReader<Box<dyn Read + Send>>>,
+    hash: Hash,
+    checksum: Checksum,
+}
+
+impl Read for ChecksumReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match self.reader.as_mut() {
+            Some(reader) => reader.read(buf)?,
+            None => return Ok(0),
+        };
+        if read == 0 && !buf.is_empty() {
+            let digest = self.reader.take().unwrap().finalize();
+            if Checksum::from_digest(&self.hash, &digest) != self.checksum {
+                eprintln!("Checksum: blob stream does not match {:?}", self.checksum);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "blob checksum mismatch"));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl BlobStorage for ChecksumBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        let buffer = self.storage.get(meta, blob_ref)?;
+        ChecksumBlobStorage::verify(meta, &buffer)?;
+        Ok(buffer)
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        self.storage.get_range(meta, blob_ref, offset, len)
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        let reader = self.storage.get_stream(meta, blob_ref)?;
+        let (hash, checksum) = match ChecksumBlobStorage::checksum_hash(meta)? {
+            Some(checksum) => checksum,
+            None => return Ok(reader),
+        };
+        if hash.hasher().is_none() {
+            // checksums of hashes without an incremental hasher are only verified by `get`
+            eprintln!("Checksum: the {} checksum of {} is not verified while streaming", hash.name(), meta);
+            return Ok(reader);
+        }
+        Ok(Box::new(ChecksumReader {
+            reader: HashingReader::new(reader, &hash),
+            hash,
+            checksum: checksum.clone(),
+        }))
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        ChecksumBlobStorage::verify(meta, &buffer)?;
+        self.storage.put(meta, buffer)
+    }
+
+    /// Verifies the checksum while the blob is written, the blob is deleted again if it
+    /// does not match.
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let (hash, checksum) = match ChecksumBlobStorage::checksum_hash(meta)? {
+            Some(checksum) => checksum,
+            None => return self.storage.put_stream(meta, reader),
+        };
+
+        let mut reader = match HashingReader::new(reader, &hash) {
+            Some(reader) => reader,
+            None => {
+                eprintln!("Checksum: the {} checksum of {} can't be verified while streaming", hash.name(), meta);
+                return Err(BlobStorageError::IntegrityError);
+            }
+        };
+        let blob_ref = self.storage.put_stream(meta, &mut reader)?;
+        if Checksum::from_digest(&hash, &reader.finalize()) != *checksum {
+            eprintln!("Checksum: blob stream of {} does not match {:?}", meta, checksum);
+            self.storage.delete(meta, &blob_ref)?;
+            return Err(BlobStorageError::IntegrityError);
+        }
+        Ok(blob_ref)
+    }
+
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        self.storage.delete(meta, blob_ref)
+    }
+
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        self.storage.rotate(blob_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::{load_fixture};
+    use crate::storage::blob::{BlobStorage, BlobStorageError};
+    use crate::storage::blob::backend::bucket::{BucketBlobRef, BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::checksum::ChecksumBlobStorage;
+    use crate::storage::blob::hashing::Hash;
+    use std::fs;
+    use std::io::{ErrorKind, Read};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checksum_verification() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let inner = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+        let storage = ChecksumBlobStorage::new(Box::new(inner));
+
+        let buffer = load_fixture(Path::new("images").join("rgb.png"));
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+
+        // the checksum needs to match the contents on put:
+        let mut other = buffer.to_vec();
+        other[0] ^= 0xff;
+        match storage.put(&meta, other) {
+            Err(BlobStorageError::IntegrityError) => {}
+            _ => panic!("expected integrity error on put!"),
+        }
+
+        let reference = storage.put(&meta, buffer.to_vec()).expect("put blob failed!");
+        assert_eq!(storage.get(&meta, &reference).expect("get blob failed!"), buffer);
+
+        // streams are verified the same way:
+        match storage.put_stream(&meta, &mut &buffer[..]) {
+            Ok(_) => {}
+            _ => panic!("expected streamed blob to be stored!"),
+        }
+        let other_meta = BlobMeta::new_from_buffer(&buffer[1..], &Hash::Sha2_256);
+        let other_meta = BlobMeta { size: buffer.len(), ..other_meta };
+        match storage.put_stream(&other_meta, &mut &buffer[..]) {
+            Err(BlobStorageError::IntegrityError) => {}
+            _ => panic!("expected integrity error on put stream!"),
+        }
+
+        // silently corrupt the blob in the bucket file:
+        let blob_ref = reference.any().downcast_ref::<BucketBlobRef>().unwrap();
+        let filename = config.path.join(format!("{:08}", blob_ref.bucket));
+        let mut contents = fs::read(&filename).unwrap();
+        contents[blob_ref.offset + 42] ^= 0xff;
+        fs::write(&filename, contents).unwrap();
+
+        match storage.get(&meta, &reference) {
+            Err(BlobStorageError::IntegrityError) => {}
+            _ => panic!("expected integrity error on get!"),
+        }
+        let mut contents = Vec::new();
+        let mut reader = storage.get_stream(&meta, &reference).expect("get stream failed!");
+        assert_eq!(reader.read_to_end(&mut contents).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // blobs without checksum are not verified:
+        let meta = BlobMeta { checksum: None, ..meta };
+        assert!(storage.get(&meta, &reference).is_ok());
+    }
+}
@@ -0,0 +1,381 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Transparent Compression
+//!
+//! Wraps any blob storage backend and compresses blobs with zstd or lz4 before they
+//! are stored, the codec is recorded in the blob reference and the blob is decompressed
+//! again on read.
+//!
+//! Media that is compressed already (JPEG, PNG, GIF, WebP, MP4, WebM, ...) is detected
+//! by its magic bytes and stored as-is, as are buffers that don't get any smaller.
+//! Streamed blobs are compressed into a temporary file first, the wrapped storage
+//! needs to know the size of the blob before it is written.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::str::FromStr;
+use typetag::serde;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+impl FromStr for Codec {
+    type Err = BlobStorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(BlobStorageError::StorageConfigError),
+        }
+    }
+}
+
+impl Codec {
+    /// Compresses everything read from the reader into the writer.
+    fn compress<W: Write>(&self, level: i32, reader: &mut dyn Read, writer: W) -> io::Result<W> {
+        match self {
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()
+            }
+            Codec::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new().level(level.max(0) as u32).build(writer)?;
+                io::copy(reader, &mut encoder)?;
+                let (writer, result) = encoder.finish();
+                result.map(|_| writer)
+            }
+        }
+    }
+
+    /// Returns a reader decompressing the reader.
+    fn decompress(&self, reader: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Codec::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            Codec::Lz4 => Ok(Box::new(lz4::Decoder::new(reader)?)),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressedBlobRef {
+    /// The codec the blob is compressed with, None if stored uncompressed.
+    pub codec: Option<Codec>,
+    /// Size of the blob in the wrapped storage.
+    pub stored_size: usize,
+    /// Reference of the blob in the wrapped storage.
+    pub blob_ref: Box<dyn BlobRef>,
+}
+
+#[typetag::serde]
+impl BlobRef for CompressedBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("CompressedBlobRef({:?}, {}, {})", self.codec, self.stored_size, self.blob_ref.display())
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionBlobStorageConfig {
+    /// The compression codec, either `zstd` or `lz4`.
+    pub codec: String,
+    /// Compression level of the codec, the default level of the codec if not set.
+    pub level: Option<i32>,
+}
+
+/// Returns true if the header of a blob indicates a compressed file format.
+fn is_compressed(header: &[u8]) -> bool {
+    let starts_with = |magic: &[u8]| header.starts_with(magic);
+    starts_with(b"\xff\xd8\xff") // jpeg
+        || starts_with(b"\x89PNG")
+        || starts_with(b"GIF8")
+        || (starts_with(b"RIFF") && header.len() >= 12 && &header[8..12] == b"WEBP")
+        || (header.len() >= 8 && &header[4..8] == b"ftyp") // mp4, mov, heic, avif
+        || starts_with(b"\x1a\x45\xdf\xa3") // webm, matroska
+        || starts_with(b"\x1f\x8b") // gzip
+        || starts_with(b"\x28\xb5\x2f\xfd") // zstd
+        || starts_with(b"\x04\x22\x4d\x18") // lz4
+        || starts_with(b"PK\x03\x04") // zip
+}
+
+/// Number of bytes of the header used to detect compressed file formats.
+const HEADER_SIZE: usize = 16;
+
+pub struct CompressionBlobStorage {
+    /// The wrapped storage holding the compressed blobs.
+    storage: Box<dyn BlobStorage>,
+    codec: Codec,
+    level: i32,
+}
+
+impl CompressionBlobStorage {
+    pub fn new(
+        config: CompressionBlobStorageConfig,
+        storage: Box<dyn BlobStorage>,
+    ) -> Result<Self, BlobStorageError> {
+        let codec = Codec::from_str(&config.codec)?;
+        let level = config.level.unwrap_or(match codec {
+            Codec::Zstd => 3,
+            Codec::Lz4 => 0,
+        });
+        Ok(Self { storage, codec, level })
+    }
+
+    /// Returns the meta of the blob as stored in the wrapped storage, the checksum
+    /// belongs to the uncompressed contents.
+    fn stored_meta(meta: &BlobMeta, stored_size: usize) -> BlobMeta {
+        BlobMeta {
+            checksum: None,
+            size: stored_size,
+            ..meta.clone()
+        }
+    }
+
+    fn compressed_ref(blob_ref: &Box<dyn BlobRef>) -> Result<&CompressedBlobRef, BlobStorageError> {
+        blob_ref
+            .any()
+            .downcast_ref::<CompressedBlobRef>()
+            .ok_or(BlobStorageError::ReadBlobRefMismatch)
+    }
+
+    fn store(
+        &self,
+        meta: &BlobMeta,
+        codec: Option<Codec>,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let stored_size = buffer.len();
+        let blob_ref = self.storage.put(&CompressionBlobStorage::stored_meta(meta, stored_size), buffer)?;
+        Ok(Box::new(CompressedBlobRef { codec, stored_size, blob_ref }))
+    }
+}
+
+impl BlobStorage for CompressionBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        let mut buffer = Vec::with_capacity(meta.size);
+        self.get_stream(meta, blob_ref)?.read_to_end(&mut buffer).map_err(|err| {
+            eprintln!("Compression: error decompressing blob {}: {:?}", meta, err);
+            BlobStorageError::ReadStorageError
+        })?;
+        if buffer.len() != meta.size {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        Ok(buffer)
+    }
+
+    /// Compressed blobs are decompressed from the start up to the end of the range.
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        let compressed = CompressionBlobStorage::compressed_ref(blob_ref)?;
+        if compressed.codec.is_none() {
+            let stored_meta = CompressionBlobStorage::stored_meta(meta, compressed.stored_size);
+            return self.storage.get_range(&stored_meta, &compressed.blob_ref, offset, len);
+        }
+        if offset + len > meta.size {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+
+        let mut reader = self.get_stream(meta, blob_ref)?;
+        io::copy(&mut (&mut reader).take(offset as u64), &mut io::sink())?;
+        let mut buffer = vec![0; len];
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        let compressed = CompressionBlobStorage::compressed_ref(blob_ref)?;
+        let stored_meta = CompressionBlobStorage::stored_meta(meta, compressed.stored_size);
+        let reader = self.storage.get_stream(&stored_meta, &compressed.blob_ref)?;
+        match compressed.codec {
+            Some(codec) => Ok(codec.decompress(reader)?),
+            None => Ok(reader),
+        }
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        if is_compressed(&buffer[..buffer.len().min(HEADER_SIZE)]) {
+            return self.store(meta, None, buffer);
+        }
+
+        let compressed = self
+            .codec
+            .compress(self.level, &mut &buffer[..], Vec::with_capacity(buffer.len() / 2))
+            .map_err(|err| {
+                eprintln!("Compression: error compressing blob {}: {:?}", meta, err);
+                BlobStorageError::PutError
+            })?;
+        if compressed.len() >= buffer.len() {
+            self.store(meta, None, buffer)
+        } else {
+            self.store(meta, Some(self.codec), compressed)
+        }
+    }
+
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        reader.take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+        let mut reader = (&header[..]).chain(reader);
+
+        if is_compressed(&header) {
+            let blob_ref = self.storage.put_stream(meta, &mut reader)?;
+            return Ok(Box::new(CompressedBlobRef {
+                codec: None,
+                stored_size: meta.size,
+                blob_ref,
+            }));
+        }
+
+        // the size of the blob read needs to match the meta, even though its not stored as-is
+        let mut counted = (&mut reader).take(meta.size as u64 + 1);
+        let mut file = self
+            .codec
+            .compress(self.level, &mut counted, tempfile::tempfile()?)
+            .map_err(|err| {
+                eprintln!("Compression: error compressing blob {}: {:?}", meta, err);
+                BlobStorageError::PutError
+            })?;
+        if counted.limit() != 1 {
+            return Err(BlobStorageError::PutError);
+        }
+
+        let stored_size = file.seek(SeekFrom::End(0))? as usize;
+        file.seek(SeekFrom::Start(0))?;
+        let stored_meta = CompressionBlobStorage::stored_meta(meta, stored_size);
+        let blob_ref = self.storage.put_stream(&stored_meta, &mut file)?;
+        Ok(Box::new(CompressedBlobRef {
+            codec: Some(self.codec),
+            stored_size,
+            blob_ref,
+        }))
+    }
+
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        let compressed = CompressionBlobStorage::compressed_ref(blob_ref)?;
+        let stored_meta = CompressionBlobStorage::stored_meta(meta, compressed.stored_size);
+        self.storage.delete(&stored_meta, &compressed.blob_ref)
+    }
+
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        let compressed = CompressionBlobStorage::compressed_ref(blob_ref)?;
+        Ok(self.storage.rotate(&compressed.blob_ref)?.map(|rotated| {
+            Box::new(CompressedBlobRef { blob_ref: rotated, ..compressed.clone() }) as Box<dyn BlobRef>
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::{load_fixture};
+    use crate::storage::blob::{BlobRef, BlobStorage};
+    use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::compression::{Codec, CompressedBlobRef, CompressionBlobStorage, CompressionBlobStorageConfig};
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn codec(blob_ref: &Box<dyn BlobRef>) -> Option<Codec> {
+        blob_ref.any().downcast_ref::<CompressedBlobRef>().unwrap().codec
+    }
+
+    fn test_compression(config: CompressionBlobStorageConfig) {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let bucket_config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&bucket_config).expect("Error in init of bucket storage!");
+        let inner = BucketBlobStorage::new(bucket_config).expect("error creating the blob storage");
+        let expected = Some(config.codec.parse().unwrap());
+        let storage = CompressionBlobStorage::new(config, Box::new(inner))
+            .expect("compression blob storage can't be created!");
+
+        let svg = load_fixture(Path::new("images").join("example.svg"));
+        let svg_meta = BlobMeta::new(svg.len());
+        let jpeg = load_fixture(Path::new("images").join("rgb.jpeg"));
+        let jpeg_meta = BlobMeta::new(jpeg.len());
+
+        let svg_ref = storage.put(&svg_meta, svg.to_vec()).expect("put blob failed!");
+        let jpeg_ref = storage.put(&jpeg_meta, jpeg.to_vec()).expect("put blob failed!");
+
+        // only the svg is compressed:
+        assert_eq!(codec(&svg_ref), expected);
+        assert_eq!(codec(&jpeg_ref), None);
+        let stored_size = svg_ref.any().downcast_ref::<CompressedBlobRef>().unwrap().stored_size;
+        assert!(stored_size < svg.len());
+
+        assert_eq!(storage.get(&svg_meta, &svg_ref).expect("get blob failed!"), svg);
+        assert_eq!(storage.get(&jpeg_meta, &jpeg_ref).expect("get blob failed!"), jpeg);
+        assert_eq!(storage.get_range(&svg_meta, &svg_ref, 10, 20).expect("get blob range failed!"), &svg[10..30]);
+        assert_eq!(storage.get_range(&jpeg_meta, &jpeg_ref, 10, 20).expect("get blob range failed!"), &jpeg[10..30]);
+
+        // streams are compressed the same way:
+        let stream_meta = BlobMeta::new(svg.len());
+        let stream_ref = storage.put_stream(&stream_meta, &mut &svg[..]).expect("put blob failed!");
+        assert_eq!(codec(&stream_ref), expected);
+        let mut contents = Vec::new();
+        storage.get_stream(&stream_meta, &stream_ref).expect("get blob failed!")
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, svg);
+        assert!(storage.put_stream(&stream_meta, &mut &svg[1..]).is_err());
+
+        storage.delete(&svg_meta, &svg_ref).expect("delete blob failed!");
+        storage.delete(&jpeg_meta, &jpeg_ref).expect("delete blob failed!");
+    }
+
+    #[test]
+    fn test_compression_zstd() {
+        test_compression(CompressionBlobStorageConfig {
+            codec: "zstd".to_string(),
+            level: Some(19),
+        });
+    }
+
+    #[test]
+    fn test_compression_lz4() {
+        test_compression(CompressionBlobStorageConfig {
+            codec: "lz4".to_string(),
+            level: None,
+        });
+    }
+}
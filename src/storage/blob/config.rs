@@ -3,13 +3,45 @@
 // Licensed under the Apache License, Version 2.0, or the MIT License
 
 use crate::storage::blob::backend::bucket::{BucketBlobStorageConfig};
+use crate::storage::blob::backend::fs::{FsBlobStorageConfig};
 use crate::storage::blob::backend::mem::{MemoryBlobStorageConfig};
+use crate::storage::blob::backend::s3::{S3BlobStorageConfig};
+use crate::storage::blob::compression::{CompressionBlobStorageConfig};
+use crate::storage::blob::dedup::{DedupBlobStorageConfig};
+use crate::storage::blob::encryption::{EncryptionBlobStorageConfig};
+use crate::storage::blob::mirror::{MirrorBlobStorageConfig};
+use crate::storage::blob::tiered::{TieredBlobStorageConfig};
 use serde::{Serialize, Deserialize};
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct BlobStorageConfig {
     pub storage_blob_type: String,
+    #[serde(default)]
     pub storage_blob_mem: MemoryBlobStorageConfig,
-    pub storage_blob_bucket: BucketBlobStorageConfig,
+    /// Only required if the bucket backend is used.
+    pub storage_blob_bucket: Option<BucketBlobStorageConfig>,
+    /// Only required if the fs backend is used.
+    pub storage_blob_fs: Option<FsBlobStorageConfig>,
+    /// Only required if the s3 backend is used.
+    pub storage_blob_s3: Option<S3BlobStorageConfig>,
+    /// Only required if the mirror backend is used.
+    pub storage_blob_mirror: Option<MirrorBlobStorageConfig>,
+    /// Only required if the tiered backend is used.
+    pub storage_blob_tiered: Option<TieredBlobStorageConfig>,
+    /// Encrypts blobs stored in the backend if set.
+    pub storage_blob_encryption: Option<EncryptionBlobStorageConfig>,
+    /// Compresses blobs stored in the backend if set.
+    pub storage_blob_compression: Option<CompressionBlobStorageConfig>,
+    /// Deduplicates blobs stored in the backend if set.
+    pub storage_blob_dedup: Option<DedupBlobStorageConfig>,
+    /// Hash algorithm used for the checksum of newly stored blobs, see `hashing::Hash`.
+    #[serde(default = "BlobStorageConfig::default_checksum")]
+    pub storage_blob_checksum: String,
+}
+
+impl BlobStorageConfig {
+    fn default_checksum() -> String {
+        "sha2_256".to_string()
+    }
 }
@@ -0,0 +1,457 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Content-Addressed Deduplication
+//!
+//! Wraps any blob storage backend, blobs are addressed by a digest of their contents.
+//! Storing a blob that is already present returns the existing reference instead of
+//! writing it again, a reference count ensures the blob is only deleted in the wrapped
+//! storage once its last owner is gone.
+//!
+//! Streamed blobs are hashed while they are written to the wrapped storage, so
+//! a duplicate is only detected (and deleted again) after it was written. Buffered
+//! blobs are written without holding the index lock as well, a concurrent put of the
+//! same contents is detected once the lock is taken again and the copy deleted.
+//!
+//! Blobs are addressed by their digest alone, so only cryptographic hashes are accepted.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::hashing::{Hash, HashingReader};
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use rocksdb::DB;
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use typetag::serde;
+use uuid::Uuid;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DedupBlobRef {
+    /// Hex encoded digest of the blob contents.
+    pub digest: String,
+    /// Reference of the blob in the wrapped storage.
+    pub blob_ref: Box<dyn BlobRef>,
+    /// Identifier of the blob meta the shared blob was written with, the wrapped storage
+    /// may check it on reads.
+    #[serde(default)]
+    pub id: Option<Uuid>,
+}
+
+impl DedupBlobRef {
+    /// Returns the blob meta the shared blob is read with from the wrapped storage.
+    fn stored_meta(&self, meta: &BlobMeta) -> BlobMeta {
+        stored_meta(meta, self.id)
+    }
+}
+
+fn stored_meta(meta: &BlobMeta, id: Option<Uuid>) -> BlobMeta {
+    BlobMeta {
+        id: id.unwrap_or(meta.id),
+        ..meta.clone()
+    }
+}
+
+#[typetag::serde]
+impl BlobRef for DedupBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("DedupBlobRef({}, {})", self.digest, self.blob_ref.display())
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DedupBlobStorageConfig {
+    /// Hash algorithm used to address the blob contents, see `hashing::Hash`, it needs to
+    /// be a cryptographic hash.
+    pub hash: String,
+    /// The rocksdb directory of the digest index, the index is kept in-memory if not set.
+    pub index_path: Option<PathBuf>,
+}
+
+/// A stored blob shared by all blobs with the same digest.
+#[derive(Clone, Serialize, Deserialize)]
+struct DedupEntry {
+    blob_ref: Box<dyn BlobRef>,
+    /// Number of owners of the blob.
+    count: usize,
+    /// Identifier of the blob meta the blob was written with.
+    #[serde(default)]
+    id: Option<Uuid>,
+}
+
+/// Maps digests to the stored blobs.
+enum DedupIndex {
+    Memory(HashMap<Vec<u8>, DedupEntry>),
+    RocksDb(DB),
+}
+
+impl DedupIndex {
+    fn get(&self, digest: &[u8]) -> Result<Option<DedupEntry>, BlobStorageError> {
+        match self {
+            DedupIndex::Memory(entries) => Ok(entries.get(digest).cloned()),
+            DedupIndex::RocksDb(db) => match db.get(digest) {
+                Ok(Some(value)) => rmp_serde::from_read_ref(&value).map(Some).map_err(|err| {
+                    eprintln!("Dedup: Unserializing Error {:?}", err);
+                    BlobStorageError::ReadStorageError
+                }),
+                Ok(None) => Ok(None),
+                Err(err) => {
+                    eprintln!("Dedup: RocksDb Error {:?}", err);
+                    Err(BlobStorageError::ReadStorageError)
+                }
+            },
+        }
+    }
+
+    fn put(&mut self, digest: &[u8], entry: DedupEntry) -> Result<(), BlobStorageError> {
+        match self {
+            DedupIndex::Memory(entries) => {
+                entries.insert(digest.to_vec(), entry);
+                Ok(())
+            }
+            DedupIndex::RocksDb(db) => {
+                let encoded = rmp_serde::to_vec_named(&entry).map_err(|err| {
+                    eprintln!("Dedup: Serialization Error {:?}", err);
+                    BlobStorageError::PutError
+                })?;
+                db.put(digest, encoded).map_err(|err| {
+                    eprintln!("Dedup: RocksDb Error {:?}", err);
+                    BlobStorageError::PutError
+                })
+            }
+        }
+    }
+
+    fn delete(&mut self, digest: &[u8]) -> Result<(), BlobStorageError> {
+        match self {
+            DedupIndex::Memory(entries) => {
+                entries.remove(digest);
+                Ok(())
+            }
+            DedupIndex::RocksDb(db) => db.delete(digest).map_err(|err| {
+                eprintln!("Dedup: RocksDb Error {:?}", err);
+                BlobStorageError::DeleteError
+            }),
+        }
+    }
+}
+
+pub struct DedupBlobStorage {
+    /// The wrapped storage holding the blob contents.
+    storage: Box<dyn BlobStorage>,
+    hash: Hash,
+    /// The lock is held while the reference count of a digest is updated.
+    index: Mutex<DedupIndex>,
+}
+
+impl DedupBlobStorage {
+    pub fn new(
+        config: DedupBlobStorageConfig,
+        storage: Box<dyn BlobStorage>,
+    ) -> Result<Self, BlobStorageError> {
+        let hash = Hash::from_str(&config.hash).map_err(|_| BlobStorageError::StorageConfigError)?;
+        if !hash.is_cryptographic() {
+            eprintln!("Dedup: the {} hash can't address blob contents, use a cryptographic hash!", hash.name());
+            return Err(BlobStorageError::StorageConfigError);
+        }
+        let index = match config.index_path {
+            Some(path) => DedupIndex::RocksDb(DB::open_default(path).map_err(|_| {
+                BlobStorageError::CreateStorageError("Error opening the dedup index!")
+            })?),
+            None => DedupIndex::Memory(HashMap::new()),
+        };
+        Ok(Self { storage, hash, index: Mutex::new(index) })
+    }
+
+    fn lock_index(&self) -> Result<MutexGuard<'_, DedupIndex>, BlobStorageError> {
+        self.index.lock().map_err(|_| {
+            eprintln!("Dedup: index lock is poisoned!");
+            BlobStorageError::WriteError
+        })
+    }
+
+    /// Adds the blob written to the wrapped storage to the index, if a blob with the same
+    /// digest was stored in the meantime the written copy is deleted again.
+    fn insert(
+        &self,
+        meta: &BlobMeta,
+        digest: Vec<u8>,
+        written: Box<dyn BlobRef>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut index = self.lock_index()?;
+        let entry = match index.get(&digest)? {
+            Some(entry) => {
+                self.storage.delete(meta, &written)?;
+                DedupEntry {
+                    blob_ref: entry.blob_ref,
+                    count: entry.count + 1,
+                    id: entry.id,
+                }
+            }
+            None => DedupEntry {
+                blob_ref: written,
+                count: 1,
+                id: Some(meta.id),
+            },
+        };
+        let blob_ref = DedupBlobRef {
+            digest: hex::encode(&digest),
+            blob_ref: entry.blob_ref.clone(),
+            id: entry.id,
+        };
+        index.put(&digest, entry)?;
+
+        Ok(Box::new(blob_ref))
+    }
+}
+
+impl BlobStorage for DedupBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<DedupBlobRef>() {
+            self.storage.get(&blob_ref.stored_meta(meta), &blob_ref.blob_ref)
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<DedupBlobRef>() {
+            self.storage.get_range(&blob_ref.stored_meta(meta), &blob_ref.blob_ref, offset, len)
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        if let Some(blob_ref) = blob_ref.any().downcast_ref::<DedupBlobRef>() {
+            self.storage.get_stream(&blob_ref.stored_meta(meta), &blob_ref.blob_ref)
+        } else {
+            Err(BlobStorageError::ReadBlobRefMismatch)
+        }
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let digest = self.hash.hash_bytes(&buffer);
+
+        {
+            let mut index = self.lock_index()?;
+            if let Some(entry) = index.get(&digest)? {
+                let blob_ref = DedupBlobRef {
+                    digest: hex::encode(&digest),
+                    blob_ref: entry.blob_ref.clone(),
+                    id: entry.id,
+                };
+                index.put(&digest, DedupEntry { count: entry.count + 1, ..entry })?;
+                return Ok(Box::new(blob_ref));
+            }
+        }
+
+        // the index is not locked while the blob is written
+        let written = self.storage.put(meta, buffer)?;
+        self.insert(meta, digest, written)
+    }
+
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut reader = HashingReader::new(reader, &self.hash).ok_or(BlobStorageError::StorageConfigError)?;
+        let written = self.storage.put_stream(meta, &mut reader)?;
+        let digest = reader.finalize();
+        self.insert(meta, digest, written)
+    }
+
+    /// Releases one owner of the blob, the blob is deleted once the last owner is gone.
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        let blob_ref = match blob_ref.any().downcast_ref::<DedupBlobRef>() {
+            Some(blob_ref) => blob_ref,
+            None => return Err(BlobStorageError::ReadBlobRefMismatch),
+        };
+        let digest = hex::decode(&blob_ref.digest).map_err(|_| BlobStorageError::DeleteError)?;
+
+        let mut index = self.lock_index()?;
+        match index.get(&digest)? {
+            Some(entry) if entry.count > 1 => index.put(
+                &digest,
+                DedupEntry {
+                    count: entry.count - 1,
+                    ..entry
+                },
+            ),
+            Some(entry) => {
+                self.storage.delete(&stored_meta(meta, entry.id), &entry.blob_ref)?;
+                index.delete(&digest)
+            }
+            None => Err(BlobStorageError::DeleteError),
+        }
+    }
+
+    /// Rotates the blob shared by all owners of the digest once and updates the index, the
+    /// references of the other owners are rotated to the same blob ref.
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        let blob_ref = match blob_ref.any().downcast_ref::<DedupBlobRef>() {
+            Some(blob_ref) => blob_ref,
+            None => return Err(BlobStorageError::ReadBlobRefMismatch),
+        };
+        let digest = hex::decode(&blob_ref.digest).map_err(|_| BlobStorageError::ReadStorageError)?;
+
+        let mut index = self.lock_index()?;
+        let mut entry = index.get(&digest)?.ok_or(BlobStorageError::ReadStorageError)?;
+        if let Some(rotated) = self.storage.rotate(&entry.blob_ref)? {
+            entry.blob_ref = rotated;
+            index.put(&digest, entry.clone())?;
+        }
+
+        // the blob ref of the owner is outdated if it still needs to be rotated
+        if self.storage.rotate(&blob_ref.blob_ref)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(DedupBlobRef {
+            digest: blob_ref.digest.clone(),
+            blob_ref: entry.blob_ref,
+            id: entry.id,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::{BlobRef, BlobStorage};
+    use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::backend::mem::{MemoryBlobRef, MemoryBlobStorage, MemoryBlobStorageConfig};
+    use crate::storage::blob::dedup::{DedupBlobRef, DedupBlobStorage, DedupBlobStorageConfig};
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    fn inner_index(blob_ref: &Box<dyn BlobRef>) -> usize {
+        let blob_ref = blob_ref.any().downcast_ref::<DedupBlobRef>().unwrap();
+        blob_ref.blob_ref.any().downcast_ref::<MemoryBlobRef>().unwrap().index
+    }
+
+    fn test_dedup(config: DedupBlobStorageConfig) {
+        let inner = MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap();
+        let storage = DedupBlobStorage::new(config, Box::new(inner))
+            .expect("dedup blob storage can't be created!");
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let other: Vec<u8> = vec![42, 0, 42];
+        let meta_1 = BlobMeta::new(buffer.len());
+        let meta_2 = BlobMeta::new(buffer.len());
+        let meta_3 = BlobMeta::new(other.len());
+
+        let reference_1 = storage.put(&meta_1, buffer.to_vec()).expect("put blob failed!");
+        let reference_2 = storage.put(&meta_2, buffer.to_vec()).expect("put blob failed!");
+        let reference_3 = storage.put(&meta_3, other.to_vec()).expect("put blob failed!");
+
+        // the same contents are only stored once:
+        assert_eq!(inner_index(&reference_1), 0);
+        assert_eq!(inner_index(&reference_2), 0);
+        assert_eq!(inner_index(&reference_3), 1);
+
+        // the blob stays around until its last owner is deleted:
+        storage.delete(&meta_1, &reference_1).expect("delete blob failed!");
+        assert_eq!(storage.get(&meta_2, &reference_2).expect("get blob failed!"), buffer);
+        storage.delete(&meta_2, &reference_2).expect("delete blob failed!");
+        assert!(storage.delete(&meta_2, &reference_2).is_err());
+
+        // stored again after it was deleted
+        let reference_4 = storage.put(&meta_1, buffer.to_vec()).expect("put blob failed!");
+        assert_eq!(inner_index(&reference_4), 2);
+        assert_eq!(storage.get(&meta_3, &reference_3).expect("get blob failed!"), other);
+
+        // streamed blobs are deduplicated against the stored ones:
+        let reference_5 = storage.put_stream(&meta_2, &mut &buffer[..]).expect("put blob failed!");
+        assert_eq!(inner_index(&reference_5), 2);
+        let mut contents = Vec::new();
+        storage.get_stream(&meta_2, &reference_5).expect("get blob failed!")
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, buffer);
+    }
+
+    #[test]
+    fn test_dedup_weak_hash() {
+        let inner = MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap();
+        let config = DedupBlobStorageConfig {
+            hash: "t1ha".to_string(),
+            index_path: None,
+        };
+        assert!(DedupBlobStorage::new(config, Box::new(inner)).is_err());
+    }
+
+    #[test]
+    fn test_dedup_mem_index() {
+        test_dedup(DedupBlobStorageConfig {
+            hash: "sha2_256".to_string(),
+            index_path: None,
+        });
+    }
+
+    #[test]
+    fn test_dedup_rocksdb_index() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        test_dedup(DedupBlobStorageConfig {
+            hash: "Blake2b".to_string(),
+            index_path: Some(dir.path().join("dedup")),
+        });
+    }
+
+    #[test]
+    fn test_dedup_bucket() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let bucket_config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&bucket_config).expect("Error in init of bucket storage!");
+        let inner = BucketBlobStorage::new(bucket_config).expect("error creating the blob storage");
+        let config = DedupBlobStorageConfig {
+            hash: "sha2_256".to_string(),
+            index_path: None,
+        };
+        let storage = DedupBlobStorage::new(config, Box::new(inner)).expect("dedup blob storage can't be created!");
+
+        // the bucket checks the blob meta of the record, the shared blob is read with the
+        // meta of the owner that wrote it:
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta_1 = BlobMeta::new(buffer.len());
+        let meta_2 = BlobMeta::new(buffer.len());
+        let reference_1 = storage.put(&meta_1, buffer.to_vec()).expect("put blob failed!");
+        let reference_2 = storage.put_stream(&meta_2, &mut &buffer[..]).expect("put blob failed!");
+        storage.delete(&meta_1, &reference_1).expect("delete blob failed!");
+        assert_eq!(storage.get(&meta_2, &reference_2).expect("get blob failed!"), buffer);
+        assert_eq!(storage.get_range(&meta_2, &reference_2, 1, 3).expect("get blob failed!"), &buffer[1..4]);
+        storage.delete(&meta_2, &reference_2).expect("delete blob failed!");
+    }
+}
@@ -0,0 +1,547 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! At-Rest Encryption
+//!
+//! Wraps any blob storage backend and encrypts blobs with AES-256-GCM before they are
+//! stored. Each blob is encrypted with its own random data key, the data key is wrapped
+//! (encrypted) by a master key from the configuration and stored in the blob reference,
+//! together with the id of the master key and the nonce of the blob.
+//!
+//! Blobs are encrypted in segments of 64 KiB, each with its own authentication tag, so
+//! ranges and streams are decrypted without reading the whole blob. The nonce of a
+//! segment is the nonce of the blob followed by the segment index and a flag marking
+//! the last segment, segments can't be reordered or truncated unnoticed:
+//!
+//! ```text
+//! segment nonce: blob nonce (7 bytes) | segment index (u32 be) | last segment (1 byte)
+//! ```
+//!
+//! Master keys are rotated by adding a new key to the configuration, making it the
+//! current key and running the `rotate-keys` command, which rewraps the data keys of
+//! existing blobs and updates their references in the meta storage (see
+//! `storage::rotation`), the blobs themselves are not rewritten. With `--reencrypt` the
+//! blobs are stored again with new data keys instead, the previous blobs are deleted.
+//! Old master keys are needed until the rotation is complete.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::io::prelude::*;
+use typetag::serde;
+
+/// Size of the plaintext of a segment, the last segment may be smaller.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+/// Size of the nonce of a blob, the rest of the segment nonces is the segment index and flag.
+const BLOB_NONCE_SIZE: usize = 7;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedBlobRef {
+    /// Id of the master key the data key is wrapped with.
+    pub key_id: String,
+    /// Hex encoded nonce and ciphertext of the data key.
+    pub wrapped_key: String,
+    /// Hex encoded nonce of the blob.
+    pub nonce: String,
+    /// Size of the encrypted blob in the wrapped storage.
+    pub stored_size: usize,
+    /// Reference of the blob in the wrapped storage.
+    pub blob_ref: Box<dyn BlobRef>,
+}
+
+#[typetag::serde]
+impl BlobRef for EncryptedBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("EncryptedBlobRef({}, {}, {})", self.key_id, self.nonce, self.blob_ref.display())
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionBlobStorageConfig {
+    /// Id of the master key new blobs are encrypted with.
+    pub key_id: String,
+    /// Hex encoded 256 bit master keys by their id, including the keys of older blobs.
+    pub keys: HashMap<String, String>,
+}
+
+/// Returns the number of segments of a blob of the given size, empty blobs have one.
+fn segments(size: usize) -> usize {
+    size.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+/// Returns the size of a blob of the given size once encrypted.
+fn stored_size(size: usize) -> usize {
+    size + segments(size) * TAG_SIZE
+}
+
+fn segment_nonce(nonce: &[u8; BLOB_NONCE_SIZE], index: usize, last: bool) -> [u8; NONCE_SIZE] {
+    let mut segment_nonce = [0u8; NONCE_SIZE];
+    segment_nonce[..BLOB_NONCE_SIZE].copy_from_slice(nonce);
+    segment_nonce[BLOB_NONCE_SIZE..NONCE_SIZE - 1].copy_from_slice(&(index as u32).to_be_bytes());
+    segment_nonce[NONCE_SIZE - 1] = last as u8;
+    segment_nonce
+}
+
+fn random_bytes(buffer: &mut [u8]) {
+    OsRng.fill_bytes(buffer);
+}
+
+fn invalid_data(error: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Reader encrypting exactly `remaining` bytes of the reader, segment by segment.
+struct EncryptingReader<'a> {
+    reader: &'a mut dyn Read,
+    cipher: Aes256Gcm,
+    nonce: [u8; BLOB_NONCE_SIZE],
+    remaining: usize,
+    index: usize,
+    done: bool,
+    segment: io::Cursor<Vec<u8>>,
+}
+
+impl<'a> Read for EncryptingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.segment.position() as usize >= self.segment.get_ref().len() && !self.done {
+            let mut plaintext = vec![0; SEGMENT_SIZE.min(self.remaining)];
+            self.reader.read_exact(&mut plaintext)?;
+            self.remaining -= plaintext.len();
+            self.done = self.remaining == 0;
+            if self.done && self.reader.read(&mut [0u8; 1])? != 0 {
+                return Err(invalid_data("blob is larger than its meta"));
+            }
+
+            let nonce = segment_nonce(&self.nonce, self.index, self.done);
+            let ciphertext = self
+                .cipher
+                .encrypt(GenericArray::from_slice(&nonce), &plaintext[..])
+                .map_err(|_| invalid_data("error encrypting segment"))?;
+            self.segment = io::Cursor::new(ciphertext);
+            self.index += 1;
+        }
+        self.segment.read(buf)
+    }
+}
+
+/// Reader decrypting the segments of a blob, starting at the segment with the given index.
+struct DecryptingReader {
+    reader: Box<dyn Read + Send>,
+    cipher: Aes256Gcm,
+    nonce: [u8; BLOB_NONCE_SIZE],
+    /// Bytes of the encrypted blob left to read.
+    remaining: usize,
+    index: usize,
+    segments: usize,
+    segment: io::Cursor<Vec<u8>>,
+}
+
+impl Read for DecryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.segment.position() as usize >= self.segment.get_ref().len() && self.remaining > 0 {
+            let mut ciphertext = vec![0; (SEGMENT_SIZE + TAG_SIZE).min(self.remaining)];
+            self.reader.read_exact(&mut ciphertext)?;
+            self.remaining -= ciphertext.len();
+
+            let nonce = segment_nonce(&self.nonce, self.index, self.index + 1 == self.segments);
+            let plaintext = self
+                .cipher
+                .decrypt(GenericArray::from_slice(&nonce), &ciphertext[..])
+                .map_err(|_| invalid_data("error decrypting segment"))?;
+            self.segment = io::Cursor::new(plaintext);
+            self.index += 1;
+        }
+        self.segment.read(buf)
+    }
+}
+
+pub struct EncryptionBlobStorage {
+    /// The wrapped storage holding the encrypted blobs.
+    storage: Box<dyn BlobStorage>,
+    key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl EncryptionBlobStorage {
+    pub fn new(
+        config: EncryptionBlobStorageConfig,
+        storage: Box<dyn BlobStorage>,
+    ) -> Result<Self, BlobStorageError> {
+        let mut keys = HashMap::new();
+        for (key_id, key) in config.keys {
+            match hex::decode(&key) {
+                Ok(ref key) if key.len() == KEY_SIZE => {
+                    keys.insert(key_id, Aes256Gcm::new(GenericArray::from_slice(key)));
+                }
+                _ => {
+                    eprintln!("Encryption: master key {} is not a hex encoded 256 bit key!", key_id);
+                    return Err(BlobStorageError::StorageConfigError);
+                }
+            }
+        }
+        if !keys.contains_key(&config.key_id) {
+            eprintln!("Encryption: current master key {} is not configured!", config.key_id);
+            return Err(BlobStorageError::StorageConfigError);
+        }
+        Ok(Self {
+            storage,
+            key_id: config.key_id,
+            keys,
+        })
+    }
+
+    fn master_key(&self, key_id: &str) -> Result<&Aes256Gcm, BlobStorageError> {
+        self.keys.get(key_id).ok_or_else(|| {
+            eprintln!("Encryption: unknown master key {}!", key_id);
+            BlobStorageError::ReadStorageError
+        })
+    }
+
+    /// Encrypts the data key with the current master key, returns the nonce and ciphertext.
+    fn wrap_key(&self, key: &[u8]) -> Result<String, BlobStorageError> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        random_bytes(&mut nonce);
+        let wrapped = self
+            .master_key(&self.key_id)?
+            .encrypt(GenericArray::from_slice(&nonce), key)
+            .map_err(|_| BlobStorageError::PutError)?;
+        Ok(hex::encode([&nonce[..], &wrapped[..]].concat()))
+    }
+
+    /// Decrypts the data key of the blob with the master key it is wrapped with.
+    fn unwrap_key(&self, blob_ref: &EncryptedBlobRef) -> Result<Vec<u8>, BlobStorageError> {
+        let wrapped = hex::decode(&blob_ref.wrapped_key).map_err(|_| BlobStorageError::IntegrityError)?;
+        if wrapped.len() <= NONCE_SIZE {
+            return Err(BlobStorageError::IntegrityError);
+        }
+        match self
+            .master_key(&blob_ref.key_id)?
+            .decrypt(GenericArray::from_slice(&wrapped[..NONCE_SIZE]), &wrapped[NONCE_SIZE..])
+        {
+            Ok(key) if key.len() == KEY_SIZE => Ok(key),
+            _ => {
+                eprintln!("Encryption: error unwrapping data key of {}!", blob_ref.display());
+                Err(BlobStorageError::IntegrityError)
+            }
+        }
+    }
+
+    fn encrypted_ref(blob_ref: &Box<dyn BlobRef>) -> Result<&EncryptedBlobRef, BlobStorageError> {
+        blob_ref
+            .any()
+            .downcast_ref::<EncryptedBlobRef>()
+            .ok_or(BlobStorageError::ReadBlobRefMismatch)
+    }
+
+    /// Returns the meta of the blob as stored in the wrapped storage, the checksum
+    /// belongs to the plaintext.
+    fn stored_meta(meta: &BlobMeta, stored_size: usize) -> BlobMeta {
+        BlobMeta {
+            checksum: None,
+            size: stored_size,
+            ..meta.clone()
+        }
+    }
+
+    /// Returns a reader decrypting the blob, starting at the given segment.
+    fn decrypt(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &EncryptedBlobRef,
+        reader: Box<dyn Read + Send>,
+        index: usize,
+        remaining: usize,
+    ) -> Result<DecryptingReader, BlobStorageError> {
+        if blob_ref.stored_size != stored_size(meta.size) {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        let nonce = hex::decode(&blob_ref.nonce).map_err(|_| BlobStorageError::IntegrityError)?;
+        let nonce = nonce[..].try_into().map_err(|_| BlobStorageError::IntegrityError)?;
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&self.unwrap_key(blob_ref)?));
+        Ok(DecryptingReader {
+            reader,
+            cipher,
+            nonce,
+            remaining,
+            index,
+            segments: segments(meta.size),
+            segment: io::Cursor::new(Vec::new()),
+        })
+    }
+
+    /// Encrypts the blob read from the reader with a new data key and stores it.
+    fn encrypt(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+        buffered: bool,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut key = [0u8; KEY_SIZE];
+        random_bytes(&mut key);
+        let mut nonce = [0u8; BLOB_NONCE_SIZE];
+        random_bytes(&mut nonce);
+
+        let wrapped_key = self.wrap_key(&key)?;
+        let stored_meta = EncryptionBlobStorage::stored_meta(meta, stored_size(meta.size));
+
+        let mut reader = EncryptingReader {
+            reader,
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&key)),
+            nonce,
+            remaining: meta.size,
+            index: 0,
+            done: false,
+            segment: io::Cursor::new(Vec::new()),
+        };
+        let blob_ref = if buffered {
+            let mut buffer = Vec::with_capacity(stored_meta.size);
+            reader.read_to_end(&mut buffer).map_err(|err| {
+                eprintln!("Encryption: error encrypting blob {}: {:?}", meta, err);
+                BlobStorageError::PutError
+            })?;
+            self.storage.put(&stored_meta, buffer)?
+        } else {
+            self.storage.put_stream(&stored_meta, &mut reader)?
+        };
+
+        Ok(Box::new(EncryptedBlobRef {
+            key_id: self.key_id.clone(),
+            wrapped_key,
+            nonce: hex::encode(nonce),
+            stored_size: stored_meta.size,
+            blob_ref,
+        }))
+    }
+}
+
+impl BlobStorage for EncryptionBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        let encrypted = EncryptionBlobStorage::encrypted_ref(blob_ref)?;
+        let stored_meta = EncryptionBlobStorage::stored_meta(meta, encrypted.stored_size);
+        let buffer = self.storage.get(&stored_meta, &encrypted.blob_ref)?;
+
+        let mut reader = self.decrypt(meta, encrypted, Box::new(io::Cursor::new(buffer)), 0, encrypted.stored_size)?;
+        let mut plaintext = Vec::with_capacity(meta.size);
+        reader.read_to_end(&mut plaintext).map_err(|err| {
+            eprintln!("Encryption: error decrypting blob {}: {:?}", meta, err);
+            BlobStorageError::IntegrityError
+        })?;
+        Ok(plaintext)
+    }
+
+    /// Only the segments of the range are read and decrypted.
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        if offset + len > meta.size {
+            return Err(BlobStorageError::ReadStorageError);
+        }
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let encrypted = EncryptionBlobStorage::encrypted_ref(blob_ref)?;
+        let stored_meta = EncryptionBlobStorage::stored_meta(meta, encrypted.stored_size);
+
+        let first = offset / SEGMENT_SIZE;
+        let last = (offset + len - 1) / SEGMENT_SIZE;
+        let stored_offset = first * (SEGMENT_SIZE + TAG_SIZE);
+        let stored_end = ((last + 1) * (SEGMENT_SIZE + TAG_SIZE)).min(encrypted.stored_size);
+        let buffer = self.storage.get_range(&stored_meta, &encrypted.blob_ref, stored_offset, stored_end - stored_offset)?;
+
+        let remaining = buffer.len();
+        let mut reader = self.decrypt(meta, encrypted, Box::new(io::Cursor::new(buffer)), first, remaining)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext).map_err(|err| {
+            eprintln!("Encryption: error decrypting blob {}: {:?}", meta, err);
+            BlobStorageError::IntegrityError
+        })?;
+
+        let start = offset - first * SEGMENT_SIZE;
+        Ok(plaintext[start..start + len].to_vec())
+    }
+
+    /// Returns a reader decrypting the blob, tampered segments fail with `InvalidData`.
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        let encrypted = EncryptionBlobStorage::encrypted_ref(blob_ref)?;
+        let stored_meta = EncryptionBlobStorage::stored_meta(meta, encrypted.stored_size);
+        let reader = self.storage.get_stream(&stored_meta, &encrypted.blob_ref)?;
+        Ok(Box::new(self.decrypt(meta, encrypted, reader, 0, encrypted.stored_size)?))
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        if buffer.len() != meta.size {
+            return Err(BlobStorageError::PutError);
+        }
+        self.encrypt(meta, &mut &buffer[..], true)
+    }
+
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.encrypt(meta, reader, false)
+    }
+
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        let encrypted = EncryptionBlobStorage::encrypted_ref(blob_ref)?;
+        let stored_meta = EncryptionBlobStorage::stored_meta(meta, encrypted.stored_size);
+        self.storage.delete(&stored_meta, &encrypted.blob_ref)
+    }
+
+    /// Rewraps the data key with the current master key, the blob itself is unchanged.
+    /// The new reference needs to be updated in the meta storage, see `storage::rotation`.
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        let encrypted = EncryptionBlobStorage::encrypted_ref(blob_ref)?;
+        if encrypted.key_id == self.key_id {
+            return Ok(None);
+        }
+
+        let key = self.unwrap_key(encrypted)?;
+        Ok(Some(Box::new(EncryptedBlobRef {
+            key_id: self.key_id.clone(),
+            wrapped_key: self.wrap_key(&key)?,
+            ..encrypted.clone()
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::{load_fixture};
+    use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+    use crate::storage::blob::backend::bucket::{BucketBlobRef, BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use crate::storage::blob::encryption::{EncryptedBlobRef, EncryptionBlobStorage, EncryptionBlobStorageConfig};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn config(key_id: &str) -> EncryptionBlobStorageConfig {
+        let mut keys = HashMap::new();
+        keys.insert("2020-01".to_string(), "11".repeat(32));
+        keys.insert("2020-02".to_string(), "22".repeat(32));
+        EncryptionBlobStorageConfig {
+            key_id: key_id.to_string(),
+            keys,
+        }
+    }
+
+    #[test]
+    fn test_encryption_mem() {
+        let inner = MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap();
+        let storage = EncryptionBlobStorage::new(config("2020-01"), Box::new(inner))
+            .expect("encryption blob storage can't be created!");
+
+        // spans multiple segments:
+        let buffer: Vec<u8> = (0..200 * 1024).map(|n| (n % 251) as u8).collect();
+        let meta = BlobMeta::new(buffer.len());
+        let empty_meta = BlobMeta::new(0);
+
+        let reference = storage.put(&meta, buffer.to_vec()).expect("put blob failed!");
+        let empty_reference = storage.put_stream(&empty_meta, &mut &[][..]).expect("put blob failed!");
+
+        assert_eq!(storage.get(&meta, &reference).expect("get blob failed!"), buffer);
+        assert_eq!(storage.get(&empty_meta, &empty_reference).expect("get blob failed!"), Vec::<u8>::new());
+        for (offset, len) in &[(0, 10), (65530, 20), (100000, 100000), (204790, 10)] {
+            assert_eq!(
+                storage.get_range(&meta, &reference, *offset, *len).expect("get blob range failed!"),
+                &buffer[*offset..offset + len]
+            );
+        }
+        let mut contents = Vec::new();
+        storage.get_stream(&meta, &reference).expect("get blob failed!")
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, buffer);
+    }
+
+    #[test]
+    fn test_encryption_bucket() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let bucket_config = BucketBlobStorageConfig {
+            path: dir.path().to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&bucket_config).expect("Error in init of bucket storage!");
+        let inner = BucketBlobStorage::new(bucket_config.clone()).expect("error creating the blob storage");
+        let storage = EncryptionBlobStorage::new(config("2020-01"), Box::new(inner))
+            .expect("encryption blob storage can't be created!");
+
+        let buffer = load_fixture(Path::new("images").join("rgb.png"));
+        let meta = BlobMeta::new(buffer.len());
+        let reference = storage.put_stream(&meta, &mut &buffer[..]).expect("put blob failed!");
+        assert_eq!(storage.get(&meta, &reference).expect("get blob failed!"), buffer);
+
+        // rotation rewraps the data key with the current master key, the blob stays readable:
+        let inner = BucketBlobStorage::new(bucket_config.clone()).expect("error creating the blob storage");
+        let rotating = EncryptionBlobStorage::new(config("2020-02"), Box::new(inner)).unwrap();
+        let rotated = rotating.rotate(&reference).unwrap().expect("expected rewrapped data key");
+        assert_eq!(rotated.any().downcast_ref::<EncryptedBlobRef>().unwrap().key_id, "2020-02");
+        assert!(rotating.rotate(&rotated).unwrap().is_none());
+        assert_eq!(rotating.get(&meta, &rotated).expect("get blob failed!"), buffer);
+        drop(rotating);
+
+        // the plaintext is not in the bucket file:
+        let encrypted = reference.any().downcast_ref::<EncryptedBlobRef>().unwrap();
+        let blob_ref = encrypted.blob_ref.any().downcast_ref::<BucketBlobRef>().unwrap();
+        let filename = bucket_config.path.join(format!("{:08}", blob_ref.bucket));
+        let mut contents = fs::read(&filename).unwrap();
+        let stored = &contents[blob_ref.offset..blob_ref.offset + blob_ref.size];
+        assert!(!stored.windows(16).any(|window| window == &buffer[100..116]));
+
+        // tampering is detected:
+        contents[blob_ref.offset + 42] ^= 0xff;
+        fs::write(&filename, contents).unwrap();
+        match storage.get(&meta, &reference) {
+            Err(BlobStorageError::IntegrityError) => {}
+            _ => panic!("expected integrity error on get!"),
+        }
+
+        // the master key is required:
+        let inner = BucketBlobStorage::new(bucket_config).expect("error creating the blob storage");
+        let mut other = config("2020-02");
+        other.keys.remove("2020-01");
+        let storage = EncryptionBlobStorage::new(other, Box::new(inner)).unwrap();
+        assert!(storage.get(&meta, &reference).is_err());
+    }
+}
@@ -5,21 +5,144 @@ use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobStorage, BlobStorageError};
 use crate::storage::blob::config::{BlobStorageConfig};
 use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+use crate::storage::blob::backend::fs::{FsBlobStorage};
 use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+use crate::storage::blob::backend::s3::{S3BlobStorage};
+use crate::storage::blob::checksum::{ChecksumBlobStorage};
+use crate::storage::blob::compression::{CompressionBlobStorage};
+use crate::storage::blob::dedup::{DedupBlobStorage};
+use crate::storage::blob::encryption::{EncryptionBlobStorage};
+use crate::storage::blob::mirror::{MirrorBlobStorage};
+use crate::storage::blob::tiered::{Tier, TierMigrator, TieredBlobStorage};
 use std::any::Any;
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 
-pub fn create_blob_storage(config: BlobStorageConfig) -> Result<Box<dyn BlobStorage>, BlobStorageError> {
+/// Runs the global initialization of the configured blob storage backend.
+/// This needs to be called once per program lifetime before any storage instance is created.
+pub fn init_blob_storage(config: &BlobStorageConfig) -> Result<(), BlobStorageError> {
     match config.storage_blob_type.as_ref() {
+        "mem" => MemoryBlobStorage::init(&config.storage_blob_mem),
+        "bucket" => match config.storage_blob_bucket {
+            Some(ref bucket) => BucketBlobStorage::init(bucket),
+            None => Err(BlobStorageError::StorageConfigError),
+        },
+        "fs" => match config.storage_blob_fs {
+            Some(ref fs) => FsBlobStorage::init(fs),
+            None => Err(BlobStorageError::StorageConfigError),
+        },
+        "s3" => match config.storage_blob_s3 {
+            Some(ref s3) => S3BlobStorage::init(s3),
+            None => Err(BlobStorageError::StorageConfigError),
+        },
+        "mirror" => match config.storage_blob_mirror {
+            Some(ref mirror) => mirror
+                .replicas
+                .iter()
+                .try_for_each(|replica| init_blob_storage(&replica.storage)),
+            None => Err(BlobStorageError::StorageConfigError),
+        },
+        "tiered" => match config.storage_blob_tiered {
+            Some(ref tiered) => tiered
+                .tiers
+                .iter()
+                .try_for_each(|tier| init_blob_storage(&tier.storage)),
+            None => Err(BlobStorageError::StorageConfigError),
+        },
+        _ => Err(BlobStorageError::UnknownBackendError),
+    }
+}
+
+pub fn create_blob_storage(config: BlobStorageConfig) -> Result<Box<dyn BlobStorage>, BlobStorageError> {
+    create_blob_storage_with_migrator(config).map(|(storage, _)| storage)
+}
+
+/// Creates the blob storage, also returns the migrator of the tiered backend
+/// that needs to run in the background if that backend is used.
+pub fn create_blob_storage_with_migrator(
+    config: BlobStorageConfig,
+) -> Result<(Box<dyn BlobStorage>, Option<TierMigrator>), BlobStorageError> {
+    let mut migrator = None;
+    let storage: Box<dyn BlobStorage> = match config.storage_blob_type.as_ref() {
         "mem" => {
-            Ok(Box::new(MemoryBlobStorage::new(config.storage_blob_mem)?))
+            Box::new(MemoryBlobStorage::new(config.storage_blob_mem)?)
         }
         "bucket" => {
-            Ok(Box::new(BucketBlobStorage::new(config.storage_blob_bucket)?))
+            let bucket = config.storage_blob_bucket.ok_or(BlobStorageError::StorageConfigError)?;
+            Box::new(BucketBlobStorage::new(bucket)?)
+        }
+        "fs" => {
+            let fs = config.storage_blob_fs.ok_or(BlobStorageError::StorageConfigError)?;
+            Box::new(FsBlobStorage::new(fs)?)
+        }
+        "s3" => {
+            let s3 = config.storage_blob_s3.ok_or(BlobStorageError::StorageConfigError)?;
+            Box::new(S3BlobStorage::new(s3)?)
+        }
+        "mirror" => {
+            let mirror = config.storage_blob_mirror.ok_or(BlobStorageError::StorageConfigError)?;
+            // every replica is created with its own decorators, e.g. its own encryption
+            let replicas = mirror
+                .replicas
+                .into_iter()
+                .map(|replica| Ok((replica.name, create_blob_storage(replica.storage)?)))
+                .collect::<Result<Vec<_>, BlobStorageError>>()?;
+            Box::new(MirrorBlobStorage::new(replicas, mirror.write_quorum)?)
+        }
+        "tiered" => {
+            let tiered = config.storage_blob_tiered.ok_or(BlobStorageError::StorageConfigError)?;
+            // the migrator replaces the tiered blob refs in the meta storage, they can't be wrapped
+            if config.storage_blob_encryption.is_some()
+                || config.storage_blob_compression.is_some()
+                || config.storage_blob_dedup.is_some()
+            {
+                eprintln!("Factory: encryption, compression and dedup need to be configured per tier!");
+                return Err(BlobStorageError::StorageConfigError);
+            }
+            let tiers = tiered
+                .tiers
+                .into_iter()
+                .map(|tier| Ok(Tier {
+                    name: tier.name,
+                    max_age: tier.max_age,
+                    max_idle: tier.max_idle,
+                    storage: create_blob_storage(tier.storage)?,
+                }))
+                .collect::<Result<Vec<_>, BlobStorageError>>()?;
+            let storage = TieredBlobStorage::new(tiers, tiered.interval)?;
+            migrator = Some(storage.migrator());
+            Box::new(storage)
+        }
+        _ => return Err(BlobStorageError::UnknownBackendError),
+    };
+
+    // blobs are compressed before they are encrypted
+    let storage: Box<dyn BlobStorage> = match config.storage_blob_encryption {
+        Some(encryption) => Box::new(EncryptionBlobStorage::new(encryption, storage)?),
+        None => storage,
+    };
+
+    let storage: Box<dyn BlobStorage> = match config.storage_blob_compression {
+        Some(compression) => Box::new(CompressionBlobStorage::new(compression, storage)?),
+        None => storage,
+    };
+
+    // the reference counts of an in-memory index are lost on restart, blobs of a persistent
+    // backend would be deleted while still in use
+    if let Some(ref dedup) = config.storage_blob_dedup {
+        if dedup.index_path.is_none() && config.storage_blob_type != "mem" {
+            eprintln!("Factory: dedup over a persistent backend requires the index_path!");
+            return Err(BlobStorageError::StorageConfigError);
         }
-        _ => Err(BlobStorageError::UnknownBackendError),
     }
+
+    let storage: Box<dyn BlobStorage> = match config.storage_blob_dedup {
+        Some(dedup) => Box::new(DedupBlobStorage::new(dedup, storage)?),
+        None => storage,
+    };
+
+    // verifies blob contents against the checksum of their meta
+    Ok((Box::new(ChecksumBlobStorage::new(storage)), migrator))
 }
@@ -11,16 +11,18 @@ use blake2::{Blake2b, Blake2s, Digest as BlakeDigest};
 use fasthash::t1ha;
 use sha2::{Digest as Sha2Digest, Sha256, Sha512};
 use sha3::{Sha3_224, Sha3_256, Sha3_384, Sha3_512};
+use std::io::{self, Read};
 use std::str::FromStr;
 use whirlpool::{Digest as WhirlpoolDigest, Whirlpool};
 
 #[derive(Debug)]
-enum HashError {
+pub enum HashError {
     HashTypeParseError,
 }
 
-#[derive(Debug)]
-enum Hash {
+/// Hash algorithms available for checksums and content addressing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hash {
     /// SHA-2 256
     Sha2_256,
 
@@ -53,56 +55,125 @@ enum Hash {
 }
 
 impl Hash {
-    fn hash_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+    /// Returns the name of the hash algorithm, the inverse of `Hash::from_str`.
+    pub fn name(&self) -> &'static str {
         match *self {
-            Hash::Sha2_256 => {
-                let mut h = Sha256::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Sha2_512 => {
-                let mut h = Sha512::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Sha3_224 => {
-                let mut h = Sha3_224::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Sha3_256 => {
-                let mut h = Sha3_256::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Sha3_384 => {
-                let mut h = Sha3_384::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Sha3_512 => {
-                let mut h = Sha3_512::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::T1ha => t1ha::hash64(bytes).to_be_bytes().to_vec(),
-            Hash::Blake2s => {
-                let mut h = Blake2s::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Blake2b => {
-                let mut h = Blake2b::new();
-                h.update(bytes);
-                h.finalize().to_vec()
-            }
-            Hash::Whirlpool => {
-                let mut h = Whirlpool::new();
-                h.update(bytes);
-                h.finalize().to_vec()
+            Hash::Sha2_256 => "sha2_256",
+            Hash::Sha2_512 => "sha2_512",
+            Hash::Sha3_224 => "sha3_224",
+            Hash::Sha3_256 => "sha3_256",
+            Hash::Sha3_384 => "sha3_384",
+            Hash::Sha3_512 => "sha3_512",
+            Hash::T1ha => "t1ha",
+            Hash::Blake2s => "Blake2s",
+            Hash::Blake2b => "Blake2b",
+            Hash::Whirlpool => "whirlpool",
+        }
+    }
+
+    /// Returns false for hash algorithms with practical collisions, they can't address contents.
+    pub fn is_cryptographic(&self) -> bool {
+        *self != Hash::T1ha
+    }
+
+    pub fn hash_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        match self.hasher() {
+            Some(mut hasher) => {
+                hasher.update(bytes);
+                hasher.finalize()
             }
+            None => t1ha::hash64(bytes).to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Returns a new incremental hasher of this algorithm, None for t1ha which has no
+    /// streaming interface, its digests can only be computed with `hash_bytes`.
+    pub fn hasher(&self) -> Option<Hasher> {
+        Some(match *self {
+            Hash::Sha2_256 => Hasher::Sha2_256(Sha256::new()),
+            Hash::Sha2_512 => Hasher::Sha2_512(Sha512::new()),
+            Hash::Sha3_224 => Hasher::Sha3_224(Sha3_224::new()),
+            Hash::Sha3_256 => Hasher::Sha3_256(Sha3_256::new()),
+            Hash::Sha3_384 => Hasher::Sha3_384(Sha3_384::new()),
+            Hash::Sha3_512 => Hasher::Sha3_512(Sha3_512::new()),
+            Hash::T1ha => return None,
+            Hash::Blake2s => Hasher::Blake2s(Blake2s::new()),
+            Hash::Blake2b => Hasher::Blake2b(Blake2b::new()),
+            Hash::Whirlpool => Hasher::Whirlpool(Whirlpool::new()),
+        })
+    }
+}
+
+/// Incremental hash state, bytes are fed with `update` as they become available.
+pub enum Hasher {
+    Sha2_256(Sha256),
+    Sha2_512(Sha512),
+    Sha3_224(Sha3_224),
+    Sha3_256(Sha3_256),
+    Sha3_384(Sha3_384),
+    Sha3_512(Sha3_512),
+    Blake2s(Blake2s),
+    Blake2b(Blake2b),
+    Whirlpool(Whirlpool),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha2_256(h) => h.update(bytes),
+            Hasher::Sha2_512(h) => h.update(bytes),
+            Hasher::Sha3_224(h) => h.update(bytes),
+            Hasher::Sha3_256(h) => h.update(bytes),
+            Hasher::Sha3_384(h) => h.update(bytes),
+            Hasher::Sha3_512(h) => h.update(bytes),
+            Hasher::Blake2s(h) => h.update(bytes),
+            Hasher::Blake2b(h) => h.update(bytes),
+            Hasher::Whirlpool(h) => h.update(bytes),
         }
     }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha2_256(h) => h.finalize().to_vec(),
+            Hasher::Sha2_512(h) => h.finalize().to_vec(),
+            Hasher::Sha3_224(h) => h.finalize().to_vec(),
+            Hasher::Sha3_256(h) => h.finalize().to_vec(),
+            Hasher::Sha3_384(h) => h.finalize().to_vec(),
+            Hasher::Sha3_512(h) => h.finalize().to_vec(),
+            Hasher::Blake2s(h) => h.finalize().to_vec(),
+            Hasher::Blake2b(h) => h.finalize().to_vec(),
+            Hasher::Whirlpool(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// Reader computing the hash of all bytes read through it.
+pub struct HashingReader<R> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Returns None if the hash algorithm has no incremental hasher, see `Hash::hasher`.
+    pub fn new(reader: R, hash: &Hash) -> Option<Self> {
+        Some(Self {
+            reader,
+            hasher: hash.hasher()?,
+        })
+    }
+
+    /// Returns the digest of the bytes read so far.
+    pub fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
 }
 
 impl FromStr for Hash {
@@ -127,9 +198,36 @@ impl FromStr for Hash {
 
 #[cfg(test)]
 mod tests {
-    use super::Hash;
+    use super::{Hash, HashingReader};
     use hex;
+    use std::io::Read;
     use std::concat;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_hashing_reader() {
+        let bytes: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+        for hash in [Hash::Sha2_256, Hash::Blake2b].iter() {
+            let mut reader = HashingReader::new(&bytes[..], hash).unwrap();
+            let mut chunk = [0u8; 333];
+            while reader.read(&mut chunk).unwrap() > 0 {}
+            assert_eq!(reader.finalize(), hash.hash_bytes(&bytes));
+        }
+
+        // t1ha is not hashed while streaming, it would buffer the whole stream:
+        assert!(HashingReader::new(&bytes[..], &Hash::T1ha).is_none());
+    }
+
+    #[test]
+    fn test_hash_names() {
+        let hashes = [
+            Hash::Sha2_256, Hash::Sha2_512, Hash::Sha3_224, Hash::Sha3_256, Hash::Sha3_384,
+            Hash::Sha3_512, Hash::T1ha, Hash::Blake2s, Hash::Blake2b, Hash::Whirlpool,
+        ];
+        for hash in hashes.iter() {
+            assert_eq!(Hash::from_str(hash.name()).unwrap(), *hash);
+        }
+    }
 
     #[test]
     fn test_hash_sha2_256() {
@@ -0,0 +1,422 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Mirrored Blob Storage
+//!
+//! Replicates blobs across multiple named blob storage backends. A blob is written
+//! to all replicas and stored once at least `write_quorum` replicas succeeded, the
+//! reference holds the references of all replicas keyed by their name.
+//!
+//! Reads try the replicas in their configured order and fall back to the next one
+//! if a replica fails or returns contents not matching the checksum of the meta.
+//! Streamed reads only fall back while the stream is opened.
+//!
+//! The references of the replicas are stored individually in the blob reference map
+//! of the meta storage, see `blob_refs` and `blob_ref`.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use typetag::serde;
+
+/// Name of the mirror backend in the configuration and in blob reference maps.
+pub const MIRROR_BACKEND: &str = "mirror";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MirrorBlobRef {
+    /// References of the blob by the name of the replica holding it.
+    pub refs: HashMap<String, Box<dyn BlobRef>>,
+}
+
+#[typetag::serde]
+impl BlobRef for MirrorBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        let mut refs: Vec<String> = self
+            .refs
+            .iter()
+            .map(|(name, blob_ref)| format!("{}: {}", name, blob_ref.display()))
+            .collect();
+        refs.sort();
+        format!("MirrorBlobRef({})", refs.join(", "))
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+/// Returns the blob reference map of a blob stored in the given backend, stored in the
+/// meta storage. The references of mirrored blobs are keyed by the name of their replica.
+pub fn blob_refs(backend: &str, blob_ref: Box<dyn BlobRef>) -> HashMap<String, Box<dyn BlobRef>> {
+    if backend == MIRROR_BACKEND {
+        if let Some(mirrored) = blob_ref.any().downcast_ref::<MirrorBlobRef>() {
+            return mirrored.refs.clone();
+        }
+    }
+    let mut blob_refs = HashMap::new();
+    blob_refs.insert(backend.to_string(), blob_ref);
+    blob_refs
+}
+
+/// Returns the reference of the blob in the given backend from its blob reference map,
+/// the inverse of `blob_refs`.
+pub fn blob_ref(backend: &str, mut blob_refs: HashMap<String, Box<dyn BlobRef>>) -> Option<Box<dyn BlobRef>> {
+    if backend == MIRROR_BACKEND && !blob_refs.contains_key(MIRROR_BACKEND) {
+        if blob_refs.is_empty() {
+            None
+        } else {
+            Some(Box::new(MirrorBlobRef { refs: blob_refs }))
+        }
+    } else {
+        blob_refs.remove(backend)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MirrorReplicaConfig {
+    /// Name of the replica, the key of its references in the meta storage.
+    pub name: String,
+    #[serde(flatten)]
+    pub storage: BlobStorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MirrorBlobStorageConfig {
+    pub replicas: Vec<MirrorReplicaConfig>,
+    /// Number of replicas a blob needs to be written to, all replicas if not set.
+    pub write_quorum: Option<usize>,
+}
+
+pub struct MirrorBlobStorage {
+    replicas: Vec<(String, Box<dyn BlobStorage>)>,
+    write_quorum: usize,
+}
+
+impl MirrorBlobStorage {
+    pub fn new(
+        replicas: Vec<(String, Box<dyn BlobStorage>)>,
+        write_quorum: Option<usize>,
+    ) -> Result<Self, BlobStorageError> {
+        let write_quorum = write_quorum.unwrap_or(replicas.len());
+        if replicas.is_empty() || write_quorum == 0 || write_quorum > replicas.len() {
+            return Err(BlobStorageError::StorageConfigError);
+        }
+        Ok(Self { replicas, write_quorum })
+    }
+
+    fn mirror_ref(blob_ref: &Box<dyn BlobRef>) -> Result<&MirrorBlobRef, BlobStorageError> {
+        blob_ref
+            .any()
+            .downcast_ref::<MirrorBlobRef>()
+            .ok_or(BlobStorageError::ReadBlobRefMismatch)
+    }
+
+    /// Reads from the first replica that succeeds, in the configured order.
+    fn read<T, F>(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>, read: F) -> Result<T, BlobStorageError>
+    where
+        F: Fn(&dyn BlobStorage, &Box<dyn BlobRef>) -> Result<T, BlobStorageError>,
+    {
+        let mirrored = MirrorBlobStorage::mirror_ref(blob_ref)?;
+        let mut result = Err(BlobStorageError::ReadStorageError);
+        for (name, storage) in self.replicas.iter() {
+            if let Some(replica_ref) = mirrored.refs.get(name) {
+                result = read(storage.as_ref(), replica_ref);
+                match result {
+                    Ok(_) => return result,
+                    Err(ref err) => eprintln!("Mirror: error reading {} from replica {}: {:?}", meta, name, err),
+                }
+            }
+        }
+        result
+    }
+
+    /// Writes the blob to all replicas, the written blobs are deleted again if the
+    /// write quorum is not reached.
+    fn write<F>(&self, meta: &BlobMeta, mut write: F) -> Result<Box<dyn BlobRef>, BlobStorageError>
+    where
+        F: FnMut(&dyn BlobStorage) -> Result<Box<dyn BlobRef>, BlobStorageError>,
+    {
+        let mut refs = HashMap::new();
+        for (name, storage) in self.replicas.iter() {
+            match write(storage.as_ref()) {
+                Ok(replica_ref) => {
+                    refs.insert(name.clone(), replica_ref);
+                }
+                Err(err) => eprintln!("Mirror: error writing {} to replica {}: {:?}", meta, name, err),
+            }
+        }
+
+        if refs.len() < self.write_quorum {
+            eprintln!("Mirror: write quorum not reached for {}! written={} quorum={}", meta, refs.len(), self.write_quorum);
+            let written = MirrorBlobRef { refs };
+            if let Err(err) = self.delete(meta, &(Box::new(written) as Box<dyn BlobRef>)) {
+                eprintln!("Mirror: error removing {} from replicas: {:?}", meta, err);
+            }
+            return Err(BlobStorageError::PutError);
+        }
+        Ok(Box::new(MirrorBlobRef { refs }))
+    }
+}
+
+impl BlobStorage for MirrorBlobStorage {
+    /// Reads the blob from the first replica returning it intact.
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, replica_ref| {
+            let buffer = storage.get(meta, replica_ref)?;
+            match meta.checksum {
+                Some(ref checksum) if !checksum.verify(&buffer).unwrap_or(false) => {
+                    Err(BlobStorageError::IntegrityError)
+                }
+                _ => Ok(buffer),
+            }
+        })
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, replica_ref| storage.get_range(meta, replica_ref, offset, len))
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, replica_ref| storage.get_stream(meta, replica_ref))
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.write(meta, |storage| storage.put(meta, buffer.clone()))
+    }
+
+    /// The stream is spooled to a temporary file first, it is read once per replica.
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        let mut file = tempfile::tempfile()?;
+        let written = io::copy(&mut reader.take(meta.size as u64 + 1), &mut file)?;
+        if written != meta.size as u64 {
+            return Err(BlobStorageError::PutError);
+        }
+
+        self.write(meta, |storage| {
+            file.seek(SeekFrom::Start(0))?;
+            storage.put_stream(meta, &mut file)
+        })
+    }
+
+    /// Deletes the blob from all replicas holding it, fails with the names of the replicas
+    /// that still hold it if any of them fails.
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        let mirrored = MirrorBlobStorage::mirror_ref(blob_ref)?;
+        let mut failed = Vec::new();
+        for (name, storage) in self.replicas.iter() {
+            if let Some(replica_ref) = mirrored.refs.get(name) {
+                if let Err(err) = storage.delete(meta, replica_ref) {
+                    eprintln!("Mirror: error deleting {} from replica {}: {:?}", meta, name, err);
+                    failed.push(name.clone());
+                }
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BlobStorageError::ReplicaDeleteError(failed))
+        }
+    }
+
+    /// Rotates the reference of every replica holding the blob, with its own encryption.
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        let mirrored = MirrorBlobStorage::mirror_ref(blob_ref)?;
+        let mut refs = mirrored.refs.clone();
+        let mut rotated = false;
+        for (name, storage) in self.replicas.iter() {
+            if let Some(replica_ref) = mirrored.refs.get(name) {
+                if let Some(replica_ref) = storage.rotate(replica_ref)? {
+                    refs.insert(name.clone(), replica_ref);
+                    rotated = true;
+                }
+            }
+        }
+        Ok(if rotated { Some(Box::new(MirrorBlobRef { refs })) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::{load_fixture};
+    use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+    use crate::storage::blob::backend::bucket::{BucketBlobRef, BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::backend::fs::{FsBlobStorage, FsBlobStorageConfig};
+    use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::blob::mirror::{blob_ref, blob_refs, MirrorBlobRef, MirrorBlobStorage};
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /// Fails every write.
+    struct FailingBlobStorage;
+
+    impl BlobStorage for FailingBlobStorage {
+        fn get(&self, _: &BlobMeta, _: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+            Err(BlobStorageError::ReadStorageError)
+        }
+
+        fn get_range(&self, _: &BlobMeta, _: &Box<dyn BlobRef>, _: usize, _: usize) -> Result<Vec<u8>, BlobStorageError> {
+            Err(BlobStorageError::ReadStorageError)
+        }
+
+        fn get_stream(&self, _: &BlobMeta, _: &Box<dyn BlobRef>) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+            Err(BlobStorageError::ReadStorageError)
+        }
+
+        fn put(&self, _: &BlobMeta, _: Vec<u8>) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+            Err(BlobStorageError::PutError)
+        }
+
+        fn put_stream(&self, _: &BlobMeta, _: &mut dyn Read) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+            Err(BlobStorageError::PutError)
+        }
+
+        fn delete(&self, _: &BlobMeta, _: &Box<dyn BlobRef>) -> Result<(), BlobStorageError> {
+            Err(BlobStorageError::DeleteError)
+        }
+    }
+
+    fn bucket_storage(path: &Path) -> (BucketBlobStorageConfig, Box<dyn BlobStorage>) {
+        let config = BucketBlobStorageConfig {
+            path: path.to_path_buf(),
+            max_size: 1024 * 1024 * 1024 * 24,
+            garbage_ratio: 0.5,
+        };
+        BucketBlobStorage::init(&config).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(config.clone()).expect("error creating the blob storage");
+        (config, Box::new(storage))
+    }
+
+    #[test]
+    fn test_mirror_fallback() {
+        let dir_1 = tempdir().expect("expected to write temporary directory!");
+        let dir_2 = tempdir().expect("expected to write temporary directory!");
+        let (config_1, storage_1) = bucket_storage(dir_1.path());
+        let (_, storage_2) = bucket_storage(dir_2.path());
+        let storage = MirrorBlobStorage::new(
+            vec![("ssd".to_string(), storage_1), ("hdd".to_string(), storage_2)],
+            None,
+        )
+        .expect("mirror blob storage can't be created!");
+
+        let buffer = load_fixture(Path::new("images").join("rgb.png"));
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let reference = storage.put(&meta, buffer.to_vec()).expect("put blob failed!");
+
+        // the references of the replicas are stored individually:
+        let refs = blob_refs("mirror", reference.clone());
+        assert_eq!(refs.len(), 2);
+        let reference = blob_ref("mirror", refs).unwrap();
+
+        // corrupt the blob on the first replica, the second one is used:
+        let mirrored = reference.any().downcast_ref::<MirrorBlobRef>().unwrap();
+        let replica_ref = mirrored.refs["ssd"].any().downcast_ref::<BucketBlobRef>().unwrap();
+        let filename = config_1.path.join(format!("{:08}", replica_ref.bucket));
+        let mut contents = fs::read(&filename).unwrap();
+        contents[replica_ref.offset + 42] ^= 0xff;
+        fs::write(&filename, contents).unwrap();
+        assert_eq!(storage.get(&meta, &reference).expect("get blob failed!"), buffer);
+
+        // remove the first replica entirely:
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(storage.get_range(&meta, &reference, 1, 3).expect("get blob range failed!"), &buffer[1..4]);
+        let mut contents = Vec::new();
+        storage.get_stream(&meta, &reference).expect("get blob failed!")
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, buffer);
+
+        // the blob is still deleted from the second replica:
+        match storage.delete(&meta, &reference) {
+            Err(BlobStorageError::ReplicaDeleteError(failed)) => assert_eq!(failed, vec!["ssd".to_string()]),
+            _ => panic!("expected the delete to fail on the removed replica!"),
+        }
+    }
+
+    #[test]
+    fn test_mirror_quorum() {
+        let replicas = || -> Vec<(String, Box<dyn BlobStorage>)> {
+            vec![
+                ("mem".to_string(), Box::new(MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap())),
+                ("failing".to_string(), Box::new(FailingBlobStorage)),
+                ("other".to_string(), Box::new(MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap())),
+            ]
+        };
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new(buffer.len());
+
+        let storage = MirrorBlobStorage::new(replicas(), Some(2)).unwrap();
+        let reference = storage.put_stream(&meta, &mut &buffer[..]).expect("put blob failed!");
+        let mirrored = reference.any().downcast_ref::<MirrorBlobRef>().unwrap();
+        assert_eq!(mirrored.refs.len(), 2);
+        assert!(!mirrored.refs.contains_key("failing"));
+
+        let storage = MirrorBlobStorage::new(replicas(), None).unwrap();
+        assert!(storage.put(&meta, buffer.to_vec()).is_err());
+
+        assert!(MirrorBlobStorage::new(replicas(), Some(4)).is_err());
+    }
+
+    #[test]
+    fn test_mirror_delete_failure() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = FsBlobStorageConfig { path: dir.path().to_path_buf(), fan_out: 0 };
+        FsBlobStorage::init(&config).expect("Error in init of fs storage!");
+        let fs = Box::new(FsBlobStorage::new(config).expect("error creating the blob storage"));
+        let storage = MirrorBlobStorage::new(
+            vec![("fs".to_string(), fs), ("failing".to_string(), Box::new(FailingBlobStorage))],
+            Some(1),
+        )
+        .expect("mirror blob storage can't be created!");
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new(buffer.len());
+        let reference = storage.put(&meta, buffer.to_vec()).expect("put blob failed!");
+
+        // pretend the failing replica holds a copy as well:
+        let mut refs = reference.any().downcast_ref::<MirrorBlobRef>().unwrap().refs.clone();
+        refs.insert("failing".to_string(), refs["fs"].clone());
+        let reference: Box<dyn BlobRef> = Box::new(MirrorBlobRef { refs });
+
+        match storage.delete(&meta, &reference) {
+            Err(BlobStorageError::ReplicaDeleteError(failed)) => assert_eq!(failed, vec!["failing".to_string()]),
+            _ => panic!("expected the delete to fail on the failing replica!"),
+        }
+        // the blob is still deleted from the healthy replica:
+        assert!(storage.get(&meta, &reference).is_err());
+    }
+}
@@ -2,15 +2,27 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 pub mod backend;
+pub mod blocking;
+pub mod checksum;
+pub mod compression;
+pub mod dedup;
+pub mod encryption;
 pub mod hashing;
+pub mod mirror;
+pub mod tiered;
 pub mod config;
 pub mod factory;
 use crate::domain::meta::BlobMeta;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
 use backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
 use backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
 use std::any::Any;
 use std::fmt;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 use typetag::serde;
 
@@ -25,7 +37,11 @@ pub enum BlobStorageError {
     WriteError,
     PutError,
     DeleteError,
+    /// The blob could not be deleted from the named replicas of a mirrored storage.
+    ReplicaDeleteError(Vec<String>),
     IOError,
+    /// The blob contents do not match the checksum of its meta data.
+    IntegrityError,
 }
 
 impl From<std::io::Error> for BlobStorageError {
@@ -36,7 +52,7 @@ impl From<std::io::Error> for BlobStorageError {
 
 /// Blob References are used to reference previously stored blobs.
 #[typetag::serde(tag = "type", content = "payload")]
-pub trait BlobRef {
+pub trait BlobRef: Send + Sync {
     /// Returns the Any trait of the reference for downcasting to concrete types in backends.
     fn any(&self) -> &dyn Any;
 
@@ -59,21 +75,86 @@ impl Clone for Box<dyn BlobRef + 'static> {
 }
 
 /// Trait all storage backends need to implement.
-pub trait BlobStorage {
+pub trait BlobStorage: Send + Sync {
     /// Reads some binary data from the storage.
     fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError>;
 
+    /// Reads `len` bytes of the binary data starting at `offset` into the blob.
+    /// The range needs to be within the size of the blob meta.
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError>;
+
+    /// Returns a reader of the binary data in the storage, the blob is not read into memory.
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError>;
+
     /// Persists some binary data into the storage.
     fn put(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         buffer: Vec<u8>,
     ) -> Result<Box<dyn BlobRef>, BlobStorageError>;
 
+    /// Persists the binary data read from the reader into the storage, the number of bytes
+    /// read needs to match the size of the blob meta.
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError>;
+
     /// Delete the associated binary data in the storage.
     fn delete(
-        &mut self,
+        &self,
         meta: &BlobMeta,
         blob_ref: &Box<dyn BlobRef>,
     ) -> Result<(), BlobStorageError>;
+
+    /// Rewraps the data keys of encrypted blobs with the current master key, returns the
+    /// new reference of the blob or None if it is unchanged. Decorators rotate the reference
+    /// of their wrapped storage, backends without encryption have nothing to rotate.
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        Ok(None)
+    }
+}
+
+/// Chunks of the binary data of a blob, streamed from or into an `AsyncBlobStorage`.
+pub type BlobStream = Pin<Box<dyn Stream<Item = Result<Bytes, BlobStorageError>> + Send>>;
+
+/// Async counterpart of `BlobStorage` for callers running on an async executor,
+/// streams are passed as chunks.
+#[async_trait]
+pub trait AsyncBlobStorage: Send + Sync {
+    /// Reads some binary data from the storage.
+    async fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError>;
+
+    /// Reads `len` bytes of the binary data starting at `offset` into the blob.
+    async fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError>;
+
+    /// Returns a stream of the binary data in the storage, the blob is not read into memory.
+    async fn get_stream(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<BlobStream, BlobStorageError>;
+
+    /// Persists some binary data into the storage.
+    async fn put(&self, meta: &BlobMeta, buffer: Vec<u8>) -> Result<Box<dyn BlobRef>, BlobStorageError>;
+
+    /// Persists the chunks of the stream into the storage, their total size needs to match
+    /// the size of the blob meta.
+    async fn put_stream(&self, meta: &BlobMeta, stream: BlobStream) -> Result<Box<dyn BlobRef>, BlobStorageError>;
+
+    /// Delete the associated binary data in the storage.
+    async fn delete(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<(), BlobStorageError>;
 }
@@ -0,0 +1,840 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Tiered Blob Storage
+//!
+//! Stores new blobs in the first (hot) tier and moves them to the following (colder)
+//! tiers once they reach the configured age or were not read for the configured time.
+//!
+//! The migration runs as a background job, see `TierMigrator`. A blob is copied to the
+//! next tier, then its blob ref is replaced in the meta storage. The previous copy is
+//! only deleted on the next run of the migrator, so readers still holding the previous
+//! blob ref keep working while a blob is migrated. Its ref is kept in the blob reference
+//! map under `TIERED_RETIRED` until then. A previous copy failing to be deleted a few
+//! times is left to the garbage collection, as an orphan of its tier.
+//!
+//! The blob ref map is replaced with a compare-and-swap, a migration racing with a
+//! rotation or compaction of the same blob drops its copy and is retried on the next run.
+//!
+//! The migrator needs to find the `TieredBlobRef` in the meta storage, so encryption,
+//! compression and deduplication have to be configured per tier instead of around the
+//! tiered backend.
+//!
+//! Access times are tracked in memory. On startup the migrator is seeded from the meta
+//! storage (see `TierMigrator::seed`), blobs stored before are considered idle since the
+//! restart.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use typetag::serde;
+use uuid::Uuid;
+
+/// Name of the tiered backend in the configuration and in blob reference maps.
+pub const TIERED_BACKEND: &str = "tiered";
+
+/// Key of the previous copy of a migrated blob in blob reference maps.
+pub const TIERED_RETIRED: &str = "tiered_retired";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TieredBlobRef {
+    /// Name of the tier holding the blob.
+    pub tier: String,
+    /// Unix timestamp in seconds of when the blob was stored in its tier.
+    pub stored_at: u64,
+    pub blob_ref: Box<dyn BlobRef>,
+}
+
+#[typetag::serde]
+impl BlobRef for TieredBlobRef {
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("TieredBlobRef(tier={}, stored_at={}, {})", self.tier, self.stored_at, self.blob_ref.display())
+    }
+
+    fn clone_as_dyn_blob_ref(&self) -> Box<dyn BlobRef + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierConfig {
+    /// Name of the tier, stored in the blob refs.
+    pub name: String,
+    /// Moves blobs to the next tier once they are stored in this tier for this many seconds.
+    pub max_age: Option<u64>,
+    /// Moves blobs to the next tier once they were not read for this many seconds.
+    pub max_idle: Option<u64>,
+    #[serde(flatten)]
+    pub storage: BlobStorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TieredBlobStorageConfig {
+    /// The tiers ordered from hot to cold.
+    pub tiers: Vec<TierConfig>,
+    /// Seconds between two runs of the migrator.
+    #[serde(default = "TieredBlobStorageConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl TieredBlobStorageConfig {
+    fn default_interval() -> u64 {
+        3600
+    }
+}
+
+pub struct Tier {
+    pub name: String,
+    pub max_age: Option<u64>,
+    pub max_idle: Option<u64>,
+    pub storage: Box<dyn BlobStorage>,
+}
+
+/// Location and last access of a blob that is not in the coldest tier yet.
+#[derive(Debug, Clone)]
+struct TierEntry {
+    tier: usize,
+    stored_at: u64,
+    accessed_at: u64,
+}
+
+/// The tiers are shared with the migrator, only the tracked entries are locked so reads
+/// and writes of the tier backends run concurrently.
+struct Tiers {
+    tiers: Vec<Tier>,
+    entries: Mutex<HashMap<Uuid, TierEntry>>,
+}
+
+impl Tiers {
+    fn position(&self, blob_ref: &TieredBlobRef) -> Result<usize, BlobStorageError> {
+        self.tiers
+            .iter()
+            .position(|tier| tier.name == blob_ref.tier)
+            .ok_or_else(|| {
+                eprintln!("Tiered: unknown tier of blob ref {}", blob_ref.display());
+                BlobStorageError::ReadBlobRefMismatch
+            })
+    }
+
+    fn lock_entries(&self) -> Result<MutexGuard<'_, HashMap<Uuid, TierEntry>>, BlobStorageError> {
+        self.entries.lock().map_err(|_| {
+            eprintln!("Tiered: entries lock is poisoned!");
+            BlobStorageError::ReadStorageError
+        })
+    }
+
+    /// Tracks the blob for migration unless it is in the coldest tier.
+    fn track(&self, meta: &BlobMeta, tier: usize, stored_at: u64, accessed_at: u64) -> Result<(), BlobStorageError> {
+        let mut entries = self.lock_entries()?;
+        if tier + 1 < self.tiers.len() {
+            entries.insert(meta.id, TierEntry { tier, stored_at, accessed_at });
+        } else {
+            entries.remove(&meta.id);
+        }
+        Ok(())
+    }
+
+    /// Tracks the blob for migration unless it is tracked already or in the coldest tier.
+    fn seed(&self, meta: &BlobMeta, tier: usize, stored_at: u64, accessed_at: u64) -> Result<bool, BlobStorageError> {
+        let mut entries = self.lock_entries()?;
+        if tier + 1 < self.tiers.len() && !entries.contains_key(&meta.id) {
+            entries.insert(meta.id, TierEntry { tier, stored_at, accessed_at });
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Stops tracking the blob.
+    fn untrack(&self, id: &Uuid) -> Result<(), BlobStorageError> {
+        self.lock_entries()?.remove(id);
+        Ok(())
+    }
+
+    /// Returns the ids of the blobs due for migration to the next tier.
+    fn due(&self, now: u64) -> Result<Vec<Uuid>, BlobStorageError> {
+        Ok(self
+            .lock_entries()?
+            .iter()
+            .filter(|(_, entry)| {
+                let tier = &self.tiers[entry.tier];
+                let aged = tier.max_age.map_or(false, |max_age| now.saturating_sub(entry.stored_at) >= max_age);
+                let idle = tier.max_idle.map_or(false, |max_idle| now.saturating_sub(entry.accessed_at) >= max_idle);
+                aged || idle
+            })
+            .map(|(id, _)| *id)
+            .collect())
+    }
+}
+
+/// Carries the previous copy of a migrated blob over from the previous to the updated blob
+/// reference map, it still needs to be deleted by the migrator.
+pub fn keep_retired(
+    previous: &HashMap<String, Box<dyn BlobRef>>,
+    mut blob_refs: HashMap<String, Box<dyn BlobRef>>,
+) -> HashMap<String, Box<dyn BlobRef>> {
+    if let Some(retired) = previous.get(TIERED_RETIRED) {
+        blob_refs.entry(TIERED_RETIRED.to_string()).or_insert_with(|| retired.clone());
+    }
+    blob_refs
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+pub struct TieredBlobStorage {
+    tiers: Arc<Tiers>,
+    interval: u64,
+}
+
+impl TieredBlobStorage {
+    pub fn new(tiers: Vec<Tier>, interval: u64) -> Result<Self, BlobStorageError> {
+        if tiers.is_empty() {
+            return Err(BlobStorageError::StorageConfigError);
+        }
+        let tiers = Tiers { tiers, entries: Mutex::new(HashMap::new()) };
+        Ok(Self { tiers: Arc::new(tiers), interval })
+    }
+
+    /// Returns the migrator moving the blobs of this storage between its tiers.
+    pub fn migrator(&self) -> TierMigrator {
+        TierMigrator {
+            tiers: self.tiers.clone(),
+            interval: Duration::from_secs(self.interval),
+            retired: Vec::new(),
+        }
+    }
+
+    fn tiered_ref(blob_ref: &Box<dyn BlobRef>) -> Result<&TieredBlobRef, BlobStorageError> {
+        blob_ref
+            .any()
+            .downcast_ref::<TieredBlobRef>()
+            .ok_or(BlobStorageError::ReadBlobRefMismatch)
+    }
+
+    /// Reads from the tier holding the blob and records the access.
+    fn read<T, F>(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>, read: F) -> Result<T, BlobStorageError>
+    where
+        F: FnOnce(&dyn BlobStorage, &Box<dyn BlobRef>) -> Result<T, BlobStorageError>,
+    {
+        let tiered_ref = TieredBlobStorage::tiered_ref(blob_ref)?;
+        let position = self.tiers.position(tiered_ref)?;
+        let result = read(self.tiers.tiers[position].storage.as_ref(), &tiered_ref.blob_ref)?;
+        // the blob might be read with the blob ref of its previous tier during migration
+        let mut entries = self.tiers.lock_entries()?;
+        match entries.get_mut(&meta.id) {
+            Some(entry) => entry.accessed_at = unix_now(),
+            None => {
+                drop(entries);
+                self.tiers.track(meta, position, tiered_ref.stored_at, unix_now())?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Writes the blob to the first tier.
+    fn write<F>(&self, meta: &BlobMeta, write: F) -> Result<Box<dyn BlobRef>, BlobStorageError>
+    where
+        F: FnOnce(&dyn BlobStorage) -> Result<Box<dyn BlobRef>, BlobStorageError>,
+    {
+        let blob_ref = write(self.tiers.tiers[0].storage.as_ref())?;
+        let now = unix_now();
+        self.tiers.track(meta, 0, now, now)?;
+        Ok(Box::new(TieredBlobRef { tier: self.tiers.tiers[0].name.clone(), stored_at: now, blob_ref }))
+    }
+}
+
+impl BlobStorage for TieredBlobStorage {
+    fn get(&self, meta: &BlobMeta, blob_ref: &Box<dyn BlobRef>) -> Result<Vec<u8>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, tier_ref| storage.get(meta, tier_ref))
+    }
+
+    fn get_range(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, tier_ref| storage.get_range(meta, tier_ref, offset, len))
+    }
+
+    fn get_stream(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<Box<dyn Read + Send>, BlobStorageError> {
+        self.read(meta, blob_ref, |storage, tier_ref| storage.get_stream(meta, tier_ref))
+    }
+
+    fn put(
+        &self,
+        meta: &BlobMeta,
+        buffer: Vec<u8>,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.write(meta, |storage| storage.put(meta, buffer))
+    }
+
+    fn put_stream(
+        &self,
+        meta: &BlobMeta,
+        reader: &mut dyn Read,
+    ) -> Result<Box<dyn BlobRef>, BlobStorageError> {
+        self.write(meta, |storage| storage.put_stream(meta, reader))
+    }
+
+    fn delete(
+        &self,
+        meta: &BlobMeta,
+        blob_ref: &Box<dyn BlobRef>,
+    ) -> Result<(), BlobStorageError> {
+        let tiered_ref = TieredBlobStorage::tiered_ref(blob_ref)?;
+        let position = self.tiers.position(tiered_ref)?;
+        self.tiers.tiers[position].storage.delete(meta, &tiered_ref.blob_ref)?;
+        self.tiers.untrack(&meta.id)
+    }
+
+    /// Rotates the reference in the tier holding the blob, with its own encryption.
+    fn rotate(&self, blob_ref: &Box<dyn BlobRef>) -> Result<Option<Box<dyn BlobRef>>, BlobStorageError> {
+        let tiered_ref = TieredBlobStorage::tiered_ref(blob_ref)?;
+        let position = self.tiers.position(tiered_ref)?;
+        Ok(self.tiers.tiers[position].storage.rotate(&tiered_ref.blob_ref)?.map(|rotated| {
+            Box::new(TieredBlobRef { blob_ref: rotated, ..tiered_ref.clone() }) as Box<dyn BlobRef>
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum TieringError {
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+}
+
+impl From<BlobStorageError> for TieringError {
+    fn from(error: BlobStorageError) -> Self {
+        TieringError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for TieringError {
+    fn from(error: MetaStorageError) -> Self {
+        TieringError::MetaStorageError(error)
+    }
+}
+
+impl From<std::io::Error> for TieringError {
+    fn from(error: std::io::Error) -> Self {
+        TieringError::BlobStorageError(error.into())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SeedReport {
+    /// Number of blobs tracked for migration.
+    pub tracked: usize,
+    /// Number of previous copies still to be deleted.
+    pub retired: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct TieringReport {
+    /// Number of blobs moved to the next tier.
+    pub migrated: usize,
+    /// Number of previous copies deleted from the tier they were moved away from.
+    pub retired: usize,
+    /// Number of blobs that failed to migrate or whose previous copy failed to be deleted,
+    /// they are retried on the next run.
+    pub failed: usize,
+    /// Number of previous copies that failed to be deleted on every attempt, their refs
+    /// are removed and the copies left to the garbage collection.
+    pub abandoned: usize,
+}
+
+/// Number of meta objects listed at once.
+const PAGE_SIZE: usize = 1000;
+
+/// Number of runs trying to delete the previous copy of a migrated blob.
+const MAX_RETIRE_ATTEMPTS: usize = 3;
+
+/// Previous copy of a migrated blob, deleted on the next run.
+struct Retired {
+    meta: BlobMeta,
+    tiered_ref: TieredBlobRef,
+    /// Number of failed attempts to delete the copy.
+    attempts: usize,
+}
+
+/// Background job moving blobs of a `TieredBlobStorage` to colder tiers.
+pub struct TierMigrator {
+    tiers: Arc<Tiers>,
+    interval: Duration,
+    /// Copies of blobs moved in the previous run, deleted on the next run.
+    retired: Vec<Retired>,
+}
+
+impl TierMigrator {
+    /// The configured time between two runs.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Tracks the blobs stored before the start of the service and the previous copies
+    /// left by an earlier migrator, the blobs are considered idle since `now`.
+    pub fn seed(&mut self, meta_storage: &dyn MetaStorage) -> Result<SeedReport, TieringError> {
+        self.seed_at(meta_storage, unix_now())
+    }
+
+    fn seed_at(&mut self, meta_storage: &dyn MetaStorage, now: u64) -> Result<SeedReport, TieringError> {
+        let mut report = SeedReport::default();
+        let mut after = None;
+        loop {
+            let page = meta_storage.list(after, PAGE_SIZE)?;
+            after = match page.last() {
+                Some(meta) => Some(meta.id),
+                None => break,
+            };
+
+            for meta in page {
+                let mut blob_refs = match meta_storage.get_blob_refs(meta.id)? {
+                    Some(blob_refs) => blob_refs,
+                    None => continue,
+                };
+                if let Some(blob_ref) = blob_refs.remove(TIERED_BACKEND) {
+                    let tiered_ref = TieredBlobStorage::tiered_ref(&blob_ref)?;
+                    let position = self.tiers.position(tiered_ref)?;
+                    if self.tiers.seed(&meta, position, tiered_ref.stored_at, now)? {
+                        report.tracked += 1;
+                    }
+                }
+                if let Some(retired) = blob_refs.remove(TIERED_RETIRED) {
+                    let tiered_ref = TieredBlobStorage::tiered_ref(&retired)?.clone();
+                    if !self.retired.iter().any(|retired| retired.meta.id == meta.id) {
+                        self.retired.push(Retired { meta, tiered_ref, attempts: 0 });
+                        report.retired += 1;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Moves all blobs due for migration to their next tier, the storage keeps serving
+    /// reads and writes while the migration runs.
+    pub fn run(&mut self, meta_storage: &dyn MetaStorage) -> TieringReport {
+        self.run_at(meta_storage, unix_now())
+    }
+
+    fn run_at(&mut self, meta_storage: &dyn MetaStorage, now: u64) -> TieringReport {
+        let mut report = TieringReport::default();
+
+        for mut retired in std::mem::take(&mut self.retired) {
+            let err = match self.retire(meta_storage, &retired.meta, &retired.tiered_ref) {
+                Ok(()) => {
+                    report.retired += 1;
+                    continue;
+                }
+                Err(err) => err,
+            };
+            eprintln!("Tiered: error deleting previous copy {}: {:?}", retired.tiered_ref.display(), err);
+            retired.attempts += 1;
+            // stop blocking the migration of the blob, the copy is an orphan from now on
+            if retired.attempts >= MAX_RETIRE_ATTEMPTS && self.forget(meta_storage, retired.meta.id).is_ok() {
+                eprintln!("Tiered: previous copy {} is left to the garbage collection", retired.tiered_ref.display());
+                report.abandoned += 1;
+                continue;
+            }
+            report.failed += 1;
+            self.retired.push(retired);
+        }
+
+        let due = match self.tiers.due(now) {
+            Ok(due) => due,
+            Err(_) => return report,
+        };
+        for id in due {
+            match self.migrate(meta_storage, id, now) {
+                Ok(true) => report.migrated += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    eprintln!("Tiered: error migrating blob {}: {:?}", id, err);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        report
+    }
+
+    fn delete(&self, meta: &BlobMeta, tiered_ref: &TieredBlobRef) -> Result<(), BlobStorageError> {
+        let position = self.tiers.position(tiered_ref)?;
+        self.tiers.tiers[position].storage.delete(meta, &tiered_ref.blob_ref)
+    }
+
+    /// Deletes the previous copy of a migrated blob and removes its ref from the meta storage.
+    fn retire(&self, meta_storage: &dyn MetaStorage, meta: &BlobMeta, tiered_ref: &TieredBlobRef) -> Result<(), TieringError> {
+        self.delete(meta, tiered_ref)?;
+        self.forget(meta_storage, meta.id)
+    }
+
+    /// Removes the ref of the previous copy from the meta storage, retried if the refs
+    /// were changed concurrently.
+    fn forget(&self, meta_storage: &dyn MetaStorage, id: Uuid) -> Result<(), TieringError> {
+        loop {
+            let expected = match meta_storage.get_blob_refs(id)? {
+                Some(blob_refs) if blob_refs.contains_key(TIERED_RETIRED) => blob_refs,
+                _ => return Ok(()),
+            };
+            let mut blob_refs = expected.clone();
+            blob_refs.remove(TIERED_RETIRED);
+            if meta_storage.compare_and_update_blob_refs(id, &expected, blob_refs)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Copies the blob to the next tier and replaces its blob ref in the meta storage,
+    /// returns false if the blob is gone.
+    fn migrate(
+        &mut self,
+        meta_storage: &dyn MetaStorage,
+        id: Uuid,
+        now: u64,
+    ) -> Result<bool, TieringError> {
+        let (meta, expected) = (meta_storage.get_meta(id)?, meta_storage.get_blob_refs(id)?);
+        let (meta, expected, tiered_ref) = match (meta, expected) {
+            (Some(meta), Some(expected)) if expected.contains_key(TIERED_BACKEND) => {
+                let tiered_ref = TieredBlobStorage::tiered_ref(&expected[TIERED_BACKEND])?.clone();
+                (meta, expected, tiered_ref)
+            }
+            _ => {
+                self.tiers.untrack(&id)?;
+                return Ok(false);
+            }
+        };
+
+        // the previous copy of the last migration failed to be deleted, it is retried first
+        if expected.contains_key(TIERED_RETIRED) {
+            return Ok(false);
+        }
+
+        let position = self.tiers.position(&tiered_ref)?;
+        let next = position + 1;
+        if next >= self.tiers.tiers.len() {
+            self.tiers.untrack(&id)?;
+            return Ok(false);
+        }
+
+        // spool the blob, the next tier needs to know its size
+        let mut reader = self.tiers.tiers[position].storage.get_stream(&meta, &tiered_ref.blob_ref)?;
+        let mut file = tempfile::tempfile()?;
+        io::copy(&mut reader, &mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let blob_ref = self.tiers.tiers[next].storage.put_stream(&meta, &mut file)?;
+        let next_ref = TieredBlobRef { tier: self.tiers.tiers[next].name.clone(), stored_at: now, blob_ref };
+        let accessed_at = self.tiers.lock_entries()?.get(&id).map_or(now, |entry| entry.accessed_at);
+
+        let mut blob_refs = expected.clone();
+        blob_refs.insert(TIERED_BACKEND.to_string(), Box::new(next_ref.clone()));
+        blob_refs.insert(TIERED_RETIRED.to_string(), Box::new(tiered_ref.clone()));
+        if !meta_storage.compare_and_update_blob_refs(id, &expected, blob_refs)? {
+            // the blob was deleted or its refs were changed while it was copied, a changed
+            // blob stays due and is migrated on the next run
+            self.delete(&meta, &next_ref)?;
+            if meta_storage.get_meta(id)?.is_none() {
+                self.tiers.untrack(&id)?;
+            }
+            return Ok(false);
+        }
+
+        self.tiers.track(&meta, next, now, accessed_at)?;
+        self.retired.push(Retired { meta, tiered_ref, attempts: 0 });
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::{BlobRef, BlobStorage};
+    use crate::storage::blob::backend::fs::{FsBlobStorage, FsBlobStorageConfig};
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::blob::tiered::{Tier, TieredBlobRef, TieredBlobStorage, TIERED_BACKEND, TIERED_RETIRED};
+    use crate::storage::meta::{MetaStorage, MetaStorageError};
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use crate::storage::blob::backend::fs::FsBlobRef;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    /// Changes the blob refs right after they are read once the race is armed, like a
+    /// rotation running at the same time.
+    struct RacingMetaStorage {
+        storage: MemoryMetaStorage,
+        race: AtomicBool,
+    }
+
+    impl MetaStorage for RacingMetaStorage {
+        fn put(&self, meta: BlobMeta, blob_refs: HashMap<String, Box<dyn BlobRef>>) -> Result<(), MetaStorageError> {
+            self.storage.put(meta, blob_refs)
+        }
+
+        fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+            self.storage.get_meta(id)
+        }
+
+        fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+            let blob_refs = self.storage.get_blob_refs(id)?;
+            if let Some(blob_refs) = &blob_refs {
+                if self.race.swap(false, Ordering::SeqCst) {
+                    let mut changed = blob_refs.clone();
+                    changed.insert("other".to_string(), blob_refs[TIERED_BACKEND].clone());
+                    self.storage.update_blob_refs(id, changed)?;
+                }
+            }
+            Ok(blob_refs)
+        }
+
+        fn update_blob_refs(&self, id: Uuid, blob_refs: HashMap<String, Box<dyn BlobRef>>) -> Result<bool, MetaStorageError> {
+            self.storage.update_blob_refs(id, blob_refs)
+        }
+
+        fn compare_and_update_blob_refs(
+            &self,
+            id: Uuid,
+            expected: &HashMap<String, Box<dyn BlobRef>>,
+            blob_refs: HashMap<String, Box<dyn BlobRef>>,
+        ) -> Result<bool, MetaStorageError> {
+            self.storage.compare_and_update_blob_refs(id, expected, blob_refs)
+        }
+
+        fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+            self.storage.update_meta(id, meta)
+        }
+
+        fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+            self.storage.list(after, limit)
+        }
+
+        fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
+            self.storage.delete(id)
+        }
+    }
+
+    fn tier(path: &Path, name: &str, max_age: Option<u64>, max_idle: Option<u64>) -> Tier {
+        let config = FsBlobStorageConfig { path: path.join(name), fan_out: 1 };
+        FsBlobStorage::init(&config).expect("Error in init of fs storage!");
+        Tier {
+            name: name.to_string(),
+            max_age,
+            max_idle,
+            storage: Box::new(FsBlobStorage::new(config).expect("error creating the blob storage")),
+        }
+    }
+
+    fn tier_of(meta_storage: &dyn MetaStorage, meta: &BlobMeta) -> (String, Box<dyn BlobRef>) {
+        let blob_refs = meta_storage.get_blob_refs(meta.id).unwrap().unwrap();
+        let blob_ref = blob_refs[TIERED_BACKEND].clone();
+        let tier = blob_ref.any().downcast_ref::<TieredBlobRef>().unwrap().tier.clone();
+        (tier, blob_ref)
+    }
+
+    #[test]
+    fn test_tiered_migration() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let storage = TieredBlobStorage::new(vec![
+            tier(dir.path(), "hot", Some(3600), None),
+            tier(dir.path(), "warm", None, Some(600)),
+            tier(dir.path(), "cold", None, None),
+        ], 60).unwrap();
+        let mut migrator = storage.migrator();
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let reference = storage.put(&meta, buffer.to_vec()).unwrap();
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert(TIERED_BACKEND.to_string(), reference.clone());
+        meta_storage.put(meta.clone(), blob_refs).unwrap();
+        let stored_at = reference.any().downcast_ref::<TieredBlobRef>().unwrap().stored_at;
+
+        // nothing is due yet:
+        let report = migrator.run_at(&meta_storage, stored_at + 60);
+        assert_eq!((report.migrated, report.retired), (0, 0));
+        assert_eq!(tier_of(&meta_storage, &meta).0, "hot");
+
+        // moved by age, the previous copy is kept until the next run:
+        let report = migrator.run_at(&meta_storage, stored_at + 3600);
+        assert_eq!((report.migrated, report.retired, report.failed), (1, 0, 0));
+        let (tier, migrated) = tier_of(&meta_storage, &meta);
+        assert_eq!(tier, "warm");
+        assert_eq!(storage.get(&meta, &reference).unwrap(), buffer);
+        assert_eq!(storage.get(&meta, &migrated).unwrap(), buffer);
+
+        // moved by idle time, the previous copy of the first run is deleted:
+        let report = migrator.run_at(&meta_storage, stored_at + 3600);
+        assert_eq!((report.migrated, report.retired), (1, 1));
+        assert!(storage.get(&meta, &reference).is_err());
+        let (tier, cold) = tier_of(&meta_storage, &meta);
+        assert_eq!(tier, "cold");
+        assert_eq!(storage.get_range(&meta, &cold, 1, 3).unwrap(), &buffer[1..4]);
+
+        // the coldest tier is final:
+        let report = migrator.run_at(&meta_storage, stored_at + 100000);
+        assert_eq!((report.migrated, report.retired), (0, 1));
+        assert!(storage.get(&meta, &migrated).is_err());
+
+        storage.delete(&meta, &cold).unwrap();
+        assert!(storage.get(&meta, &cold).is_err());
+    }
+
+    #[test]
+    fn test_tiered_without_meta() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let storage = TieredBlobStorage::new(vec![
+            tier(dir.path(), "hot", Some(0), None),
+            tier(dir.path(), "cold", None, None),
+        ], 60).unwrap();
+        let mut migrator = storage.migrator();
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        // the blob has no meta, it is not migrated:
+        let meta = BlobMeta::new(3);
+        storage.put(&meta, vec![1, 2, 3]).unwrap();
+        let report = migrator.run_at(&meta_storage, super::unix_now());
+        assert_eq!((report.migrated, report.failed), (0, 0));
+    }
+
+    #[test]
+    fn test_tiered_seed() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let tiers = || vec![
+            tier(dir.path(), "hot", Some(3600), None),
+            tier(dir.path(), "warm", None, Some(600)),
+            tier(dir.path(), "cold", None, None),
+        ];
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let (reference, stored_at) = {
+            let storage = TieredBlobStorage::new(tiers(), 60).unwrap();
+            let reference = storage.put(&meta, buffer.to_vec()).unwrap();
+            let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+            blob_refs.insert(TIERED_BACKEND.to_string(), reference.clone());
+            meta_storage.put(meta.clone(), blob_refs).unwrap();
+            let stored_at = reference.any().downcast_ref::<TieredBlobRef>().unwrap().stored_at;
+
+            // the previous copy is recorded in the meta storage:
+            let report = storage.migrator().run_at(&meta_storage, stored_at + 3600);
+            assert_eq!(report.migrated, 1);
+            assert!(meta_storage.get_blob_refs(meta.id).unwrap().unwrap().contains_key(TIERED_RETIRED));
+            (reference, stored_at)
+        };
+
+        // after a restart the blob is tracked again and the previous copy is deleted:
+        let storage = TieredBlobStorage::new(tiers(), 60).unwrap();
+        let mut migrator = storage.migrator();
+        let report = migrator.seed_at(&meta_storage, stored_at + 3600).unwrap();
+        assert_eq!((report.tracked, report.retired), (1, 1));
+        assert_eq!(storage.get(&meta, &reference).unwrap(), buffer);
+
+        let report = migrator.run_at(&meta_storage, stored_at + 4200);
+        assert_eq!((report.migrated, report.retired, report.failed), (1, 1, 0));
+        assert!(storage.get(&meta, &reference).is_err());
+        assert_eq!(tier_of(&meta_storage, &meta).0, "cold");
+    }
+
+    #[test]
+    fn test_tiered_concurrent_update() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let storage = TieredBlobStorage::new(vec![
+            tier(dir.path(), "hot", Some(3600), None),
+            tier(dir.path(), "cold", None, None),
+        ], 60).unwrap();
+        let mut migrator = storage.migrator();
+        let meta_storage = RacingMetaStorage {
+            storage: MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap(),
+            race: AtomicBool::new(false),
+        };
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let reference = storage.put(&meta, buffer.to_vec()).unwrap();
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert(TIERED_BACKEND.to_string(), reference.clone());
+        meta_storage.put(meta.clone(), blob_refs).unwrap();
+        let stored_at = reference.any().downcast_ref::<TieredBlobRef>().unwrap().stored_at;
+
+        // the refs change while the blob is copied, the copy is dropped:
+        meta_storage.race.store(true, Ordering::SeqCst);
+        let report = migrator.run_at(&meta_storage, stored_at + 3600);
+        assert_eq!((report.migrated, report.failed), (0, 0));
+        assert_eq!(tier_of(&meta_storage, &meta).0, "hot");
+        assert!(meta_storage.get_blob_refs(meta.id).unwrap().unwrap().contains_key("other"));
+        let fan_out = fs::read_dir(dir.path().join("cold")).unwrap();
+        assert_eq!(fan_out.flat_map(|entry| fs::read_dir(entry.unwrap().path()).unwrap()).count(), 0);
+
+        // still due on the next run:
+        let report = migrator.run_at(&meta_storage, stored_at + 3600);
+        assert_eq!(report.migrated, 1);
+        let blob_refs = meta_storage.get_blob_refs(meta.id).unwrap().unwrap();
+        assert!(blob_refs.contains_key("other"));
+        assert_eq!(tier_of(&meta_storage, &meta).0, "cold");
+    }
+
+    #[test]
+    fn test_tiered_retire_failure() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let storage = TieredBlobStorage::new(vec![
+            tier(dir.path(), "hot", Some(3600), None),
+            tier(dir.path(), "warm", Some(3600), None),
+            tier(dir.path(), "cold", None, None),
+        ], 60).unwrap();
+        let mut migrator = storage.migrator();
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let reference = storage.put(&meta, buffer.to_vec()).unwrap();
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert(TIERED_BACKEND.to_string(), reference.clone());
+        meta_storage.put(meta.clone(), blob_refs).unwrap();
+        let tiered_ref = reference.any().downcast_ref::<TieredBlobRef>().unwrap();
+        let stored_at = tiered_ref.stored_at;
+
+        let report = migrator.run_at(&meta_storage, stored_at + 3600);
+        assert_eq!(report.migrated, 1);
+
+        // the previous copy can't be deleted, which blocks the next migration:
+        let path = &tiered_ref.blob_ref.any().downcast_ref::<FsBlobRef>().unwrap().path;
+        fs::remove_file(dir.path().join("hot").join(path)).unwrap();
+        for _ in 1..super::MAX_RETIRE_ATTEMPTS {
+            let report = migrator.run_at(&meta_storage, stored_at + 7200);
+            assert_eq!((report.migrated, report.retired, report.failed, report.abandoned), (0, 0, 1, 0));
+            assert_eq!(tier_of(&meta_storage, &meta).0, "warm");
+        }
+
+        // after the last attempt the copy is left to the garbage collection:
+        let report = migrator.run_at(&meta_storage, stored_at + 7200);
+        assert_eq!((report.migrated, report.failed, report.abandoned), (1, 0, 1));
+        assert_eq!(tier_of(&meta_storage, &meta).0, "cold");
+    }
+}
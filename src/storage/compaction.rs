@@ -0,0 +1,303 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Bucket Compaction
+//!
+//! Rewrites the bucket files with too much garbage in all bucket backends of the blob
+//! storage (see `BucketBlobStorage::compact`) and updates the blob refs of the meta
+//! objects to the relocated blobs. The bucket refs are rebuilt through the refs of the
+//! decorating, mirrored and tiered storages.
+//!
+//! The compaction is refused with deduplication configured, the digest index would
+//! still point at the previous bucket refs. It needs to run while the service is
+//! stopped, blobs of a rewritten bucket can't be read until their meta is updated. An
+//! interrupted update is completed by the next compaction.
+//!
+use crate::storage::blob::backend::bucket::{self, BucketBlobRef, BucketBlobStorage};
+use crate::storage::blob::compression::CompressedBlobRef;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::blob::encryption::EncryptedBlobRef;
+use crate::storage::blob::mirror::{blob_ref, blob_refs, MirrorBlobRef};
+use crate::storage::blob::tiered::{keep_retired, TieredBlobRef, TIERED_RETIRED};
+use crate::storage::blob::{BlobRef, BlobStorageError};
+use crate::storage::gc::{join, uses_dedup};
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use std::collections::HashMap;
+
+/// Number of meta objects listed at once.
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug)]
+pub enum CompactionError {
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+    /// Compacting the buckets of a deduplicated storage is not supported.
+    DedupCompactionError,
+}
+
+impl From<BlobStorageError> for CompactionError {
+    fn from(error: BlobStorageError) -> Self {
+        CompactionError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for CompactionError {
+    fn from(error: MetaStorageError) -> Self {
+        CompactionError::MetaStorageError(error)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CompactionSummary {
+    /// Rewritten bucket files by the location of their backend, like `mirror/ssd`, empty
+    /// for the configured backend itself.
+    pub buckets: Vec<(String, usize)>,
+    /// Number of bytes reclaimed.
+    pub reclaimed: u64,
+    /// Number of meta objects whose blob ref was updated.
+    pub updated: usize,
+}
+
+/// Compacts the bucket backends of the storage config, keyed by their location.
+fn compact_backends(
+    config: &BlobStorageConfig,
+    location: &str,
+    reports: &mut HashMap<String, bucket::CompactionReport>,
+) -> Result<(), CompactionError> {
+    match config.storage_blob_type.as_ref() {
+        "bucket" => {
+            let bucket = config.storage_blob_bucket.clone().ok_or(BlobStorageError::StorageConfigError)?;
+            let report = BucketBlobStorage::new(bucket)?.compact()?;
+            reports.insert(location.to_string(), report);
+        }
+        "mirror" => {
+            let mirror = config.storage_blob_mirror.as_ref().ok_or(BlobStorageError::StorageConfigError)?;
+            for replica in mirror.replicas.iter() {
+                compact_backends(&replica.storage, &join(location, "mirror", &replica.name), reports)?;
+            }
+        }
+        "tiered" => {
+            let tiered = config.storage_blob_tiered.as_ref().ok_or(BlobStorageError::StorageConfigError)?;
+            for tier in tiered.tiers.iter() {
+                compact_backends(&tier.storage, &join(location, "tiered", &tier.name), reports)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns the blob ref with the bucket refs relocated by the compaction, None if none of
+/// them was relocated.
+fn relocate(
+    config: &BlobStorageConfig,
+    location: &str,
+    blob_ref: &Box<dyn BlobRef>,
+    reports: &HashMap<String, bucket::CompactionReport>,
+) -> Option<Box<dyn BlobRef>> {
+    // refs of the decorating storages wrap the ref of the backend
+    let any = blob_ref.any();
+    if let Some(wrapped) = any.downcast_ref::<CompressedBlobRef>() {
+        let relocated = relocate(config, location, &wrapped.blob_ref, reports)?;
+        return Some(Box::new(CompressedBlobRef { blob_ref: relocated, ..wrapped.clone() }));
+    }
+    if let Some(wrapped) = any.downcast_ref::<EncryptedBlobRef>() {
+        let relocated = relocate(config, location, &wrapped.blob_ref, reports)?;
+        return Some(Box::new(EncryptedBlobRef { blob_ref: relocated, ..wrapped.clone() }));
+    }
+
+    match config.storage_blob_type.as_ref() {
+        "bucket" => {
+            let bucket_ref = any.downcast_ref::<BucketBlobRef>()?;
+            let relocated = reports.get(location)?.relocate(bucket_ref)?;
+            Some(Box::new(relocated))
+        }
+        "mirror" => {
+            let mirror = config.storage_blob_mirror.as_ref()?;
+            let mirrored = any.downcast_ref::<MirrorBlobRef>()?;
+            let mut refs = mirrored.refs.clone();
+            let mut relocated = false;
+            for replica in mirror.replicas.iter() {
+                let location = join(location, "mirror", &replica.name);
+                if let Some(replica_ref) = mirrored.refs.get(&replica.name) {
+                    if let Some(replica_ref) = relocate(&replica.storage, &location, replica_ref, reports) {
+                        refs.insert(replica.name.clone(), replica_ref);
+                        relocated = true;
+                    }
+                }
+            }
+            if relocated {
+                Some(Box::new(MirrorBlobRef { refs }))
+            } else {
+                None
+            }
+        }
+        "tiered" => {
+            let tiered = config.storage_blob_tiered.as_ref()?;
+            let tiered_ref = any.downcast_ref::<TieredBlobRef>()?;
+            let tier = tiered.tiers.iter().find(|tier| tier.name == tiered_ref.tier)?;
+            let location = join(location, "tiered", &tier.name);
+            let relocated = relocate(&tier.storage, &location, &tiered_ref.blob_ref, reports)?;
+            Some(Box::new(TieredBlobRef { blob_ref: relocated, ..tiered_ref.clone() }))
+        }
+        _ => None,
+    }
+}
+
+/// Compacts the bucket backends and updates the blob refs of all meta objects, looked up
+/// under the given backend name.
+pub fn compact_buckets(
+    config: &BlobStorageConfig,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+) -> Result<CompactionSummary, CompactionError> {
+    if uses_dedup(config) {
+        return Err(CompactionError::DedupCompactionError);
+    }
+
+    let mut reports = HashMap::new();
+    compact_backends(config, "", &mut reports)?;
+
+    let mut summary = CompactionSummary::default();
+    for (location, report) in reports.iter() {
+        summary.buckets.extend(report.buckets.iter().map(|index| (location.clone(), *index)));
+        summary.reclaimed += report.reclaimed;
+    }
+    if summary.buckets.is_empty() {
+        return Ok(summary);
+    }
+
+    let mut after = None;
+    loop {
+        let page = meta_storage.list(after, PAGE_SIZE)?;
+        after = match page.last() {
+            Some(meta) => Some(meta.id),
+            None => break,
+        };
+
+        for meta in page {
+            let refs = match meta_storage.get_blob_refs(meta.id)? {
+                Some(refs) => refs,
+                None => continue,
+            };
+            let current = match blob_ref(backend, refs.clone()) {
+                Some(current) => current,
+                None => continue,
+            };
+            // the previous copy of a migrated blob is relocated as well
+            let retired = refs.get(TIERED_RETIRED).and_then(|retired| relocate(config, "", retired, &reports));
+            let relocated = relocate(config, "", &current, &reports);
+            if relocated.is_none() && retired.is_none() {
+                continue;
+            }
+
+            let mut updated = keep_retired(&refs, blob_refs(backend, relocated.unwrap_or(current)));
+            if let Some(retired) = retired {
+                updated.insert(TIERED_RETIRED.to_string(), retired);
+            }
+            // false if the meta was deleted in the meantime
+            if meta_storage.update_blob_refs(meta.id, updated)? {
+                summary.updated += 1;
+            }
+        }
+    }
+
+    // the relocation logs are replayed by the next compaction until the refs are updated
+    for report in reports.values() {
+        report.commit()?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::config::BlobStorageConfig;
+    use crate::storage::blob::factory::{create_blob_storage, init_blob_storage};
+    use crate::storage::blob::mirror::{blob_ref, blob_refs};
+    use crate::storage::compaction::{compact_buckets, CompactionError};
+    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use tempfile::tempdir;
+
+    fn config(yaml: &str) -> BlobStorageConfig {
+        serde_yaml::from_str(yaml).expect("invalid blob storage config!")
+    }
+
+    #[test]
+    fn test_compact_buckets() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        // the first three blobs fill the first bucket
+        let config = config(&format!(
+            "storage_blob_type: mirror
+storage_blob_mirror:
+  replicas:
+    - name: bucket
+      storage_blob_type: bucket
+      storage_blob_bucket:
+        path: {}
+        max_size: 1000
+        garbage_ratio: 0.25
+    - name: fs
+      storage_blob_type: fs
+      storage_blob_fs:
+        path: {}
+",
+            dir.path().join("bucket").display(),
+            dir.path().join("fs").display()
+        ));
+        init_blob_storage(&config).expect("Error in init of blob storage!");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffers: Vec<Vec<u8>> = (1..=4).map(|n| vec![n as u8; 400]).collect();
+        let metas: Vec<BlobMeta> = buffers.iter().map(|buffer| BlobMeta::new(buffer.len())).collect();
+        {
+            let storage = create_blob_storage(config.clone()).expect("error creating blob storage!");
+            for (meta, buffer) in metas.iter().zip(buffers.iter()) {
+                let blob_ref = storage.put(meta, buffer.clone()).expect("put blob failed!");
+                meta_storage.put(meta.clone(), blob_refs("mirror", blob_ref)).unwrap();
+            }
+            let mirrored = blob_ref("mirror", meta_storage.get_blob_refs(metas[1].id).unwrap().unwrap()).unwrap();
+            storage.delete(&metas[1], &mirrored).expect("delete blob failed!");
+            meta_storage.delete(metas[1].id).unwrap();
+        }
+
+        // the first and third blob are relocated:
+        let summary = compact_buckets(&config, "mirror", &meta_storage).expect("compaction failed!");
+        assert_eq!(summary.buckets, vec![("mirror/bucket".to_string(), 1)]);
+        assert_eq!(summary.updated, 2);
+
+        let storage = create_blob_storage(config.clone()).expect("error creating blob storage!");
+        for n in [0, 2, 3].iter() {
+            let mirrored = blob_ref("mirror", meta_storage.get_blob_refs(metas[*n].id).unwrap().unwrap()).unwrap();
+            assert_eq!(storage.get(&metas[*n], &mirrored).expect("get blob failed!"), buffers[*n]);
+        }
+
+        // the relocation log is removed once the refs are updated:
+        let summary = compact_buckets(&config, "mirror", &meta_storage).expect("compaction failed!");
+        assert!(summary.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_compact_buckets_dedup() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+        let config = config(&format!(
+            "storage_blob_type: bucket
+storage_blob_bucket:
+  path: {}
+  max_size: 1000
+storage_blob_dedup:
+  hash: sha2_256
+  index_path: {}
+",
+            dir.path().join("bucket").display(),
+            dir.path().join("dedup").display()
+        ));
+        match compact_buckets(&config, "bucket", &meta_storage) {
+            Err(CompactionError::DedupCompactionError) => {}
+            _ => panic!("expected the compaction of a deduplicated storage to be refused!"),
+        }
+    }
+}
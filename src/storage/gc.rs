@@ -0,0 +1,395 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Garbage Collection
+//!
+//! Mark-and-sweep of the blob storage against the meta storage. The blob refs of every
+//! meta object are resolved down to the refs in the backends (through the refs of the
+//! decorating, mirrored and tiered storages) and marked, then the blobs stored in the
+//! backends are swept:
+//!
+//! * orphans are blobs in a backend no meta object refers to, e.g. if a process died
+//!   between storing the blob and its meta,
+//! * dangling refs are meta objects whose blob is missing in all backends.
+//!
+//! Only the bucket and fs backends can be listed. Reclaiming deletes orphaned blobs in
+//! their backend and removes dangling meta objects, it is refused with deduplication
+//! configured, as the digest index would still point at the reclaimed blobs.
+//!
+//! The collection needs to run while the service is stopped, blobs being uploaded are
+//! orphans until their meta is stored.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+use crate::storage::blob::backend::fs::{FsBlobStorage, FsBlobStorageConfig};
+use crate::storage::blob::compression::CompressedBlobRef;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::blob::dedup::DedupBlobRef;
+use crate::storage::blob::encryption::EncryptedBlobRef;
+use crate::storage::blob::mirror::{blob_ref, MirrorBlobRef};
+use crate::storage::blob::tiered::{TieredBlobRef, TIERED_RETIRED};
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Number of meta objects listed at once while marking.
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug)]
+pub enum GcError {
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+    /// The backend type can't be listed.
+    UnsupportedBackend(String),
+    /// Reclaiming blobs of a deduplicated storage is not supported.
+    DedupReclaimError,
+}
+
+impl From<BlobStorageError> for GcError {
+    fn from(error: BlobStorageError) -> Self {
+        GcError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for GcError {
+    fn from(error: MetaStorageError) -> Self {
+        GcError::MetaStorageError(error)
+    }
+}
+
+/// A blob stored in a backend without any meta object referring to it.
+pub struct Orphan {
+    /// Location of the backend, like `mirror/ssd`, empty for the configured backend itself.
+    pub location: String,
+    /// Identifier of the blob meta the blob was stored with.
+    pub id: Uuid,
+    pub blob_ref: Box<dyn BlobRef>,
+}
+
+#[derive(Default)]
+pub struct GcReport {
+    /// Number of blobs in the backends referred to by meta objects.
+    pub marked: usize,
+    pub orphans: Vec<Orphan>,
+    /// Ids of the meta objects whose blob is missing.
+    pub dangling: Vec<Uuid>,
+    /// Whether the orphans and dangling meta objects were removed.
+    pub reclaimed: bool,
+}
+
+/// Config of a backend that can be listed.
+enum Backend {
+    Bucket(BucketBlobStorageConfig),
+    Fs(FsBlobStorageConfig),
+}
+
+/// The blobs stored in a backend, by the key of their blob ref.
+struct Inventory {
+    backend: Backend,
+    blobs: HashMap<String, (Uuid, usize, Box<dyn BlobRef>)>,
+}
+
+impl Inventory {
+    fn scan(backend: Backend) -> Result<Self, GcError> {
+        let mut blobs = HashMap::new();
+        match backend {
+            Backend::Bucket(ref config) => {
+                for record in BucketBlobStorage::scan(config)? {
+                    let size = record.blob_ref.size;
+                    let blob_ref: Box<dyn BlobRef> = Box::new(record.blob_ref);
+                    blobs.insert(ref_key(&blob_ref), (record.id, size, blob_ref));
+                }
+            }
+            Backend::Fs(ref config) => {
+                for record in FsBlobStorage::scan(config)? {
+                    let size = record.blob_ref.size;
+                    let blob_ref: Box<dyn BlobRef> = Box::new(record.blob_ref);
+                    blobs.insert(ref_key(&blob_ref), (record.id, size, blob_ref));
+                }
+            }
+        }
+        Ok(Self { backend, blobs })
+    }
+
+    fn create_storage(&self) -> Result<Box<dyn BlobStorage>, BlobStorageError> {
+        Ok(match self.backend {
+            Backend::Bucket(ref config) => Box::new(BucketBlobStorage::new(config.clone())?),
+            Backend::Fs(ref config) => Box::new(FsBlobStorage::new(config.clone())?),
+        })
+    }
+}
+
+/// Identifies a blob ref of a backend, refs are equal if they serialize the same way.
+fn ref_key(blob_ref: &Box<dyn BlobRef>) -> String {
+    serde_json::to_string(blob_ref).unwrap_or_else(|_| blob_ref.display())
+}
+
+/// Scans the backends of the storage config, keyed by their location.
+fn scan_backends(
+    config: &BlobStorageConfig,
+    location: &str,
+    inventories: &mut HashMap<String, Inventory>,
+) -> Result<(), GcError> {
+    let backend = match config.storage_blob_type.as_ref() {
+        "bucket" => Backend::Bucket(config.storage_blob_bucket.clone().ok_or(BlobStorageError::StorageConfigError)?),
+        "fs" => Backend::Fs(config.storage_blob_fs.clone().ok_or(BlobStorageError::StorageConfigError)?),
+        "mirror" => {
+            let mirror = config.storage_blob_mirror.as_ref().ok_or(BlobStorageError::StorageConfigError)?;
+            for replica in mirror.replicas.iter() {
+                scan_backends(&replica.storage, &join(location, "mirror", &replica.name), inventories)?;
+            }
+            return Ok(());
+        }
+        "tiered" => {
+            let tiered = config.storage_blob_tiered.as_ref().ok_or(BlobStorageError::StorageConfigError)?;
+            for tier in tiered.tiers.iter() {
+                scan_backends(&tier.storage, &join(location, "tiered", &tier.name), inventories)?;
+            }
+            return Ok(());
+        }
+        other => return Err(GcError::UnsupportedBackend(other.to_string())),
+    };
+    inventories.insert(location.to_string(), Inventory::scan(backend)?);
+    Ok(())
+}
+
+pub(crate) fn join(location: &str, backend: &str, name: &str) -> String {
+    if location.is_empty() {
+        format!("{}/{}", backend, name)
+    } else {
+        format!("{}/{}/{}", location, backend, name)
+    }
+}
+
+pub(crate) fn uses_dedup(config: &BlobStorageConfig) -> bool {
+    config.storage_blob_dedup.is_some()
+        || config.storage_blob_mirror.iter().flat_map(|mirror| mirror.replicas.iter()).any(|replica| uses_dedup(&replica.storage))
+        || config.storage_blob_tiered.iter().flat_map(|tiered| tiered.tiers.iter()).any(|tier| uses_dedup(&tier.storage))
+}
+
+/// Resolves the blob ref down to the refs in the backends, returned with their location.
+fn resolve(config: &BlobStorageConfig, location: &str, mut blob_ref: &Box<dyn BlobRef>, refs: &mut Vec<(String, String)>) {
+    // refs of the decorating storages wrap the ref of the backend
+    loop {
+        let any = blob_ref.any();
+        blob_ref = if let Some(wrapped) = any.downcast_ref::<DedupBlobRef>() {
+            &wrapped.blob_ref
+        } else if let Some(wrapped) = any.downcast_ref::<CompressedBlobRef>() {
+            &wrapped.blob_ref
+        } else if let Some(wrapped) = any.downcast_ref::<EncryptedBlobRef>() {
+            &wrapped.blob_ref
+        } else {
+            break;
+        };
+    }
+
+    match config.storage_blob_type.as_ref() {
+        "mirror" => {
+            let mirrored = blob_ref.any().downcast_ref::<MirrorBlobRef>();
+            if let (Some(mirror), Some(mirrored)) = (config.storage_blob_mirror.as_ref(), mirrored) {
+                for replica in mirror.replicas.iter() {
+                    if let Some(replica_ref) = mirrored.refs.get(&replica.name) {
+                        resolve(&replica.storage, &join(location, "mirror", &replica.name), replica_ref, refs);
+                    }
+                }
+            }
+        }
+        "tiered" => {
+            let tiered_ref = blob_ref.any().downcast_ref::<TieredBlobRef>();
+            if let (Some(tiered), Some(tiered_ref)) = (config.storage_blob_tiered.as_ref(), tiered_ref) {
+                if let Some(tier) = tiered.tiers.iter().find(|tier| tier.name == tiered_ref.tier) {
+                    resolve(&tier.storage, &join(location, "tiered", &tier.name), &tiered_ref.blob_ref, refs);
+                }
+            }
+        }
+        _ => refs.push((location.to_string(), ref_key(blob_ref))),
+    }
+}
+
+/// Cross-references the blob refs of all meta objects with the blobs stored in the
+/// backends. The blob refs are looked up under the given backend name, orphans and
+/// dangling meta objects are only reported with `dry_run` set.
+pub fn collect_garbage(
+    config: &BlobStorageConfig,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+    dry_run: bool,
+) -> Result<GcReport, GcError> {
+    if !dry_run && uses_dedup(config) {
+        return Err(GcError::DedupReclaimError);
+    }
+
+    let mut inventories = HashMap::new();
+    scan_backends(config, "", &mut inventories)?;
+
+    // mark
+    let mut report = GcReport::default();
+    let mut marked: HashSet<(String, String)> = HashSet::new();
+    let mut after = None;
+    loop {
+        let page = meta_storage.list(after, PAGE_SIZE)?;
+        after = match page.last() {
+            Some(meta) => Some(meta.id),
+            None => break,
+        };
+
+        for meta in page {
+            let mut refs = Vec::new();
+            let mut retired = Vec::new();
+            if let Some(blob_refs) = meta_storage.get_blob_refs(meta.id)? {
+                // the previous copy of a migrated blob is deleted by the tier migrator
+                if let Some(retired_ref) = blob_refs.get(TIERED_RETIRED) {
+                    resolve(config, "", retired_ref, &mut retired);
+                }
+                if let Some(blob_ref) = blob_ref(backend, blob_refs) {
+                    resolve(config, "", &blob_ref, &mut refs);
+                }
+            }
+
+            let mut found = false;
+            for (location, key) in refs {
+                if inventories.get(&location).map_or(false, |inventory| inventory.blobs.contains_key(&key)) {
+                    found = true;
+                    marked.insert((location, key));
+                }
+            }
+            if !found {
+                report.dangling.push(meta.id);
+            }
+            for (location, key) in retired {
+                if inventories.get(&location).map_or(false, |inventory| inventory.blobs.contains_key(&key)) {
+                    marked.insert((location, key));
+                }
+            }
+        }
+    }
+    report.marked = marked.len();
+
+    // sweep
+    for (location, inventory) in inventories.iter() {
+        for (key, (id, _, blob_ref)) in inventory.blobs.iter() {
+            if !marked.contains(&(location.clone(), key.clone())) {
+                report.orphans.push(Orphan { location: location.clone(), id: *id, blob_ref: blob_ref.clone() });
+            }
+        }
+    }
+
+    if !dry_run {
+        let mut storages: HashMap<&str, Box<dyn BlobStorage>> = HashMap::new();
+        for orphan in report.orphans.iter() {
+            let inventory = &inventories[&orphan.location];
+            if !storages.contains_key(orphan.location.as_str()) {
+                storages.insert(&orphan.location, inventory.create_storage()?);
+            }
+            let (_, size, _) = inventory.blobs[&ref_key(&orphan.blob_ref)];
+            let meta = BlobMeta { id: orphan.id, ..BlobMeta::new(size) };
+            storages.get_mut(orphan.location.as_str()).unwrap().delete(&meta, &orphan.blob_ref)?;
+        }
+        for id in report.dangling.iter() {
+            meta_storage.delete(*id)?;
+        }
+        report.reclaimed = true;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::config::BlobStorageConfig;
+    use crate::storage::blob::factory::{create_blob_storage, init_blob_storage};
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::blob::mirror::blob_refs;
+    use crate::storage::gc::{collect_garbage, GcError};
+    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use tempfile::tempdir;
+
+    /// Stores four blobs, the meta of the first blob is missing, the blob of the second one.
+    fn test_gc(config: BlobStorageConfig, replicas: usize) {
+        init_blob_storage(&config).expect("Error in init of blob storage!");
+        let storage = create_blob_storage(config.clone()).expect("error creating the blob storage");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+        let backend = config.storage_blob_type.clone();
+
+        let mut metas = Vec::new();
+        let mut references = Vec::new();
+        for i in 0..4u8 {
+            let buffer = vec![i; 1024];
+            let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+            references.push(storage.put(&meta, buffer).expect("put blob failed!"));
+            metas.push(meta);
+        }
+
+        for (meta, reference) in metas.iter().zip(references.iter()).skip(1) {
+            meta_storage.put(meta.clone(), blob_refs(&backend, reference.clone())).unwrap();
+        }
+        storage.delete(&metas[1], &references[1]).expect("delete blob failed!");
+
+        let report = collect_garbage(&config, &backend, &meta_storage, true).expect("gc failed!");
+        assert!(!report.reclaimed);
+        assert_eq!(report.dangling, vec![metas[1].id]);
+        assert_eq!(report.orphans.len(), replicas);
+        assert!(report.orphans.iter().all(|orphan| orphan.id == metas[0].id));
+        assert!(meta_storage.get_meta(metas[1].id).unwrap().is_some());
+
+        let report = collect_garbage(&config, &backend, &meta_storage, false).expect("gc failed!");
+        assert!(report.reclaimed);
+        assert!(meta_storage.get_meta(metas[1].id).unwrap().is_none());
+        assert_eq!(storage.get(&metas[2], &references[2]).unwrap(), vec![2; 1024]);
+
+        let report = collect_garbage(&config, &backend, &meta_storage, false).expect("gc failed!");
+        assert!(report.orphans.is_empty());
+        assert!(report.dangling.is_empty());
+        assert_eq!(report.marked, 2 * replicas);
+    }
+
+    fn config(yaml: &str) -> BlobStorageConfig {
+        serde_yaml::from_str(yaml).expect("invalid blob storage config!")
+    }
+
+    #[test]
+    fn test_gc_bucket() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        test_gc(config(&format!(
+            "storage_blob_type: bucket\nstorage_blob_bucket:\n  path: {}\n  max_size: 25769803776\n",
+            dir.path().display()
+        )), 1);
+    }
+
+    #[test]
+    fn test_gc_mirror() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        test_gc(config(&format!(
+            "storage_blob_type: mirror
+storage_blob_mirror:
+  replicas:
+    - name: bucket
+      storage_blob_type: bucket
+      storage_blob_bucket:
+        path: {}
+        max_size: 25769803776
+    - name: fs
+      storage_blob_type: fs
+      storage_blob_fs:
+        path: {}
+      storage_blob_compression:
+        codec: zstd
+",
+            dir.path().join("bucket").display(),
+            dir.path().join("fs").display()
+        )), 2);
+    }
+
+    #[test]
+    fn test_gc_unsupported() {
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+        let config = config("storage_blob_type: mem\n");
+        match collect_garbage(&config, "mem", &meta_storage, true) {
+            Err(GcError::UnsupportedBackend(backend)) => assert_eq!(backend, "mem"),
+            _ => panic!("expected the mem backend to be unsupported!"),
+        }
+    }
+}
@@ -2,59 +2,128 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 use uuid::Uuid;
-use crate::storage::meta::{MetaStorageError, MetaStorage};
+use crate::storage::meta::{same_blob_refs, MetaStorageError, MetaStorage};
 use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::sync::Arc;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::usize;
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MemoryMetaStorageConfig {}
 
-pub struct MemoryMetaStorage {
-    metas: HashMap<Uuid, BlobMeta>,
+struct MemoryEntries {
+    metas: BTreeMap<Uuid, BlobMeta>,
     blob_refs: HashMap<Uuid, HashMap<String, Box<dyn BlobRef>>>,
 }
 
+pub struct MemoryMetaStorage {
+    /// Metas and blob refs share one lock, so they are always updated together.
+    entries: RwLock<MemoryEntries>,
+}
+
 impl MemoryMetaStorage {
+    pub fn init(config: &MemoryMetaStorageConfig) -> Result<(), MetaStorageError> {
+        Ok(())
+    }
+
     pub fn new(config: MemoryMetaStorageConfig) -> Result<Self, MetaStorageError> {
-        Ok(Self { metas: HashMap::new(), blob_refs: HashMap::new() })
+        let entries = MemoryEntries { metas: BTreeMap::new(), blob_refs: HashMap::new() };
+        Ok(Self { entries: RwLock::new(entries) })
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, MemoryEntries>, MetaStorageError> {
+        self.entries.read().map_err(|_| MetaStorageError::LockError)
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, MemoryEntries>, MetaStorageError> {
+        self.entries.write().map_err(|_| MetaStorageError::LockError)
     }
 }
 
 impl MetaStorage for MemoryMetaStorage {
     fn put(
-        &mut self,
+        &self,
         meta: BlobMeta,
         blob_refs: HashMap<String, Box<dyn BlobRef>>
     ) -> Result<(), MetaStorageError> {
         let key = meta.id;
-        self.metas.insert(key, meta);
-        self.blob_refs.insert(key, blob_refs);
+        let mut entries = self.write()?;
+        entries.metas.insert(key, meta);
+        entries.blob_refs.insert(key, blob_refs);
         Ok(())
     }
 
-    fn get_meta(&mut self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
-        match self.metas.get(&id) {
-            Some(meta) => Ok(Some(*meta)),
+    fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+        match self.read()?.metas.get(&id) {
+            Some(meta) => Ok(Some(meta.clone())),
             None => Ok(None)
         }
     }
 
-    fn get_blob_refs(&mut self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
-        match self.blob_refs.get(&id) {
+    fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+        match self.read()?.blob_refs.get(&id) {
             Some(blob_refs) => Ok(Some((*blob_refs).clone())),
             None => Ok(None)
         }
     }
 
-    fn delete(&mut self, id: Uuid) -> Result<(), MetaStorageError> {
-        self.metas.remove(&id);
-        self.blob_refs.remove(&id);
+    fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let mut entries = self.write()?;
+        if !entries.metas.contains_key(&id) {
+            return Ok(false);
+        }
+        entries.blob_refs.insert(id, blob_refs);
+        Ok(true)
+    }
+
+    fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let mut entries = self.write()?;
+        match entries.blob_refs.get(&id) {
+            Some(stored) if same_blob_refs(stored, expected)? => {
+                entries.blob_refs.insert(id, blob_refs);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+        let mut entries = self.write()?;
+        match entries.metas.get_mut(&id) {
+            Some(stored) => {
+                *stored = meta;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+        let start = match after {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+        Ok(self.read()?.metas.range((start, Bound::Unbounded)).take(limit).map(|(_, meta)| meta.clone()).collect())
+    }
+
+    fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
+        let mut entries = self.write()?;
+        entries.metas.remove(&id);
+        entries.blob_refs.remove(&id);
         Ok(())
     }
 }
@@ -62,7 +131,7 @@ impl MetaStorage for MemoryMetaStorage {
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::{test_compare_and_update_blob_refs, test_list, test_update_meta, MetaStorage};
     use crate::domain::meta::BlobMeta;
     use crate::{load_fixture};
     use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
@@ -71,20 +140,21 @@ mod tests {
     use crate::storage::blob::backend::bucket::{BucketBlobRef};
     use std::path::{Path, PathBuf};
     use std::collections::HashMap;
+    use uuid::Uuid;
 
     #[test]
     fn test_memory_meta_storage() {
-        let mut storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        let storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
 
         let meta = BlobMeta::new(1024);
         let memory_blob_ref = MemoryBlobRef { index: 42 };
-        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024 };
+        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024, generation: 0 };
 
         let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
         blob_refs.insert("mem".to_string(), Box::new(memory_blob_ref));
         blob_refs.insert("bucket".to_string(), Box::new(bucket_blob_ref));
 
-        storage.put(meta, blob_refs).expect("cant put to meta storage");
+        storage.put(meta.clone(), blob_refs).expect("cant put to meta storage");
 
         let got_meta = storage.get_meta(meta.id).unwrap();
         let got_blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
@@ -108,7 +178,33 @@ mod tests {
         assert_eq!(got_bucket_blob_ref.offset, 1);
         assert_eq!(got_bucket_blob_ref.size, 1024);
 
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert("mem".to_string(), Box::new(MemoryBlobRef { index: 23 }));
+        assert!(storage.update_blob_refs(meta.id, blob_refs.clone()).unwrap());
+        let got_blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
+        assert_eq!(got_blob_refs.len(), 1);
+        assert_eq!(got_blob_refs["mem"].any().downcast_ref::<MemoryBlobRef>().unwrap().index, 23);
+
         storage.delete(meta.id).unwrap();
+        assert!(!storage.update_blob_refs(meta.id, blob_refs).unwrap());
+    }
+
+    #[test]
+    fn test_memory_meta_storage_list() {
+        let storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        test_list(&storage, false);
+    }
+
+    #[test]
+    fn test_memory_meta_storage_update_meta() {
+        let storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        test_update_meta(&storage);
+    }
+
+    #[test]
+    fn test_memory_meta_storage_compare_and_update_blob_refs() {
+        let storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        test_compare_and_update_blob_refs(&storage);
     }
 }
 
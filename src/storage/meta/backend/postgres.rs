@@ -2,19 +2,22 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 use uuid::Uuid;
-use crate::storage::meta::{MetaStorageError, MetaStorage};
+use crate::storage::meta::{AsyncMetaStorage, MetaStorageError, MetaStorage};
 use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
-use std::sync::Arc;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::path::{Path, PathBuf};
 use std::usize;
 use serde::{Serialize, Deserialize};
 use postgres::{Config, Client, NoTls};
 use postgres::types::Type;
+use async_trait::async_trait;
 
 
 #[derive(Debug, Clone, Deserialize)]
@@ -24,10 +27,53 @@ pub struct PostgresMetaStorageConfig {
     pub database: String,
     pub username: String,
     pub password: String,
+    /// Maximum number of connections held by the connection pool.
+    #[serde(default = "PostgresMetaStorageConfig::default_pool_size")]
+    pub pool_size: usize,
 }
 
+impl PostgresMetaStorageConfig {
+    fn default_pool_size() -> usize {
+        4
+    }
+}
+
+/// Connections are opened on demand up to the pool size, callers wait for a
+/// connection to be returned to the pool once all of them are in use.
 pub struct PostgresMetaStorage {
-    client: Client
+    config: Config,
+    pool_size: usize,
+    /// Idle connections and the number of connections opened.
+    pool: Mutex<(Vec<Client>, usize)>,
+    returned: Condvar,
+}
+
+/// A connection taken from the pool, it is returned to the pool when dropped.
+struct PooledClient<'a> {
+    storage: &'a PostgresMetaStorage,
+    client: Option<Client>,
+}
+
+impl<'a> Deref for PooledClient<'a> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledClient<'a> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledClient<'a> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.storage.release(client);
+        }
+    }
 }
 
 
@@ -48,9 +94,23 @@ impl From<serde_json::error::Error> for MetaStorageError {
 
 
 
+const INSERT_META: &str = "INSERT INTO meta (id, meta, blob_refs) VALUES ($1::uuid, $2::jsonb, $3::jsonb)";
+const SELECT_META: &str = "SELECT meta::text FROM meta WHERE id = $1::uuid";
+const SELECT_BLOB_REFS: &str = "SELECT blob_refs::text FROM meta WHERE id = $1::uuid";
+const UPDATE_BLOB_REFS: &str = "UPDATE meta SET blob_refs = $2::jsonb WHERE id = $1::uuid";
+const SWAP_BLOB_REFS: &str = "UPDATE meta SET blob_refs = $3::jsonb WHERE id = $1::uuid AND blob_refs = $2::jsonb";
+const UPDATE_META: &str = "UPDATE meta SET meta = $2::jsonb WHERE id = $1::uuid";
+const LIST_META_AFTER: &str = "SELECT meta::text FROM meta WHERE id > $1::uuid ORDER BY id LIMIT $2";
+const LIST_META: &str = "SELECT meta::text FROM meta ORDER BY id LIMIT $1";
+const DELETE_META: &str = "DELETE FROM meta WHERE id = $1::uuid;";
+
 impl PostgresMetaStorage {
     fn get_config(config: &PostgresMetaStorageConfig) -> Config {
-        let mut dbconfig = Config::new();
+        Config::from(Self::get_async_config(config))
+    }
+
+    fn get_async_config(config: &PostgresMetaStorageConfig) -> tokio_postgres::Config {
+        let mut dbconfig = tokio_postgres::Config::new();
 
         dbconfig.user(&config.username);
         dbconfig.password(&config.password);
@@ -77,14 +137,63 @@ impl PostgresMetaStorage {
     }
 
     pub fn new(config: PostgresMetaStorageConfig) -> Result<Self, MetaStorageError> {
-        let mut client = Self::get_config(&config).connect(NoTls)?;
-        Ok(Self { client })
+        if config.pool_size == 0 {
+            return Err(MetaStorageError::CreateStorageError("The pool size needs to be at least 1!"));
+        }
+        // connect once to fail early with a wrong configuration
+        let client = Self::get_config(&config).connect(NoTls)?;
+        Ok(Self {
+            config: Self::get_config(&config),
+            pool_size: config.pool_size,
+            pool: Mutex::new((vec![client], 1)),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Takes an idle connection from the pool, opens a new one if the pool is not
+    /// full, otherwise waits for a connection to be returned.
+    fn client(&self) -> Result<PooledClient<'_>, MetaStorageError> {
+        let mut pool = self.pool.lock().map_err(|_| MetaStorageError::LockError)?;
+        loop {
+            if let Some(client) = pool.0.pop() {
+                return Ok(PooledClient { storage: self, client: Some(client) });
+            }
+            if pool.1 < self.pool_size {
+                pool.1 += 1;
+                drop(pool);
+                return match self.config.connect(NoTls) {
+                    Ok(client) => Ok(PooledClient { storage: self, client: Some(client) }),
+                    Err(err) => {
+                        self.discard();
+                        Err(err.into())
+                    }
+                };
+            }
+            pool = self.returned.wait(pool).map_err(|_| MetaStorageError::LockError)?;
+        }
+    }
+
+    /// Returns the connection to the pool, closed connections are discarded.
+    fn release(&self, client: Client) {
+        if client.is_closed() {
+            self.discard();
+        } else if let Ok(mut pool) = self.pool.lock() {
+            pool.0.push(client);
+            self.returned.notify_one();
+        }
+    }
+
+    fn discard(&self) {
+        if let Ok(mut pool) = self.pool.lock() {
+            pool.1 -= 1;
+            self.returned.notify_one();
+        }
     }
 }
 
 impl MetaStorage for PostgresMetaStorage {
     fn put(
-        &mut self,
+        &self,
         meta: BlobMeta,
         blob_refs: HashMap<String, Box<dyn BlobRef>>
     ) -> Result<(), MetaStorageError> {
@@ -94,24 +203,26 @@ impl MetaStorage for PostgresMetaStorage {
         let meta_encoded = serde_json::to_string(&meta)?;
         let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
 
-        let statement = self.client.prepare_typed(
-            "INSERT INTO meta (id, meta, blob_refs) VALUES ($1::uuid, $2::jsonb, $3::jsonb)",
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            INSERT_META,
             &[Type::TEXT, Type::TEXT, Type::TEXT],
         )?;
-        self.client.execute(&statement, &[&key, &meta_encoded, &blob_refs_encoded])?;
+        client.execute(&statement, &[&key, &meta_encoded, &blob_refs_encoded])?;
 
         Ok(())
     }
 
-    fn get_meta(&mut self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+    fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
         let key: String = id.to_hyphenated().to_string();
 
-        let statement = self.client.prepare_typed(
-            "SELECT meta::text FROM meta WHERE id = $1::uuid",
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            SELECT_META,
             &[Type::TEXT],
         )?;
 
-        match self.client.query_opt(&statement, &[&key])? {
+        match client.query_opt(&statement, &[&key])? {
             Some(row) => {
                 let meta_string = row.try_get(0)?;
                 let meta: BlobMeta = serde_json::from_str(meta_string)?;
@@ -121,15 +232,16 @@ impl MetaStorage for PostgresMetaStorage {
         }
     }
 
-    fn get_blob_refs(&mut self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+    fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
         let key: String = id.to_hyphenated().to_string();
 
-        let statement = self.client.prepare_typed(
-            "SELECT blob_refs::text FROM meta WHERE id = $1::uuid",
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            SELECT_BLOB_REFS,
             &[Type::TEXT],
         )?;
 
-        match self.client.query_opt(&statement, &[&key])? {
+        match client.query_opt(&statement, &[&key])? {
             Some(row) => {
                 let blob_refs_string = row.try_get(0)?;
                 let blob_refs: HashMap<String, Box<dyn BlobRef>> = serde_json::from_str(blob_refs_string)?;
@@ -139,31 +251,283 @@ impl MetaStorage for PostgresMetaStorage {
         }
     }
 
-    fn delete(&mut self, id: Uuid) -> Result<(), MetaStorageError> {
+    fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
+
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            UPDATE_BLOB_REFS,
+            &[Type::TEXT, Type::TEXT],
+        )?;
+        let updated = client.execute(&statement, &[&key, &blob_refs_encoded])?;
+
+        Ok(updated > 0)
+    }
+
+    fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let expected_encoded = serde_json::to_string(expected)?;
+        let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
+
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            SWAP_BLOB_REFS,
+            &[Type::TEXT, Type::TEXT, Type::TEXT],
+        )?;
+        let updated = client.execute(&statement, &[&key, &expected_encoded, &blob_refs_encoded])?;
+
+        Ok(updated > 0)
+    }
+
+    fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let meta_encoded = serde_json::to_string(&meta)?;
+
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            UPDATE_META,
+            &[Type::TEXT, Type::TEXT],
+        )?;
+        let updated = client.execute(&statement, &[&key, &meta_encoded])?;
+
+        Ok(updated > 0)
+    }
+
+    fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+        let limit = limit as i64;
+
+        let mut client = self.client()?;
+        // keyset pagination, continues after the last id of the previous page
+        let rows = match after {
+            Some(id) => {
+                let key: String = id.to_hyphenated().to_string();
+                let statement = client.prepare_typed(
+                    LIST_META_AFTER,
+                    &[Type::TEXT, Type::INT8],
+                )?;
+                client.query(&statement, &[&key, &limit])?
+            },
+            None => {
+                let statement = client.prepare_typed(
+                    LIST_META,
+                    &[Type::INT8],
+                )?;
+                client.query(&statement, &[&limit])?
+            },
+        };
+
+        let mut metas = Vec::with_capacity(rows.len());
+        for row in rows {
+            let meta_string = row.try_get(0)?;
+            metas.push(serde_json::from_str(meta_string)?);
+        }
+        Ok(metas)
+    }
+
+    fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
         let key = id.to_string();
 
-        let statement = self.client.prepare_typed(
-            "DELETE FROM meta WHERE id = $1::uuid;",
+        let mut client = self.client()?;
+        let statement = client.prepare_typed(
+            DELETE_META,
             &[Type::TEXT],
         )?;
-        self.client.execute(&statement, &[&key])?;
+        client.execute(&statement, &[&key])?;
 
         Ok(())
     }
 }
 
 
+/// Meta storage on the native async postgres driver. Connections are opened on demand
+/// up to the pool size and used in turn, queries of concurrent requests are pipelined
+/// over each of them. Closed connections are reopened when they are used next.
+pub struct AsyncPostgresMetaStorage {
+    config: tokio_postgres::Config,
+    connections: Vec<futures::lock::Mutex<Option<Arc<tokio_postgres::Client>>>>,
+    next: AtomicUsize,
+}
+
+impl AsyncPostgresMetaStorage {
+    pub async fn new(config: PostgresMetaStorageConfig) -> Result<Self, MetaStorageError> {
+        if config.pool_size == 0 {
+            return Err(MetaStorageError::CreateStorageError("The pool size needs to be at least 1!"));
+        }
+        let storage = Self {
+            config: PostgresMetaStorage::get_async_config(&config),
+            connections: (0..config.pool_size).map(|_| futures::lock::Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        };
+        // connect once to fail early with a wrong configuration
+        storage.client().await?;
+        Ok(storage)
+    }
+
+    /// Connects to the database, the connection is driven by a task on the current arbiter.
+    async fn connect(&self) -> Result<Arc<tokio_postgres::Client>, MetaStorageError> {
+        let (client, connection) = self.config.connect(NoTls).await?;
+        actix_rt::spawn(async move {
+            if let Err(err) = connection.await {
+                eprintln!("Meta: PostgreSQL connection error {:?}", err);
+            }
+        });
+        Ok(Arc::new(client))
+    }
+
+    /// Returns the next connection of the pool, it is opened again if it was closed.
+    async fn client(&self) -> Result<Arc<tokio_postgres::Client>, MetaStorageError> {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        let mut connection = self.connections[slot].lock().await;
+        match connection.as_ref() {
+            Some(client) if !client.is_closed() => Ok(client.clone()),
+            _ => {
+                let client = self.connect().await?;
+                *connection = Some(client.clone());
+                Ok(client)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncMetaStorage for AsyncPostgresMetaStorage {
+    async fn put(
+        &self,
+        meta: BlobMeta,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<(), MetaStorageError> {
+        let key: String = meta.id.to_hyphenated().to_string();
+        let meta_encoded = serde_json::to_string(&meta)?;
+        let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(INSERT_META, &[Type::TEXT, Type::TEXT, Type::TEXT]).await?;
+        client.execute(&statement, &[&key, &meta_encoded, &blob_refs_encoded]).await?;
+
+        Ok(())
+    }
+
+    async fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(SELECT_META, &[Type::TEXT]).await?;
+        match client.query_opt(&statement, &[&key]).await? {
+            Some(row) => Ok(Some(serde_json::from_str(row.try_get(0)?)?)),
+            None => Ok(None)
+        }
+    }
+
+    async fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(SELECT_BLOB_REFS, &[Type::TEXT]).await?;
+        match client.query_opt(&statement, &[&key]).await? {
+            Some(row) => Ok(Some(serde_json::from_str(row.try_get(0)?)?)),
+            None => Ok(None)
+        }
+    }
+
+    async fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(UPDATE_BLOB_REFS, &[Type::TEXT, Type::TEXT]).await?;
+        let updated = client.execute(&statement, &[&key, &blob_refs_encoded]).await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let expected_encoded = serde_json::to_string(expected)?;
+        let blob_refs_encoded = serde_json::to_string(&blob_refs)?;
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(SWAP_BLOB_REFS, &[Type::TEXT, Type::TEXT, Type::TEXT]).await?;
+        let updated = client.execute(&statement, &[&key, &expected_encoded, &blob_refs_encoded]).await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+        let key: String = id.to_hyphenated().to_string();
+        let meta_encoded = serde_json::to_string(&meta)?;
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(UPDATE_META, &[Type::TEXT, Type::TEXT]).await?;
+        let updated = client.execute(&statement, &[&key, &meta_encoded]).await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+        let limit = limit as i64;
+
+        let client = self.client().await?;
+        let rows = match after {
+            Some(id) => {
+                let key: String = id.to_hyphenated().to_string();
+                let statement = client.prepare_typed(LIST_META_AFTER, &[Type::TEXT, Type::INT8]).await?;
+                client.query(&statement, &[&key, &limit]).await?
+            },
+            None => {
+                let statement = client.prepare_typed(LIST_META, &[Type::INT8]).await?;
+                client.query(&statement, &[&limit]).await?
+            },
+        };
+
+        let mut metas = Vec::with_capacity(rows.len());
+        for row in rows {
+            metas.push(serde_json::from_str(row.try_get(0)?)?);
+        }
+        Ok(metas)
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
+        let key = id.to_string();
+
+        let client = self.client().await?;
+        let statement = client.prepare_typed(DELETE_META, &[Type::TEXT]).await?;
+        client.execute(&statement, &[&key]).await?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::{test_compare_and_update_blob_refs, test_list, test_update_meta, AsyncMetaStorage, MetaStorage};
     use crate::domain::meta::BlobMeta;
     use crate::{load_fixture};
     use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
-    use crate::storage::meta::backend::postgres::{PostgresMetaStorage, PostgresMetaStorageConfig};
+    use crate::storage::meta::backend::postgres::{AsyncPostgresMetaStorage, PostgresMetaStorage, PostgresMetaStorageConfig};
     use crate::storage::blob::backend::mem::{MemoryBlobRef};
     use crate::storage::blob::backend::bucket::{BucketBlobRef};
     use std::path::{Path, PathBuf};
     use std::collections::HashMap;
+    use uuid::Uuid;
     use tempfile::tempdir;
 
     #[test]
@@ -176,21 +540,22 @@ mod tests {
             database: "rupee".to_string(),
             username: "rupee".to_string(),
             password: "hu4euShohn7e".to_string(),
+            pool_size: 2,
         };
 
         PostgresMetaStorage::init(&config).expect("Error in init of bucket storage!");
 
-        let mut storage = PostgresMetaStorage::new(config).expect("cant create meta storage");
+        let storage = PostgresMetaStorage::new(config).expect("cant create meta storage");
 
         let meta = BlobMeta::new(1024);
         let memory_blob_ref = MemoryBlobRef { index: 42 };
-        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024 };
+        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024, generation: 0 };
 
         let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
         blob_refs.insert("mem".to_string(), Box::new(memory_blob_ref));
         blob_refs.insert("bucket".to_string(), Box::new(bucket_blob_ref));
 
-        storage.put(meta, blob_refs).expect("cant put to meta storage");
+        storage.put(meta.clone(), blob_refs).expect("cant put to meta storage");
 
         let got_meta = storage.get_meta(meta.id).unwrap();
         let got_blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
@@ -216,4 +581,90 @@ mod tests {
 
         storage.delete(meta.id).unwrap();
     }
+
+    #[test]
+    fn test_postgres_meta_storage_list() {
+        let config = PostgresMetaStorageConfig {
+            hostname: "localhost".to_string(),
+            port: 5432,
+            database: "rupee".to_string(),
+            username: "rupee".to_string(),
+            password: "hu4euShohn7e".to_string(),
+            pool_size: 2,
+        };
+
+        PostgresMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = PostgresMetaStorage::new(config).expect("cant create meta storage");
+        test_list(&storage, true);
+    }
+
+    #[test]
+    fn test_postgres_meta_storage_update_meta() {
+        let config = PostgresMetaStorageConfig {
+            hostname: "localhost".to_string(),
+            port: 5432,
+            database: "rupee".to_string(),
+            username: "rupee".to_string(),
+            password: "hu4euShohn7e".to_string(),
+            pool_size: 2,
+        };
+
+        PostgresMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = PostgresMetaStorage::new(config).expect("cant create meta storage");
+        test_update_meta(&storage);
+    }
+
+    #[test]
+    fn test_postgres_meta_storage_compare_and_update_blob_refs() {
+        let config = PostgresMetaStorageConfig {
+            hostname: "localhost".to_string(),
+            port: 5432,
+            database: "rupee".to_string(),
+            username: "rupee".to_string(),
+            password: "hu4euShohn7e".to_string(),
+            pool_size: 2,
+        };
+
+        PostgresMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = PostgresMetaStorage::new(config).expect("cant create meta storage");
+        test_compare_and_update_blob_refs(&storage);
+    }
+
+    #[actix_rt::test]
+    async fn test_async_postgres_meta_storage() {
+        let config = PostgresMetaStorageConfig {
+            hostname: "localhost".to_string(),
+            port: 5432,
+            database: "rupee".to_string(),
+            username: "rupee".to_string(),
+            password: "hu4euShohn7e".to_string(),
+            pool_size: 2,
+        };
+
+        // the blocking client can't be used on the async runtime
+        let init_config = config.clone();
+        std::thread::spawn(move || PostgresMetaStorage::init(&init_config))
+            .join()
+            .unwrap()
+            .expect("Error in init of bucket storage!");
+
+        let storage = AsyncPostgresMetaStorage::new(config).await.expect("cant create meta storage");
+
+        let meta = BlobMeta::new(1024);
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert("mem".to_string(), Box::new(MemoryBlobRef { index: 42 }));
+        storage.put(meta.clone(), blob_refs.clone()).await.expect("cant put to meta storage");
+
+        assert_eq!(storage.get_meta(meta.id).await.unwrap().unwrap().size, 1024);
+        let got_blob_refs = storage.get_blob_refs(meta.id).await.unwrap().unwrap();
+        assert_eq!(got_blob_refs["mem"].any().downcast_ref::<MemoryBlobRef>().unwrap().index, 42);
+        assert!(storage.update_blob_refs(meta.id, blob_refs).await.unwrap());
+        assert!(storage.list(None, 1000).await.unwrap().iter().any(|listed| listed.id == meta.id));
+
+        storage.delete(meta.id).await.unwrap();
+        assert!(storage.get_meta(meta.id).await.unwrap().is_none());
+    }
 }
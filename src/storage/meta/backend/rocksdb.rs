@@ -2,18 +2,18 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 use uuid::Uuid;
-use crate::storage::meta::{MetaStorageError, MetaStorage};
+use crate::storage::meta::{same_blob_refs, MetaStorageError, MetaStorage};
 use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::path::{Path, PathBuf};
 use std::usize;
 use serde::{Serialize, Deserialize};
-use rocksdb::DB;
+use rocksdb::{Direction, IteratorMode, DB};
 
 
 #[derive(Debug, Clone, Deserialize)]
@@ -22,9 +22,13 @@ pub struct RocksDbMetaStorageConfig {
     pub path: PathBuf,
 }
 
+/// The databases are opened once per process, clones of the storage share the handles.
+#[derive(Clone)]
 pub struct RocksDbMetaStorage {
-    metas: DB,
-    blob_refs: DB,
+    metas: Arc<DB>,
+    blob_refs: Arc<DB>,
+    /// Serializes the writes, rocksdb has no compare-and-swap of its own.
+    writes: Arc<Mutex<()>>,
 }
 
 impl From<rocksdb::Error> for MetaStorageError {
@@ -69,13 +73,20 @@ impl RocksDbMetaStorage {
         let metas = DB::open_default(config.path.join("metas"))?;
         let blob_refs = DB::open_default(config.path.join("blob_refs"))?;
 
-        Ok(Self { metas, blob_refs })
+        Ok(Self { metas: Arc::new(metas), blob_refs: Arc::new(blob_refs), writes: Arc::new(Mutex::new(())) })
+    }
+
+    fn lock_writes(&self) -> Result<MutexGuard<'_, ()>, MetaStorageError> {
+        self.writes.lock().map_err(|_| {
+            eprintln!("Meta: RocksDb write lock is poisoned!");
+            MetaStorageError::LockError
+        })
     }
 }
 
 impl MetaStorage for RocksDbMetaStorage {
     fn put(
-        &mut self,
+        &self,
         meta: BlobMeta,
         blob_refs: HashMap<String, Box<dyn BlobRef>>
     ) -> Result<(), MetaStorageError> {
@@ -85,13 +96,14 @@ impl MetaStorage for RocksDbMetaStorage {
         let meta_encoded = rmp_serde::to_vec_named(&meta)?;
         let blob_refs_encoded = rmp_serde::to_vec_named(&blob_refs)?;
 
+        let _writes = self.lock_writes()?;
         self.metas.put(key, meta_encoded)?;
         self.blob_refs.put(key, blob_refs_encoded)?;
 
         Ok(())
     }
 
-    fn get_meta(&mut self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+    fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
         let key = id.as_bytes();
 
         match self.metas.get(key)? {
@@ -103,7 +115,7 @@ impl MetaStorage for RocksDbMetaStorage {
         }
     }
 
-    fn get_blob_refs(&mut self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+    fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
         let key = id.as_bytes();
 
         match self.blob_refs.get(key)? {
@@ -115,8 +127,81 @@ impl MetaStorage for RocksDbMetaStorage {
         }
     }
 
-    fn delete(&mut self, id: Uuid) -> Result<(), MetaStorageError> {
+    fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key = id.as_bytes();
+
+        let _writes = self.lock_writes()?;
+        if self.metas.get(key)?.is_none() {
+            return Ok(false);
+        }
+        let blob_refs_encoded = rmp_serde::to_vec_named(&blob_refs)?;
+        self.blob_refs.put(key, blob_refs_encoded)?;
+
+        Ok(true)
+    }
+
+    fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let key = id.as_bytes();
+
+        let _writes = self.lock_writes()?;
+        let stored: HashMap<String, Box<dyn BlobRef>> = match self.blob_refs.get(key)? {
+            Some(value) => rmp_serde::from_read_ref(&value)?,
+            None => return Ok(false),
+        };
+        if !same_blob_refs(&stored, expected)? {
+            return Ok(false);
+        }
+        let blob_refs_encoded = rmp_serde::to_vec_named(&blob_refs)?;
+        self.blob_refs.put(key, blob_refs_encoded)?;
+
+        Ok(true)
+    }
+
+    fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+        let key = id.as_bytes();
+
+        let _writes = self.lock_writes()?;
+        if self.metas.get(key)?.is_none() {
+            return Ok(false);
+        }
+        let meta_encoded = rmp_serde::to_vec_named(&meta)?;
+        self.metas.put(key, meta_encoded)?;
+
+        Ok(true)
+    }
+
+    fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+        // uuid keys are ordered by their bytes, same as the uuids themselves
+        let mode = match after {
+            Some(ref id) => IteratorMode::From(id.as_bytes(), Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let mut metas = Vec::new();
+        for (key, value) in self.metas.iterator(mode) {
+            if metas.len() >= limit {
+                break;
+            }
+            if after.map_or(false, |id| &key[..] == id.as_bytes()) {
+                continue;
+            }
+            metas.push(rmp_serde::from_read_ref(&value)?);
+        }
+        Ok(metas)
+    }
+
+    fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
         let key = id.as_bytes();
+        let _writes = self.lock_writes()?;
         self.metas.delete(&key)?;
         self.blob_refs.delete(&key)?;
         Ok(())
@@ -126,7 +211,7 @@ impl MetaStorage for RocksDbMetaStorage {
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::{test_compare_and_update_blob_refs, test_list, test_update_meta, MetaStorage};
     use crate::domain::meta::BlobMeta;
     use crate::{load_fixture};
     use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
@@ -135,6 +220,7 @@ mod tests {
     use crate::storage::blob::backend::bucket::{BucketBlobRef};
     use std::path::{Path, PathBuf};
     use std::collections::HashMap;
+    use uuid::Uuid;
     use tempfile::tempdir;
 
     #[test]
@@ -147,17 +233,17 @@ mod tests {
 
         RocksDbMetaStorage::init(&config).expect("Error in init of bucket storage!");
 
-        let mut storage = RocksDbMetaStorage::new(config).expect("cant create meta storage");
+        let storage = RocksDbMetaStorage::new(config).expect("cant create meta storage");
 
         let meta = BlobMeta::new(1024);
         let memory_blob_ref = MemoryBlobRef { index: 42 };
-        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024 };
+        let bucket_blob_ref = BucketBlobRef { bucket: 23, offset: 1, size: 1024, generation: 0 };
 
         let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
         blob_refs.insert("mem".to_string(), Box::new(memory_blob_ref));
         blob_refs.insert("bucket".to_string(), Box::new(bucket_blob_ref));
 
-        storage.put(meta, blob_refs).expect("cant put to meta storage");
+        storage.put(meta.clone(), blob_refs).expect("cant put to meta storage");
 
         let got_meta = storage.get_meta(meta.id).unwrap();
         let got_blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
@@ -181,6 +267,56 @@ mod tests {
         assert_eq!(got_bucket_blob_ref.offset, 1);
         assert_eq!(got_bucket_blob_ref.size, 1024);
 
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert("mem".to_string(), Box::new(MemoryBlobRef { index: 23 }));
+        assert!(storage.update_blob_refs(meta.id, blob_refs.clone()).unwrap());
+        let got_blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
+        assert_eq!(got_blob_refs.len(), 1);
+        assert_eq!(got_blob_refs["mem"].any().downcast_ref::<MemoryBlobRef>().unwrap().index, 23);
+
         storage.delete(meta.id).unwrap();
+        assert!(!storage.update_blob_refs(meta.id, blob_refs).unwrap());
+    }
+
+    #[test]
+    fn test_rocksdb_meta_storage_list() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+
+        let config = RocksDbMetaStorageConfig {
+            path: dir.path().to_path_buf(),
+        };
+
+        RocksDbMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = RocksDbMetaStorage::new(config).expect("cant create meta storage");
+        test_list(&storage, false);
+    }
+
+    #[test]
+    fn test_rocksdb_meta_storage_update_meta() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+
+        let config = RocksDbMetaStorageConfig {
+            path: dir.path().to_path_buf(),
+        };
+
+        RocksDbMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = RocksDbMetaStorage::new(config).expect("cant create meta storage");
+        test_update_meta(&storage);
+    }
+
+    #[test]
+    fn test_rocksdb_meta_storage_compare_and_update_blob_refs() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+
+        let config = RocksDbMetaStorageConfig {
+            path: dir.path().to_path_buf(),
+        };
+
+        RocksDbMetaStorage::init(&config).expect("Error in init of bucket storage!");
+
+        let storage = RocksDbMetaStorage::new(config).expect("cant create meta storage");
+        test_compare_and_update_blob_refs(&storage);
     }
 }
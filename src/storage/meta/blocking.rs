@@ -0,0 +1,98 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Blocking Pool Adapter
+//!
+//! Implements `AsyncMetaStorage` for any `MetaStorage`, every call runs on the
+//! blocking thread pool. Used for the backends without a native async driver.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::BlobRef;
+use crate::storage::meta::{AsyncMetaStorage, MetaStorage, MetaStorageError};
+use actix_threadpool::BlockingError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Runs the storage operation on the blocking thread pool.
+async fn run<F, T>(operation: F) -> Result<T, MetaStorageError>
+where
+    F: FnOnce() -> Result<T, MetaStorageError> + Send + 'static,
+    T: Send + 'static,
+{
+    actix_threadpool::run(operation).await.map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => {
+            eprintln!("Blocking: meta storage operation was canceled!");
+            MetaStorageError::BackendError("operation canceled")
+        }
+    })
+}
+
+pub struct BlockingMetaStorage {
+    storage: Arc<dyn MetaStorage>,
+}
+
+impl BlockingMetaStorage {
+    pub fn new(storage: Arc<dyn MetaStorage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl AsyncMetaStorage for BlockingMetaStorage {
+    async fn put(
+        &self,
+        meta: BlobMeta,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<(), MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.put(meta, blob_refs)).await
+    }
+
+    async fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.get_meta(id)).await
+    }
+
+    async fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.get_blob_refs(id)).await
+    }
+
+    async fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.update_blob_refs(id, blob_refs)).await
+    }
+
+    async fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError> {
+        let storage = self.storage.clone();
+        let expected = expected.clone();
+        run(move || storage.compare_and_update_blob_refs(id, &expected, blob_refs)).await
+    }
+
+    async fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.update_meta(id, meta)).await
+    }
+
+    async fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.list(after, limit)).await
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<(), MetaStorageError> {
+        let storage = self.storage.clone();
+        run(move || storage.delete(id)).await
+    }
+}
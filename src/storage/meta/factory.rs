@@ -2,17 +2,29 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 use crate::domain::meta::BlobMeta;
-use crate::storage::meta::{MetaStorage, MetaStorageError};
+use crate::storage::meta::{AsyncMetaStorage, MetaStorage, MetaStorageError};
 use crate::storage::meta::config::{MetaStorageConfig};
 use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
 use crate::storage::meta::backend::rocksdb::{RocksDbMetaStorage, RocksDbMetaStorageConfig};
-use crate::storage::meta::backend::postgres::{PostgresMetaStorage, PostgresMetaStorageConfig};
+use crate::storage::meta::backend::postgres::{AsyncPostgresMetaStorage, PostgresMetaStorage, PostgresMetaStorageConfig};
+use crate::storage::meta::blocking::BlockingMetaStorage;
 use std::any::Any;
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 
+/// Runs the global initialization of the configured meta storage backend.
+/// This needs to be called once per program lifetime before any storage instance is created.
+pub fn init_meta_storage(config: &MetaStorageConfig) -> Result<(), MetaStorageError> {
+    match config.storage_meta_type.as_ref() {
+        "mem" => MemoryMetaStorage::init(&config.storage_meta_mem),
+        "rocksdb" => RocksDbMetaStorage::init(&config.storage_meta_rocksdb),
+        "postgres" => PostgresMetaStorage::init(&config.storage_meta_postgres),
+        _ => Err(MetaStorageError::UnknownBackendError),
+    }
+}
+
 pub fn create_meta_storage(config: MetaStorageConfig) -> Result<Box<dyn MetaStorage>, MetaStorageError> {
     match config.storage_meta_type.as_ref() {
         "mem" => {
@@ -28,3 +40,16 @@ pub fn create_meta_storage(config: MetaStorageConfig) -> Result<Box<dyn MetaStor
     }
 }
 
+/// Creates the async meta storage, postgres uses the native async driver, the other
+/// backends run the given blocking storage on the blocking thread pool.
+pub async fn create_async_meta_storage(
+    config: MetaStorageConfig,
+    storage: Arc<dyn MetaStorage>,
+) -> Result<Arc<dyn AsyncMetaStorage>, MetaStorageError> {
+    match config.storage_meta_type.as_ref() {
+        "postgres" => {
+            Ok(Arc::new(AsyncPostgresMetaStorage::new(config.storage_meta_postgres).await?))
+        }
+        _ => Ok(Arc::new(BlockingMetaStorage::new(storage))),
+    }
+}
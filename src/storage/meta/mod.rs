@@ -2,8 +2,10 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 pub mod backend;
+pub mod blocking;
 pub mod config;
 pub mod factory;
+use async_trait::async_trait;
 use uuid::Uuid;
 use crate::domain::meta::BlobMeta;
 use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
@@ -22,23 +24,188 @@ pub enum MetaStorageError {
     BackendError(&'static str),
     InitError,
     PutError,
+    /// A lock of the storage was poisoned by a panic in another thread.
+    LockError,
 }
 
 
-pub trait MetaStorage {
+/// Trait all meta storage backends need to implement.
+pub trait MetaStorage: Send + Sync {
     /// Persist meta objects into the storage.
     fn put(
-        &mut self,
+        &self,
         meta: BlobMeta,
         blob_refs: HashMap<String, Box<dyn BlobRef>>
     ) -> Result<(), MetaStorageError>;
 
     /// Load meta object from storage.
-    fn get_meta(&mut self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError>;
+    fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError>;
 
     /// Load blob refs from storage.
-    fn get_blob_refs(&mut self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError>;
+    fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError>;
+
+    /// Replace the blob refs of a stored meta object in a single write, returns false
+    /// if there is no meta object with the id.
+    fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError>;
+
+    /// Replace the blob refs of a stored meta object only if they still equal `expected`,
+    /// returns false if they were changed in the meantime or there is no meta object with
+    /// the id.
+    fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError>;
+
+    /// Replace a stored meta object while keeping its blob refs, returns false if there
+    /// is no meta object with the id.
+    fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError>;
+
+    /// List meta objects ordered by their id, up to `limit` objects after the given id.
+    /// The id of the last object returned is the cursor for the next page.
+    fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError>;
+
+    /// Delete meta and blob refs from storage.
+    fn delete(&self, id: Uuid) -> Result<(), MetaStorageError>;
+}
+
+/// Async counterpart of `MetaStorage` for callers running on an async executor.
+#[async_trait]
+pub trait AsyncMetaStorage: Send + Sync {
+    /// Persist meta objects into the storage.
+    async fn put(
+        &self,
+        meta: BlobMeta,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<(), MetaStorageError>;
+
+    /// Load meta object from storage.
+    async fn get_meta(&self, id: Uuid) -> Result<Option<BlobMeta>, MetaStorageError>;
+
+    /// Load blob refs from storage.
+    async fn get_blob_refs(&self, id: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError>;
+
+    /// Replace the blob refs of a stored meta object, returns false if there is no meta
+    /// object with the id.
+    async fn update_blob_refs(
+        &self,
+        id: Uuid,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError>;
+
+    /// Replace the blob refs of a stored meta object only if they still equal `expected`,
+    /// returns false if they were changed in the meantime or there is no meta object with
+    /// the id.
+    async fn compare_and_update_blob_refs(
+        &self,
+        id: Uuid,
+        expected: &HashMap<String, Box<dyn BlobRef>>,
+        blob_refs: HashMap<String, Box<dyn BlobRef>>
+    ) -> Result<bool, MetaStorageError>;
+
+    /// Replace a stored meta object while keeping its blob refs, returns false if there
+    /// is no meta object with the id.
+    async fn update_meta(&self, id: Uuid, meta: BlobMeta) -> Result<bool, MetaStorageError>;
+
+    /// List meta objects ordered by their id, up to `limit` objects after the given id.
+    async fn list(&self, after: Option<Uuid>, limit: usize) -> Result<Vec<BlobMeta>, MetaStorageError>;
 
     /// Delete meta and blob refs from storage.
-    fn delete(&mut self, id: Uuid) -> Result<(), MetaStorageError>;
+    async fn delete(&self, id: Uuid) -> Result<(), MetaStorageError>;
+}
+
+/// Compares blob refs by their serialized form, the refs themselves can't be compared.
+pub(crate) fn same_blob_refs(
+    a: &HashMap<String, Box<dyn BlobRef>>,
+    b: &HashMap<String, Box<dyn BlobRef>>
+) -> Result<bool, MetaStorageError> {
+    Ok(serde_json::to_value(a)? == serde_json::to_value(b)?)
+}
+
+/// Lists the meta objects of the storage in pages of two, shared by the tests of the
+/// backends. With `shared` set other tests might store objects in the same storage.
+#[cfg(test)]
+pub(crate) fn test_list(storage: &dyn MetaStorage, shared: bool) {
+    let mut ids: Vec<Uuid> = Vec::new();
+    for size in 0..5 {
+        let meta = BlobMeta::new(size);
+        ids.push(meta.id);
+        storage.put(meta, HashMap::new()).expect("cant put to meta storage");
+    }
+    ids.sort();
+
+    // pages of two, continuing after the last id of the previous page:
+    let mut listed: Vec<Uuid> = Vec::new();
+    let mut after = None;
+    loop {
+        let page = storage.list(after, 2).unwrap();
+        assert!(page.len() <= 2);
+        match page.last() {
+            Some(meta) => after = Some(meta.id),
+            None => break,
+        }
+        listed.extend(page.iter().map(|meta| meta.id));
+    }
+    if shared {
+        assert!(ids.iter().all(|id| listed.contains(id)));
+    } else {
+        assert_eq!(listed, ids);
+    }
+
+    for id in ids {
+        storage.delete(id).unwrap();
+    }
+}
+
+/// Replaces the meta of a stored object, shared by the tests of the backends. The blob
+/// refs need to stay untouched.
+#[cfg(test)]
+pub(crate) fn test_update_meta(storage: &dyn MetaStorage) {
+    use crate::storage::blob::backend::mem::MemoryBlobRef;
+
+    let meta = BlobMeta::new(42);
+    let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+    blob_refs.insert("mem".to_string(), Box::new(MemoryBlobRef { index: 42 }));
+    storage.put(meta.clone(), blob_refs).expect("cant put to meta storage");
+
+    let updated = BlobMeta { size: 23, ..meta.clone() };
+    assert!(storage.update_meta(meta.id, updated).unwrap());
+    assert_eq!(storage.get_meta(meta.id).unwrap().unwrap().size, 23);
+    let blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
+    assert_eq!(blob_refs.len(), 1);
+    assert!(blob_refs.contains_key("mem"));
+
+    storage.delete(meta.id).unwrap();
+    assert!(!storage.update_meta(meta.id, meta.clone()).unwrap());
+    assert!(storage.get_meta(meta.id).unwrap().is_none());
+}
+
+/// Swaps the blob refs of a stored object, shared by the tests of the backends. Refs
+/// changed in the meantime must not be replaced.
+#[cfg(test)]
+pub(crate) fn test_compare_and_update_blob_refs(storage: &dyn MetaStorage) {
+    use crate::storage::blob::backend::mem::MemoryBlobRef;
+
+    let refs = |index| {
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert("mem".to_string(), Box::new(MemoryBlobRef { index }) as Box<dyn BlobRef>);
+        blob_refs
+    };
+    let meta = BlobMeta::new(42);
+    storage.put(meta.clone(), refs(1)).expect("cant put to meta storage");
+
+    assert!(storage.compare_and_update_blob_refs(meta.id, &refs(1), refs(2)).unwrap());
+    // changed in the meantime:
+    assert!(!storage.compare_and_update_blob_refs(meta.id, &refs(1), refs(3)).unwrap());
+    let blob_refs = storage.get_blob_refs(meta.id).unwrap().unwrap();
+    assert!(same_blob_refs(&blob_refs, &refs(2)).unwrap());
+
+    storage.delete(meta.id).unwrap();
+    assert!(!storage.compare_and_update_blob_refs(meta.id, &refs(2), refs(3)).unwrap());
+    assert!(storage.get_blob_refs(meta.id).unwrap().is_none());
 }
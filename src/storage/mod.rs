@@ -2,4 +2,10 @@
 // Copyright (C) 2020 Matthias Hecker
 // Licensed under the Apache License, Version 2.0, or the MIT License
 pub mod blob;
+pub mod compaction;
+pub mod gc;
 pub mod meta;
+pub mod recovery;
+pub mod rotation;
+pub mod store;
+pub mod variant;
@@ -0,0 +1,160 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Disaster Recovery
+//!
+//! Rebuilds the meta storage from the self-describing records in the bucket files,
+//! in case the meta storage is lost.
+//!
+//! Only a plain bucket storage can be recovered: the refs of the encrypting, compressing
+//! and deduplicating storages hold state that is not part of the bucket records, like the
+//! wrapped data key or the stored size, so their blob refs can't be rebuilt.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::{BlobRef, BlobStorageError};
+use crate::storage::blob::backend::bucket::BucketBlobStorage;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+    /// The backend type is not the bucket backend.
+    UnsupportedBackend(String),
+    /// The blobs are stored through the named decorating storage.
+    DecoratedStorage(&'static str),
+}
+
+impl From<BlobStorageError> for RecoveryError {
+    fn from(error: BlobStorageError) -> Self {
+        RecoveryError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for RecoveryError {
+    fn from(error: MetaStorageError) -> Self {
+        RecoveryError::MetaStorageError(error)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Number of blobs put into the meta storage.
+    pub recovered: usize,
+    /// Number of blobs already known to the meta storage.
+    pub skipped: usize,
+}
+
+/// Scans every bucket file and puts the rebuilt meta and blob refs into the meta storage.
+/// The blob refs are stored under the given backend name. Blobs already present in the
+/// meta storage are left untouched, so recovery can be repeated after an interruption.
+pub fn rebuild_meta_storage(
+    config: &BlobStorageConfig,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+) -> Result<RecoveryReport, RecoveryError> {
+    if config.storage_blob_type != "bucket" {
+        return Err(RecoveryError::UnsupportedBackend(config.storage_blob_type.clone()));
+    }
+    if config.storage_blob_encryption.is_some() {
+        return Err(RecoveryError::DecoratedStorage("encryption"));
+    }
+    if config.storage_blob_compression.is_some() {
+        return Err(RecoveryError::DecoratedStorage("compression"));
+    }
+    if config.storage_blob_dedup.is_some() {
+        return Err(RecoveryError::DecoratedStorage("dedup"));
+    }
+    let bucket = config.storage_blob_bucket.as_ref().ok_or(BlobStorageError::StorageConfigError)?;
+
+    let mut report = RecoveryReport::default();
+
+    for record in BucketBlobStorage::scan(bucket)? {
+        if meta_storage.get_meta(record.id)?.is_some() {
+            report.skipped += 1;
+            continue;
+        }
+
+        let meta = BlobMeta {
+            id: record.id,
+            ..BlobMeta::new(record.blob_ref.size)
+        };
+        let mut blob_refs: HashMap<String, Box<dyn BlobRef>> = HashMap::new();
+        blob_refs.insert(backend.to_string(), Box::new(record.blob_ref));
+
+        meta_storage.put(meta, blob_refs)?;
+        report.recovered += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::BlobStorage;
+    use crate::storage::blob::backend::bucket::{BucketBlobRef, BucketBlobStorage};
+    use crate::storage::blob::config::BlobStorageConfig;
+    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use crate::storage::recovery::{rebuild_meta_storage, RecoveryError};
+    use tempfile::tempdir;
+
+    fn config(yaml: &str) -> BlobStorageConfig {
+        serde_yaml::from_str(yaml).expect("invalid blob storage config!")
+    }
+
+    #[test]
+    fn test_rebuild_meta_storage() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = config(&format!(
+            "storage_blob_type: bucket\nstorage_blob_bucket:\n  path: {}\n  max_size: 25769803776\n",
+            dir.path().display()
+        ));
+        let bucket = config.storage_blob_bucket.clone().unwrap();
+        BucketBlobStorage::init(&bucket).expect("Error in init of bucket storage!");
+        let storage = BucketBlobStorage::new(bucket).expect("error creating the blob storage");
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta_1 = BlobMeta::new(buffer.len());
+        let meta_2 = BlobMeta::new(buffer.len());
+        let reference_1 = storage.put(&meta_1, buffer.to_vec()).expect("put blob failed!");
+        let reference_2 = storage.put(&meta_2, buffer.to_vec()).expect("put blob failed!");
+        storage.delete(&meta_2, &reference_2).expect("delete blob failed!");
+
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        let report = rebuild_meta_storage(&config, "bucket", &meta_storage).expect("recovery failed!");
+        assert_eq!(report.recovered, 1);
+
+        let got_meta = meta_storage.get_meta(meta_1.id).unwrap().expect("expected recovered meta");
+        assert_eq!(got_meta.size, meta_1.size);
+        assert!(meta_storage.get_meta(meta_2.id).unwrap().is_none());
+
+        let got_blob_refs = meta_storage.get_blob_refs(meta_1.id).unwrap().unwrap();
+        let got_bucket_blob_ref = got_blob_refs.get("bucket").unwrap().any().downcast_ref::<BucketBlobRef>().unwrap();
+        let bucket_blob_ref = reference_1.any().downcast_ref::<BucketBlobRef>().unwrap();
+        assert_eq!(got_bucket_blob_ref.offset, bucket_blob_ref.offset);
+        assert_eq!(storage.get(&got_meta, got_blob_refs.get("bucket").unwrap()).unwrap(), buffer);
+
+        // repeated recovery leaves existing metas alone
+        let report = rebuild_meta_storage(&config, "bucket", &meta_storage).expect("recovery failed!");
+        assert_eq!(report.recovered, 0);
+        assert_eq!(report.skipped, 1);
+    }
+
+    #[test]
+    fn test_rebuild_meta_storage_decorated() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).expect("cant create meta storage");
+        let config = config(&format!(
+            "storage_blob_type: bucket\nstorage_blob_bucket:\n  path: {}\n  max_size: 25769803776\nstorage_blob_compression:\n  codec: zstd\n",
+            dir.path().display()
+        ));
+        match rebuild_meta_storage(&config, "bucket", &meta_storage) {
+            Err(RecoveryError::DecoratedStorage(decorator)) => assert_eq!(decorator, "compression"),
+            _ => panic!("expected the recovery of a compressed storage to be refused!"),
+        }
+    }
+}
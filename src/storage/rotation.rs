@@ -0,0 +1,301 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Master Key Rotation
+//!
+//! Rewraps the data keys of all encrypted blobs with the current master key of the
+//! configured blob storage. The blob ref of every meta object is rotated through the
+//! decorating, mirrored and tiered storages (see `BlobStorage::rotate`) and the new
+//! blob ref is updated in the meta storage, the blobs themselves are not rewritten.
+//!
+//! A rewrap doesn't help if the data keys are compromised with a leaked master key,
+//! `reencrypt_blobs` rewrites the blobs instead: each blob is read and stored again
+//! with a new data key, the blob ref is swapped in the meta storage and the previous
+//! blob is deleted. The rewritten blobs are stored like new ones (in the first tier of
+//! a tiered storage). It is refused with deduplication configured, a duplicate would
+//! be stored as the existing blob again.
+//!
+//! The rotation needs to run while the service is stopped, the digest index of the
+//! deduplication is updated as well. It can be repeated after an interruption or
+//! failure, blob refs wrapped with the current master key already are left untouched.
+//!
+use crate::domain::meta::BlobMeta;
+use crate::storage::blob::config::BlobStorageConfig;
+use crate::storage::blob::mirror::{blob_ref, blob_refs};
+use crate::storage::blob::tiered::keep_retired;
+use crate::storage::blob::{BlobRef, BlobStorage, BlobStorageError};
+use crate::storage::gc::uses_dedup;
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Number of meta objects listed at once.
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug)]
+pub enum RotationError {
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+    /// Rewriting the blobs of a deduplicated storage is not supported.
+    DedupReencryptionError,
+}
+
+impl From<BlobStorageError> for RotationError {
+    fn from(error: BlobStorageError) -> Self {
+        RotationError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for RotationError {
+    fn from(error: MetaStorageError) -> Self {
+        RotationError::MetaStorageError(error)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RotationReport {
+    /// Number of blob refs rewrapped with the current master key, or rewritten with a new
+    /// data key.
+    pub rotated: usize,
+    /// Number of blob refs wrapped with the current master key already, or unencrypted.
+    pub unchanged: usize,
+    /// Ids of the meta objects whose blob ref failed to rotate, e.g. with a missing master key.
+    pub failed: Vec<Uuid>,
+}
+
+/// Rotates the blob refs of all meta objects, looked up under the given backend name.
+pub fn rotate_keys(
+    blob_storage: &dyn BlobStorage,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+) -> Result<RotationReport, RotationError> {
+    rotate(blob_storage, backend, meta_storage, false)
+}
+
+/// Rewrites all blobs encrypted with a previous master key with new data keys, looked up
+/// under the given backend name.
+pub fn reencrypt_blobs(
+    config: &BlobStorageConfig,
+    blob_storage: &dyn BlobStorage,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+) -> Result<RotationReport, RotationError> {
+    if uses_dedup(config) {
+        return Err(RotationError::DedupReencryptionError);
+    }
+    rotate(blob_storage, backend, meta_storage, true)
+}
+
+fn rotate(
+    blob_storage: &dyn BlobStorage,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+    reencrypt: bool,
+) -> Result<RotationReport, RotationError> {
+    let mut report = RotationReport::default();
+    let mut after = None;
+    loop {
+        let page = meta_storage.list(after, PAGE_SIZE)?;
+        after = match page.last() {
+            Some(meta) => Some(meta.id),
+            None => break,
+        };
+
+        for meta in page {
+            let refs = match meta_storage.get_blob_refs(meta.id)? {
+                Some(refs) => refs,
+                None => continue,
+            };
+            let current = match blob_ref(backend, refs.clone()) {
+                Some(current) => current,
+                None => continue,
+            };
+            let rotated = match blob_storage.rotate(&current) {
+                Ok(Some(_)) if reencrypt => rewrite(blob_storage, backend, meta_storage, &meta, &refs, current),
+                // false if the meta was deleted in the meantime
+                Ok(Some(rotated)) => Ok(meta_storage.update_blob_refs(meta.id, keep_retired(&refs, blob_refs(backend, rotated)))?),
+                Ok(None) => {
+                    report.unchanged += 1;
+                    continue;
+                }
+                Err(err) => Err(err.into()),
+            };
+            match rotated {
+                Ok(true) => report.rotated += 1,
+                Ok(false) => {}
+                Err(RotationError::BlobStorageError(err)) => {
+                    eprintln!("Rotation: error rotating blob ref of {}: {:?}", meta, err);
+                    report.failed.push(meta.id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Stores the blob again with a new data key and swaps the blob ref, the previous blob is
+/// deleted once it is no longer referenced. Returns false if the blob ref was changed or the
+/// meta deleted in the meantime, the rewritten blob is deleted again.
+fn rewrite(
+    blob_storage: &dyn BlobStorage,
+    backend: &str,
+    meta_storage: &dyn MetaStorage,
+    meta: &BlobMeta,
+    refs: &HashMap<String, Box<dyn BlobRef>>,
+    current: Box<dyn BlobRef>,
+) -> Result<bool, RotationError> {
+    let mut reader = blob_storage.get_stream(meta, &current)?;
+    let rewritten = blob_storage.put_stream(meta, &mut reader)?;
+
+    let updated = keep_retired(refs, blob_refs(backend, rewritten.clone()));
+    if !meta_storage.compare_and_update_blob_refs(meta.id, refs, updated)? {
+        blob_storage.delete(meta, &rewritten)?;
+        return Ok(false);
+    }
+    if let Err(err) = blob_storage.delete(meta, &current) {
+        eprintln!("Rotation: error deleting previous blob of {}, left to gc: {:?}", meta, err);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::backend::bucket::{BucketBlobStorage, BucketBlobStorageConfig};
+    use crate::storage::blob::compression::CompressedBlobRef;
+    use crate::storage::blob::config::BlobStorageConfig;
+    use crate::storage::blob::dedup::DedupBlobStorageConfig;
+    use crate::storage::blob::encryption::EncryptedBlobRef;
+    use crate::storage::blob::factory::{create_blob_storage, init_blob_storage};
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use crate::storage::rotation::{reencrypt_blobs, rotate_keys, RotationError};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn config(path: &Path, key_id: &str, keys: &[&str]) -> BlobStorageConfig {
+        BlobStorageConfig {
+            storage_blob_dedup: Some(DedupBlobStorageConfig {
+                hash: "sha2_256".to_string(),
+                index_path: Some(path.join("dedup")),
+            }),
+            ..config_without_dedup(path, key_id, keys)
+        }
+    }
+
+    fn config_without_dedup(path: &Path, key_id: &str, keys: &[&str]) -> BlobStorageConfig {
+        let keys: Vec<String> = keys.iter().map(|key| format!("      \"{}\": \"{}\"", key, key.repeat(32))).collect();
+        serde_yaml::from_str(&format!(
+            "storage_blob_type: bucket
+storage_blob_bucket:
+  path: {bucket:?}
+  max_size: 1073741824
+  garbage_ratio: 0.5
+storage_blob_encryption:
+  key_id: \"{key_id}\"
+  keys:
+{keys}
+storage_blob_compression:
+  codec: zstd
+  level: 3
+",
+            bucket = path.join("bucket"),
+            key_id = key_id,
+            keys = keys.join("\n"),
+        ))
+        .expect("invalid blob storage config!")
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffer: Vec<u8> = (0..4096).map(|n| (n % 7) as u8).collect();
+        let metas: Vec<BlobMeta> = (0..3).map(|_| BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256)).collect();
+        {
+            let config = config(dir.path(), "11", &["11"]);
+            init_blob_storage(&config).expect("error initializing blob storage!");
+            let storage = create_blob_storage(config).expect("error creating blob storage!");
+            for meta in metas.iter() {
+                let blob_ref = storage.put(meta, buffer.clone()).expect("put blob failed!");
+                let mut blob_refs = HashMap::new();
+                blob_refs.insert("bucket".to_string(), blob_ref);
+                meta_storage.put(meta.clone(), blob_refs).unwrap();
+            }
+        }
+
+        let storage = create_blob_storage(config(dir.path(), "22", &["11", "22"])).unwrap();
+        let report = rotate_keys(storage.as_ref(), "bucket", &meta_storage).expect("rotation failed!");
+        assert_eq!((report.rotated, report.unchanged, report.failed.len()), (3, 0, 0));
+        let report = rotate_keys(storage.as_ref(), "bucket", &meta_storage).expect("rotation failed!");
+        assert_eq!((report.rotated, report.unchanged, report.failed.len()), (0, 3, 0));
+        drop(storage);
+
+        // readable without the previous master key, new duplicates share the rotated blob:
+        let storage = create_blob_storage(config(dir.path(), "22", &["22"])).unwrap();
+        for meta in metas.iter() {
+            let blob_refs = meta_storage.get_blob_refs(meta.id).unwrap().unwrap();
+            assert_eq!(storage.get(meta, &blob_refs["bucket"]).expect("get blob failed!"), buffer);
+        }
+        let meta = BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256);
+        let blob_ref = storage.put(&meta, buffer.clone()).expect("put blob failed!");
+        assert_eq!(storage.get(&meta, &blob_ref).expect("get blob failed!"), buffer);
+    }
+
+    #[test]
+    fn test_reencrypt_blobs() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let meta_storage = MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap();
+
+        let buffer: Vec<u8> = (0..4096).map(|n| (n % 7) as u8).collect();
+        let metas: Vec<BlobMeta> = (0..3).map(|_| BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256)).collect();
+        {
+            let config = config_without_dedup(dir.path(), "11", &["11"]);
+            init_blob_storage(&config).expect("error initializing blob storage!");
+            let storage = create_blob_storage(config).expect("error creating blob storage!");
+            for meta in metas.iter() {
+                let blob_ref = storage.put(meta, buffer.clone()).expect("put blob failed!");
+                let mut blob_refs = HashMap::new();
+                blob_refs.insert("bucket".to_string(), blob_ref);
+                meta_storage.put(meta.clone(), blob_refs).unwrap();
+            }
+        }
+
+        // a duplicate would not be rewritten:
+        let config = config(dir.path(), "22", &["11", "22"]);
+        let storage = create_blob_storage(config.clone()).unwrap();
+        match reencrypt_blobs(&config, storage.as_ref(), "bucket", &meta_storage) {
+            Err(RotationError::DedupReencryptionError) => {}
+            _ => panic!("expected the reencryption of a deduplicated storage to be refused!"),
+        }
+        drop(storage);
+
+        let config = config_without_dedup(dir.path(), "22", &["11", "22"]);
+        let storage = create_blob_storage(config.clone()).unwrap();
+        let report = reencrypt_blobs(&config, storage.as_ref(), "bucket", &meta_storage).expect("rotation failed!");
+        assert_eq!((report.rotated, report.unchanged, report.failed.len()), (3, 0, 0));
+        let report = reencrypt_blobs(&config, storage.as_ref(), "bucket", &meta_storage).expect("rotation failed!");
+        assert_eq!((report.rotated, report.unchanged, report.failed.len()), (0, 3, 0));
+        drop(storage);
+
+        // only the rewritten blobs are left, readable without the previous master key:
+        let bucket = BucketBlobStorageConfig {
+            path: dir.path().join("bucket"),
+            max_size: 1073741824,
+            garbage_ratio: 0.5,
+        };
+        assert_eq!(BucketBlobStorage::scan(&bucket).expect("scan failed!").len(), 3);
+        let storage = create_blob_storage(config_without_dedup(dir.path(), "22", &["22"])).unwrap();
+        for meta in metas.iter() {
+            let blob_refs = meta_storage.get_blob_refs(meta.id).unwrap().unwrap();
+            let compressed = blob_refs["bucket"].any().downcast_ref::<CompressedBlobRef>().unwrap();
+            assert_eq!(compressed.blob_ref.any().downcast_ref::<EncryptedBlobRef>().unwrap().key_id, "22");
+            assert_eq!(storage.get(meta, &blob_refs["bucket"]).expect("get blob failed!"), buffer);
+        }
+    }
+}
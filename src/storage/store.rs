@@ -0,0 +1,327 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Media Store
+//!
+//! Owns the blob and the meta storage and keeps them consistent: a blob is stored
+//! before its meta and removed again if the meta can't be stored. Media is removed
+//! meta first, a failure leaves an orphaned blob behind that garbage collection can
+//! reclaim, instead of meta pointing at a missing blob.
+//!
+//! The store is async, blocking backends are wrapped in the blocking pool adapters.
+//! Streamed media is spooled to a temporary file first, its size and checksum need to
+//! be known before the blob is stored.
+//!
+use crate::domain::meta::{BlobMeta, Checksum};
+use crate::storage::blob::blocking::{consume_stream, read_stream};
+use crate::storage::blob::hashing::{Hash, HashingReader};
+use crate::storage::blob::mirror::{blob_ref, blob_refs};
+use crate::storage::blob::{AsyncBlobStorage, BlobRef, BlobStorageError, BlobStream};
+use crate::storage::meta::{AsyncMetaStorage, MetaStorageError};
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// There is no media with the id.
+    NotFound,
+    BlobStorageError(BlobStorageError),
+    MetaStorageError(MetaStorageError),
+}
+
+impl From<BlobStorageError> for StoreError {
+    fn from(error: BlobStorageError) -> Self {
+        StoreError::BlobStorageError(error)
+    }
+}
+
+impl From<MetaStorageError> for StoreError {
+    fn from(error: MetaStorageError) -> Self {
+        StoreError::MetaStorageError(error)
+    }
+}
+
+pub struct MediaStore {
+    /// Name of the blob storage backend, used as the key in the blob ref map.
+    backend: String,
+    /// Hash algorithm of the checksum computed for stored media.
+    checksum: Hash,
+    blob: Arc<dyn AsyncBlobStorage>,
+    meta: Arc<dyn AsyncMetaStorage>,
+}
+
+impl MediaStore {
+    pub fn new(
+        backend: String,
+        checksum: Hash,
+        blob: Arc<dyn AsyncBlobStorage>,
+        meta: Arc<dyn AsyncMetaStorage>,
+    ) -> Self {
+        Self { backend, checksum, blob, meta }
+    }
+
+    /// Stores the blob and its meta, returns the meta of the new media.
+    pub async fn store(&self, buffer: Vec<u8>) -> Result<BlobMeta, StoreError> {
+        let meta = BlobMeta::new_from_buffer(&buffer, &self.checksum);
+        self.put(meta, buffer).await
+    }
+
+    /// Stores a variant derived from the source media under the given id.
+    pub async fn store_variant(&self, source: Uuid, id: Uuid, buffer: Vec<u8>) -> Result<BlobMeta, StoreError> {
+        let meta = BlobMeta {
+            id,
+            source: Some(source),
+            ..BlobMeta::new_from_buffer(&buffer, &self.checksum)
+        };
+        self.put(meta, buffer).await
+    }
+
+    /// Stores the streamed blob and its meta, returns the meta of the new media.
+    pub async fn store_stream(&self, stream: BlobStream) -> Result<BlobMeta, StoreError> {
+        let hash = self.checksum;
+        let (file, size, digest) = consume_stream(stream, move |reader| {
+            let mut reader = HashingReader::new(reader, &hash).ok_or(BlobStorageError::StorageConfigError)?;
+            let mut file = tempfile::tempfile()?;
+            let size = io::copy(&mut reader, &mut file)? as usize;
+            file.seek(SeekFrom::Start(0))?;
+            Ok((file, size, reader.finalize()))
+        }).await?;
+
+        let meta = BlobMeta {
+            checksum: Some(Checksum::from_digest(&self.checksum, &digest)),
+            ..BlobMeta::new(size)
+        };
+        let blob_ref = self.blob.put_stream(&meta, read_stream(Box::new(file))).await?;
+        self.put_meta(meta, blob_ref).await
+    }
+
+    async fn put(&self, meta: BlobMeta, buffer: Vec<u8>) -> Result<BlobMeta, StoreError> {
+        let blob_ref = self.blob.put(&meta, buffer).await?;
+        self.put_meta(meta, blob_ref).await
+    }
+
+    /// Stores the meta of a stored blob, the blob is removed again if that fails.
+    async fn put_meta(&self, meta: BlobMeta, blob_ref: Box<dyn BlobRef>) -> Result<BlobMeta, StoreError> {
+        if let Err(err) = self.meta.put(meta.clone(), blob_refs(&self.backend, blob_ref.clone())).await {
+            // the blob is unreachable without its meta, remove it again
+            if let Err(delete_err) = self.blob.delete(&meta, &blob_ref).await {
+                eprintln!("Store: error removing orphaned blob {}: {:?}", blob_ref, delete_err);
+            }
+            return Err(err.into());
+        }
+
+        Ok(meta)
+    }
+
+    /// Replaces the meta of stored media, its blob refs are kept.
+    pub async fn update_meta(&self, meta: BlobMeta) -> Result<(), StoreError> {
+        if !self.meta.update_meta(meta.id, meta).await? {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Returns the meta of the media.
+    pub async fn load_meta(&self, id: Uuid) -> Result<BlobMeta, StoreError> {
+        self.meta.get_meta(id).await?.ok_or(StoreError::NotFound)
+    }
+
+    /// Returns the meta and the blob ref of the configured backend.
+    async fn load_blob_ref(&self, id: Uuid) -> Result<(BlobMeta, Box<dyn BlobRef>), StoreError> {
+        let meta = self.meta.get_meta(id).await?.ok_or(StoreError::NotFound)?;
+        let blob_ref = self
+            .meta
+            .get_blob_refs(id)
+            .await?
+            .and_then(|refs| blob_ref(&self.backend, refs))
+            .ok_or(StoreError::NotFound)?;
+
+        Ok((meta, blob_ref))
+    }
+
+    /// Returns the meta and the binary contents of the media.
+    pub async fn load(&self, id: Uuid) -> Result<(BlobMeta, Vec<u8>), StoreError> {
+        let (meta, blob_ref) = self.load_blob_ref(id).await?;
+        let buffer = self.blob.get(&meta, &blob_ref).await?;
+        Ok((meta, buffer))
+    }
+
+    /// Returns the meta and a stream of the binary contents of the media.
+    pub async fn load_stream(&self, id: Uuid) -> Result<(BlobMeta, BlobStream), StoreError> {
+        let (meta, blob_ref) = self.load_blob_ref(id).await?;
+        let stream = self.blob.get_stream(&meta, &blob_ref).await?;
+        Ok((meta, stream))
+    }
+
+    /// Returns a range of the binary contents of the media.
+    pub async fn load_range(&self, id: Uuid, offset: usize, len: usize) -> Result<Vec<u8>, StoreError> {
+        let (meta, blob_ref) = self.load_blob_ref(id).await?;
+        let buffer = self.blob.get_range(&meta, &blob_ref, offset, len).await?;
+        Ok(buffer)
+    }
+
+    /// Removes the meta and the blob of the media.
+    pub async fn remove(&self, id: Uuid) -> Result<(), StoreError> {
+        let (meta, blob_ref) = self.load_blob_ref(id).await?;
+
+        self.meta.delete(id).await?;
+        self.blob.delete(&meta, &blob_ref).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::{BlobRef, BlobStorageError};
+    use crate::storage::blob::backend::fs::{FsBlobStorage, FsBlobStorageConfig};
+    use crate::storage::blob::blocking::BlockingBlobStorage;
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::meta::{MetaStorage, MetaStorageError};
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use crate::storage::meta::blocking::BlockingMetaStorage;
+    use crate::storage::store::{MediaStore, StoreError};
+    use bytes::Bytes;
+    use futures::{stream, TryStreamExt};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    /// Fails every write.
+    struct FailingMetaStorage;
+
+    impl MetaStorage for FailingMetaStorage {
+        fn put(&self, _: BlobMeta, _: HashMap<String, Box<dyn BlobRef>>) -> Result<(), MetaStorageError> {
+            Err(MetaStorageError::PutError)
+        }
+
+        fn get_meta(&self, _: Uuid) -> Result<Option<BlobMeta>, MetaStorageError> {
+            Ok(None)
+        }
+
+        fn get_blob_refs(&self, _: Uuid) -> Result<Option<HashMap<String, Box<dyn BlobRef>>>, MetaStorageError> {
+            Ok(None)
+        }
+
+        fn update_blob_refs(&self, _: Uuid, _: HashMap<String, Box<dyn BlobRef>>) -> Result<bool, MetaStorageError> {
+            Err(MetaStorageError::PutError)
+        }
+
+        fn compare_and_update_blob_refs(
+            &self,
+            _: Uuid,
+            _: &HashMap<String, Box<dyn BlobRef>>,
+            _: HashMap<String, Box<dyn BlobRef>>,
+        ) -> Result<bool, MetaStorageError> {
+            Err(MetaStorageError::PutError)
+        }
+
+        fn update_meta(&self, _: Uuid, _: BlobMeta) -> Result<bool, MetaStorageError> {
+            Err(MetaStorageError::PutError)
+        }
+
+        fn list(&self, _: Option<Uuid>, _: usize) -> Result<Vec<BlobMeta>, MetaStorageError> {
+            Ok(Vec::new())
+        }
+
+        fn delete(&self, _: Uuid) -> Result<(), MetaStorageError> {
+            Err(MetaStorageError::PutError)
+        }
+    }
+
+    fn fs_storage(config: &FsBlobStorageConfig) -> Arc<BlockingBlobStorage> {
+        FsBlobStorage::init(config).expect("Error in init of fs storage!");
+        let storage = FsBlobStorage::new(config.clone()).expect("error creating the blob storage");
+        Arc::new(BlockingBlobStorage::new(Arc::new(storage)))
+    }
+
+    #[actix_rt::test]
+    async fn test_media_store() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = FsBlobStorageConfig { path: dir.path().to_path_buf(), fan_out: 1 };
+        let store = MediaStore::new(
+            "fs".to_string(),
+            Hash::Sha2_256,
+            fs_storage(&config),
+            Arc::new(BlockingMetaStorage::new(Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap()))),
+        );
+
+        let buffer: Vec<u8> = vec![0, 42, 0, 42, 0];
+        let meta = store.store(buffer.to_vec()).await.expect("store failed!");
+        assert_eq!(meta.size, buffer.len());
+        assert!(meta.checksum.is_some());
+
+        assert_eq!(store.load_meta(meta.id).await.unwrap().checksum, meta.checksum);
+        let (loaded, contents) = store.load(meta.id).await.unwrap();
+        assert_eq!((loaded.id, contents), (meta.id, buffer.to_vec()));
+        assert_eq!(store.load_range(meta.id, 1, 2).await.unwrap(), &buffer[1..3]);
+
+        let mut updated = meta.clone();
+        updated.variant_errors.insert("thumbnail".to_string(), "error".to_string());
+        store.update_meta(updated).await.expect("update failed!");
+        assert_eq!(store.load_meta(meta.id).await.unwrap().variant_errors.len(), 1);
+        assert_eq!(store.load(meta.id).await.unwrap().1, buffer);
+
+        store.remove(meta.id).await.expect("remove failed!");
+        assert!(FsBlobStorage::scan(&config).unwrap().is_empty());
+        match store.load(meta.id).await {
+            Err(StoreError::NotFound) => {}
+            _ => panic!("expected removed media to be gone!"),
+        }
+        match store.remove(Uuid::new_v4()).await {
+            Err(StoreError::NotFound) => {}
+            _ => panic!("expected unknown media to be not found!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_media_store_stream() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = FsBlobStorageConfig { path: dir.path().to_path_buf(), fan_out: 1 };
+        let store = MediaStore::new(
+            "fs".to_string(),
+            Hash::Sha2_256,
+            fs_storage(&config),
+            Arc::new(BlockingMetaStorage::new(Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap()))),
+        );
+
+        let buffer: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<_> = buffer.chunks(1000).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        let meta = store.store_stream(Box::pin(stream::iter(chunks))).await.expect("store failed!");
+        assert_eq!(meta.size, buffer.len());
+        assert_eq!(meta.checksum, BlobMeta::new_from_buffer(&buffer, &Hash::Sha2_256).checksum);
+
+        let (loaded, stream) = store.load_stream(meta.id).await.unwrap();
+        assert_eq!(loaded.id, meta.id);
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), buffer);
+
+        // a failing stream stores nothing:
+        let failing = stream::iter(vec![Ok(Bytes::from(vec![1, 2, 3])), Err(BlobStorageError::IOError)]);
+        assert!(store.store_stream(Box::pin(failing)).await.is_err());
+        assert_eq!(FsBlobStorage::scan(&config).unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_media_store_rollback() {
+        let dir = tempdir().expect("expected to write temporary directory!");
+        let config = FsBlobStorageConfig { path: dir.path().to_path_buf(), fan_out: 1 };
+        let store = MediaStore::new(
+            "fs".to_string(),
+            Hash::Sha2_256,
+            fs_storage(&config),
+            Arc::new(BlockingMetaStorage::new(Arc::new(FailingMetaStorage))),
+        );
+
+        match store.store(vec![1, 2, 3]).await {
+            Err(StoreError::MetaStorageError(MetaStorageError::PutError)) => {}
+            _ => panic!("expected the meta storage error!"),
+        }
+        // the blob is removed again:
+        assert!(FsBlobStorage::scan(&config).unwrap().is_empty());
+    }
+}
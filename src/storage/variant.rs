@@ -0,0 +1,437 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Variant Cache
+//!
+//! Derived image variants are stored as media of their own: their id is a hash of the
+//! id of the original and the canonical form of the variant parameters, their meta
+//! refers back to the original. The cache keeps an index of the stored variants to
+//! evict the least recently used ones once their total size exceeds the maximum, and
+//! removes the variants of an original when it is deleted.
+//!
+//! The variants of the eager presets are pinned: they are neither evicted nor limited
+//! by the maximum size, and are only removed along with their original.
+//!
+//! Concurrent puts of the same variant store it only once. A variant whose original is
+//! removed while it is stored is removed again instead of being indexed.
+//!
+//! The index is rebuilt from the meta storage on startup. Variants recovered from the
+//! bucket files lose the reference to their original and become ordinary media.
+//!
+use crate::storage::blob::hashing::Hash;
+use crate::storage::meta::{MetaStorage, MetaStorageError};
+use crate::storage::store::{MediaStore, StoreError};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Number of meta objects listed at once while loading the index.
+const PAGE_SIZE: usize = 1000;
+
+struct VariantEntry {
+    source: Uuid,
+    size: usize,
+    /// Tick of the last access.
+    accessed: u64,
+    /// Pinned variants are not evicted.
+    pinned: bool,
+}
+
+/// Stored variants ordered by their last access.
+#[derive(Default)]
+struct VariantIndex {
+    entries: HashMap<Uuid, VariantEntry>,
+    /// Ids of the variants that can be evicted by the tick of their last access.
+    recency: BTreeMap<u64, Uuid>,
+    /// Ids of the variants of each original.
+    sources: HashMap<Uuid, HashSet<Uuid>>,
+    /// Originals of the variants being stored, by the id of the variant.
+    pending: HashMap<Uuid, Uuid>,
+    /// Total size of the variants that can be evicted.
+    size: usize,
+    tick: u64,
+}
+
+impl VariantIndex {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, id: Uuid, source: Uuid, size: usize, pinned: bool) {
+        self.remove(id);
+        let accessed = self.next_tick();
+        self.entries.insert(id, VariantEntry { source, size, accessed, pinned });
+        self.sources.entry(source).or_default().insert(id);
+        if !pinned {
+            self.recency.insert(accessed, id);
+            self.size += size;
+        }
+    }
+
+    /// Marks the variant as most recently used, returns false if it isn't indexed.
+    fn touch(&mut self, id: Uuid) -> bool {
+        let accessed = self.next_tick();
+        match self.entries.get_mut(&id) {
+            Some(entry) if entry.pinned => true,
+            Some(entry) => {
+                self.recency.remove(&entry.accessed);
+                entry.accessed = accessed;
+                self.recency.insert(accessed, id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: Uuid) {
+        if let Some(entry) = self.entries.remove(&id) {
+            if let Some(variants) = self.sources.get_mut(&entry.source) {
+                variants.remove(&id);
+                if variants.is_empty() {
+                    self.sources.remove(&entry.source);
+                }
+            }
+            if !entry.pinned {
+                self.recency.remove(&entry.accessed);
+                self.size -= entry.size;
+            }
+        }
+    }
+
+    /// Removes the least recently used variants until the total size is within the
+    /// maximum, returns their ids.
+    fn evict(&mut self, max_size: usize) -> Vec<Uuid> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let id = match self.recency.values().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            self.remove(id);
+            evicted.push(id);
+        }
+        evicted
+    }
+
+    /// Removes the variants of the original, returns their ids. Variants of the original
+    /// being stored are not indexed once they are stored.
+    fn remove_source(&mut self, source: Uuid) -> Vec<Uuid> {
+        self.pending.retain(|_, pending| *pending != source);
+        let variants: Vec<Uuid> = self
+            .sources
+            .get(&source)
+            .map(|variants| variants.iter().cloned().collect())
+            .unwrap_or_default();
+        for id in variants.iter() {
+            self.remove(*id);
+        }
+        variants
+    }
+}
+
+pub struct VariantCache {
+    /// Maximum total size of the variants (in bytes) that can be evicted, 0 disables
+    /// the cache for them.
+    max_size: usize,
+    /// Canonical parameters of the pinned variants.
+    pinned: Vec<String>,
+    index: Mutex<VariantIndex>,
+}
+
+impl VariantCache {
+    /// Creates the cache, the variants with the given canonical parameters are pinned.
+    pub fn new(max_size: usize, pinned: Vec<String>) -> Self {
+        Self {
+            max_size,
+            pinned,
+            index: Mutex::new(VariantIndex::default()),
+        }
+    }
+
+    /// Creates the cache with the index of the variants in the meta storage.
+    pub fn load(meta_storage: &dyn MetaStorage, max_size: usize, pinned: Vec<String>) -> Result<Self, MetaStorageError> {
+        let cache = VariantCache::new(max_size, pinned);
+        if max_size == 0 && cache.pinned.is_empty() {
+            return Ok(cache);
+        }
+
+        {
+            let mut index = cache.index.lock().map_err(|_| MetaStorageError::LockError)?;
+            let mut after = None;
+            loop {
+                let page = meta_storage.list(after, PAGE_SIZE)?;
+                after = match page.last() {
+                    Some(meta) => Some(meta.id),
+                    None => break,
+                };
+                for meta in page {
+                    if let Some(source) = meta.source {
+                        let pinned = cache.is_pinned(source, meta.id);
+                        if pinned || max_size > 0 {
+                            index.insert(meta.id, source, meta.size, pinned);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Returns the id of the variant of the source media with the given canonical
+    /// parameters.
+    pub fn variant_id(source: Uuid, canonical: &str) -> Uuid {
+        let key = format!("{}/{}", source.to_hyphenated(), canonical);
+        let digest = Hash::Sha2_256.hash_bytes(key.as_bytes());
+        Uuid::from_slice(&digest[..16]).expect("expected 16 bytes for the variant id!")
+    }
+
+    /// Returns true if the variant of the source is pinned.
+    fn is_pinned(&self, source: Uuid, id: Uuid) -> bool {
+        self.pinned.iter().any(|canonical| VariantCache::variant_id(source, canonical) == id)
+    }
+
+    /// Returns true if the variant is cached.
+    pub fn contains(&self, id: Uuid) -> Result<bool, StoreError> {
+        Ok(self.lock_index()?.entries.contains_key(&id))
+    }
+
+    /// Number of the cached variants and total size of those that can be evicted.
+    pub fn usage(&self) -> Result<(usize, usize), StoreError> {
+        let index = self.lock_index()?;
+        Ok((index.entries.len(), index.size))
+    }
+
+    fn lock_index(&self) -> Result<MutexGuard<'_, VariantIndex>, StoreError> {
+        self.index.lock().map_err(|_| {
+            eprintln!("Variant: index lock is poisoned!");
+            StoreError::MetaStorageError(MetaStorageError::LockError)
+        })
+    }
+
+    /// Returns the contents of the cached variant.
+    pub async fn get(&self, store: &MediaStore, id: Uuid) -> Result<Option<Vec<u8>>, StoreError> {
+        if !self.lock_index()?.touch(id) {
+            return Ok(None);
+        }
+        match store.load(id).await {
+            Ok((_, buffer)) => Ok(Some(buffer)),
+            Err(StoreError::NotFound) => {
+                // removed with the media api
+                self.lock_index()?.remove(id);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Stores the variant of the source media and evicts the least recently used
+    /// variants exceeding the maximum size.
+    pub async fn put(&self, store: &MediaStore, source: Uuid, id: Uuid, buffer: Vec<u8>) -> Result<(), StoreError> {
+        let pinned = self.is_pinned(source, id);
+        {
+            let mut index = self.lock_index()?;
+            // too large, or stored by a concurrent request already
+            if (!pinned && buffer.len() > self.max_size) || index.touch(id) || index.pending.contains_key(&id) {
+                return Ok(());
+            }
+            index.pending.insert(id, source);
+        }
+
+        let meta = match store.store_variant(source, id, buffer).await {
+            Ok(meta) => meta,
+            Err(err) => {
+                self.lock_index()?.pending.remove(&id);
+                return Err(err);
+            }
+        };
+        // the source might have been removed before the variant was stored
+        let exists = match store.load_meta(source).await {
+            Ok(_) => true,
+            Err(StoreError::NotFound) => false,
+            Err(err) => {
+                self.lock_index()?.pending.remove(&id);
+                remove_variants(store, vec![id]).await;
+                return Err(err);
+            }
+        };
+
+        let evicted = {
+            let mut index = self.lock_index()?;
+            // no longer pending if the source was invalidated in the meantime
+            if index.pending.remove(&id).is_some() && exists {
+                index.insert(meta.id, source, meta.size, pinned);
+                index.evict(self.max_size)
+            } else {
+                vec![id]
+            }
+        };
+        remove_variants(store, evicted).await;
+
+        Ok(())
+    }
+
+    /// Removes the cached variants of the source media.
+    pub async fn invalidate(&self, store: &MediaStore, source: Uuid) -> Result<(), StoreError> {
+        let variants = self.lock_index()?.remove_source(source);
+        remove_variants(store, variants).await;
+        Ok(())
+    }
+}
+
+/// Removes the variants from the store, failures leave orphans behind for garbage
+/// collection.
+async fn remove_variants(store: &MediaStore, variants: Vec<Uuid>) {
+    for id in variants {
+        match store.remove(id).await {
+            Ok(()) | Err(StoreError::NotFound) => {}
+            Err(err) => eprintln!("Variant: error removing variant {}: {:?}", id, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::meta::BlobMeta;
+    use crate::storage::blob::backend::mem::{MemoryBlobStorage, MemoryBlobStorageConfig};
+    use crate::storage::blob::blocking::BlockingBlobStorage;
+    use crate::storage::blob::hashing::Hash;
+    use crate::storage::meta::MetaStorage;
+    use crate::storage::meta::backend::mem::{MemoryMetaStorage, MemoryMetaStorageConfig};
+    use crate::storage::meta::blocking::BlockingMetaStorage;
+    use crate::storage::store::MediaStore;
+    use crate::storage::variant::{VariantCache, VariantIndex};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    /// Returns the ids of the variants in the page.
+    fn variants(page: &[BlobMeta]) -> Vec<Uuid> {
+        page.iter().filter(|meta| meta.source.is_some()).map(|meta| meta.id).collect()
+    }
+
+    #[test]
+    fn test_variant_index() {
+        let source = Uuid::new_v4();
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut index = VariantIndex::default();
+        for id in ids.iter() {
+            index.insert(*id, source, 10, false);
+        }
+        assert_eq!(index.size, 30);
+
+        assert!(index.touch(ids[0]));
+        assert_eq!(index.evict(20), vec![ids[1]]);
+        assert_eq!(index.evict(10), vec![ids[2]]);
+        assert_eq!(index.remove_source(source), vec![ids[0]]);
+        assert_eq!(index.size, 0);
+        assert!(index.sources.is_empty());
+        assert!(!index.touch(ids[0]));
+
+        // pinned variants are not evicted:
+        index.insert(ids[0], source, 10, true);
+        index.insert(ids[1], source, 10, false);
+        assert_eq!(index.size, 10);
+        assert_eq!(index.evict(0), vec![ids[1]]);
+        assert!(index.touch(ids[0]));
+        assert_eq!(index.remove_source(source), vec![ids[0]]);
+        assert!(index.entries.is_empty());
+    }
+
+    #[test]
+    fn test_variant_id() {
+        let source = Uuid::new_v4();
+        assert_eq!(VariantCache::variant_id(source, "a"), VariantCache::variant_id(source, "a"));
+        assert_ne!(VariantCache::variant_id(source, "a"), VariantCache::variant_id(source, "b"));
+        assert_ne!(VariantCache::variant_id(source, "a"), VariantCache::variant_id(Uuid::new_v4(), "a"));
+    }
+
+    /// Media store over in-memory blob storage and the given meta storage.
+    fn store(meta_storage: Arc<MemoryMetaStorage>) -> MediaStore {
+        MediaStore::new(
+            "mem".to_string(),
+            Hash::Sha2_256,
+            Arc::new(BlockingBlobStorage::new(Arc::new(MemoryBlobStorage::new(MemoryBlobStorageConfig {}).unwrap()))),
+            Arc::new(BlockingMetaStorage::new(meta_storage)),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn test_variant_cache() {
+        let meta_storage = Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap());
+        let store = store(meta_storage.clone());
+        let cache = VariantCache::new(10, vec![]);
+
+        let source = store.store(vec![1, 2, 3]).await.unwrap().id;
+        let small = VariantCache::variant_id(source, "small");
+        let large = VariantCache::variant_id(source, "large");
+        assert_eq!(cache.get(&store, small).await.unwrap(), None);
+
+        cache.put(&store, source, small, vec![1; 4]).await.unwrap();
+        cache.put(&store, source, large, vec![2; 8]).await.unwrap();
+        // the small variant is evicted:
+        assert_eq!(cache.get(&store, small).await.unwrap(), None);
+        assert_eq!(cache.get(&store, large).await.unwrap(), Some(vec![2; 8]));
+        assert!(cache.contains(large).unwrap() && !cache.contains(small).unwrap());
+        assert_eq!(cache.usage().unwrap(), (1, 8));
+
+        // exceeding the maximum on its own, not cached:
+        cache.put(&store, source, small, vec![1; 11]).await.unwrap();
+        assert_eq!(cache.usage().unwrap(), (1, 8));
+
+        let loaded = VariantCache::load(meta_storage.as_ref(), 10, vec![]).unwrap();
+        assert_eq!(loaded.usage().unwrap(), (1, 8));
+        assert_eq!(variants(&meta_storage.list(None, 10).unwrap()), vec![large]);
+
+        cache.invalidate(&store, source).await.unwrap();
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+        assert!(variants(&meta_storage.list(None, 10).unwrap()).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_variant_cache_pinned() {
+        let meta_storage = Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap());
+        let store = store(meta_storage.clone());
+        // evicted variants are disabled:
+        let cache = VariantCache::new(0, vec!["eager".to_string()]);
+
+        let source = store.store(vec![1, 2, 3]).await.unwrap().id;
+        let eager = VariantCache::variant_id(source, "eager");
+        let other = VariantCache::variant_id(source, "other");
+        cache.put(&store, source, eager, vec![1; 100]).await.unwrap();
+        cache.put(&store, source, other, vec![2; 100]).await.unwrap();
+        assert_eq!(cache.get(&store, eager).await.unwrap(), Some(vec![1; 100]));
+        assert_eq!(cache.get(&store, other).await.unwrap(), None);
+        assert_eq!(cache.usage().unwrap(), (1, 0));
+
+        let loaded = VariantCache::load(meta_storage.as_ref(), 0, vec!["eager".to_string()]).unwrap();
+        assert!(loaded.contains(eager).unwrap());
+
+        cache.invalidate(&store, source).await.unwrap();
+        assert!(variants(&meta_storage.list(None, 10).unwrap()).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_variant_cache_races() {
+        let meta_storage = Arc::new(MemoryMetaStorage::new(MemoryMetaStorageConfig {}).unwrap());
+        let store = store(meta_storage.clone());
+        let cache = VariantCache::new(10, vec![]);
+        let source = store.store(vec![1, 2, 3]).await.unwrap().id;
+        let small = VariantCache::variant_id(source, "small");
+
+        // stored by a concurrent put already:
+        cache.index.lock().unwrap().pending.insert(small, source);
+        cache.put(&store, source, small, vec![1; 4]).await.unwrap();
+        assert!(variants(&meta_storage.list(None, 10).unwrap()).is_empty());
+
+        // the pending put is dropped once the source is invalidated:
+        cache.invalidate(&store, source).await.unwrap();
+        assert!(cache.index.lock().unwrap().pending.is_empty());
+
+        // the source is removed before the variant is stored:
+        store.remove(source).await.unwrap();
+        cache.put(&store, source, small, vec![1; 4]).await.unwrap();
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+        assert!(variants(&meta_storage.list(None, 10).unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+use crate::transcoder::variant::{Variant, VariantLimits, VariantParams, MAX_DIMENSION};
+use crate::transcoder::TranscoderError;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TranscodingConfig {
+    /// Only the presets are served, variant parameters in the query are rejected.
+    #[serde(default)]
+    pub transcoding_strict: bool,
+    /// Maximum width and height of a variant in pixels.
+    #[serde(default = "TranscodingConfig::default_max_dimension")]
+    pub transcoding_max_dimension: u32,
+    /// Variants may be larger than the original image.
+    #[serde(default)]
+    pub transcoding_upscale: bool,
+    /// Named variants served at `/media/{id}/{preset}`.
+    #[serde(default)]
+    pub transcoding_presets: HashMap<String, VariantParams>,
+    /// Names of the presets generated in the background after every upload.
+    #[serde(default)]
+    pub transcoding_eager: Vec<String>,
+}
+
+impl Default for TranscodingConfig {
+    fn default() -> Self {
+        Self {
+            transcoding_strict: false,
+            transcoding_max_dimension: MAX_DIMENSION,
+            transcoding_upscale: false,
+            transcoding_presets: HashMap::new(),
+            transcoding_eager: Vec::new(),
+        }
+    }
+}
+
+impl TranscodingConfig {
+    fn default_max_dimension() -> u32 {
+        MAX_DIMENSION
+    }
+
+    pub fn limits(&self) -> VariantLimits {
+        VariantLimits {
+            max_dimension: self.transcoding_max_dimension,
+            upscale: self.transcoding_upscale,
+        }
+    }
+
+    /// Returns the variants of the presets, fails on the first invalid preset.
+    pub fn presets(&self) -> Result<HashMap<String, Variant>, TranscoderError> {
+        self.transcoding_presets
+            .iter()
+            .map(|(name, params)| {
+                let variant = params.variant(&self.limits()).map_err(|err| {
+                    eprintln!("Transcoder: invalid preset {}: {:?}", name, err);
+                    err
+                })?;
+                Ok((name.clone(), variant))
+            })
+            .collect()
+    }
+
+    /// Returns the eagerly generated presets, fails if one of them isn't configured.
+    pub fn eager_presets(&self) -> Result<Vec<(String, Variant)>, TranscoderError> {
+        let presets = self.presets()?;
+        self.transcoding_eager
+            .iter()
+            .map(|name| match presets.get(name) {
+                Some(variant) => Ok((name.clone(), variant.clone())),
+                None => {
+                    eprintln!("Transcoder: unknown eager preset {}", name);
+                    Err(TranscoderError::InvalidParameter("unknown eager preset"))
+                }
+            })
+            .collect()
+    }
+}
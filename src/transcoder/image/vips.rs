@@ -0,0 +1,183 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Image Transcoding with Vips
+//!
+//! Decodes the image, applies the transformations in order and encodes it
+//! in the target format. libvips is initialized once, on first use.
+//!
+use crate::domain::media::ImageFormat;
+use crate::transcoder::{CropMode, MediaTransformation, ResizeMode, TranscoderError};
+use ::vips::{Image, Vips, VipsCropMode, VipsError, VipsFormat, VipsSizeMode};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref VIPS: Result<Vips, VipsError> = Vips::new();
+}
+
+fn vips() -> Result<&'static Vips, TranscoderError> {
+    VIPS.as_ref().map_err(|err| TranscoderError::from(err.clone()))
+}
+
+fn vips_format(format: ImageFormat) -> VipsFormat {
+    match format {
+        ImageFormat::Jpeg => VipsFormat::VipsJpeg,
+        ImageFormat::Png => VipsFormat::VipsPng,
+        ImageFormat::Gif => VipsFormat::VipsGif,
+        ImageFormat::WebP => VipsFormat::VipsWebP,
+        ImageFormat::Svg => VipsFormat::VipsSvg,
+    }
+}
+
+fn image_format(format: VipsFormat) -> ImageFormat {
+    match format {
+        VipsFormat::VipsJpeg => ImageFormat::Jpeg,
+        VipsFormat::VipsPng => ImageFormat::Png,
+        VipsFormat::VipsGif => ImageFormat::Gif,
+        VipsFormat::VipsWebP => ImageFormat::WebP,
+        VipsFormat::VipsSvg => ImageFormat::Svg,
+    }
+}
+
+fn vips_size_mode(mode: ResizeMode) -> VipsSizeMode {
+    match mode {
+        ResizeMode::Both => VipsSizeMode::VipsSizeBoth,
+        ResizeMode::Up => VipsSizeMode::VipsSizeUp,
+        ResizeMode::Down => VipsSizeMode::VipsSizeDown,
+        ResizeMode::Force | ResizeMode::ForceDown => VipsSizeMode::VipsSizeForce,
+    }
+}
+
+fn vips_crop_mode(crop: CropMode) -> VipsCropMode {
+    match crop {
+        CropMode::None => VipsCropMode::VipsCropNone,
+        CropMode::Center => VipsCropMode::VipsCropCenter,
+        CropMode::Entropy => VipsCropMode::VipsCropEntropy,
+        CropMode::Attention => VipsCropMode::VipsCropAttention,
+    }
+}
+
+/// Returns the dimension of a forced resize, scaled down to fit the image unless the
+/// resize is allowed to upscale.
+fn forced_dimension(image: (u32, u32), width: u32, height: u32, mode: ResizeMode) -> (u32, u32) {
+    let scale = (image.0 as f64 / width as f64).min(image.1 as f64 / height as f64);
+    if mode != ResizeMode::ForceDown || scale >= 1.0 {
+        return (width, height);
+    }
+    let scaled = |dimension: u32| ((dimension as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Applies the transformations recursively, every image borrows from its predecessor.
+fn apply(image: &Image, transforms: &[MediaTransformation], target: &VipsFormat) -> Result<Vec<u8>, TranscoderError> {
+    match transforms.split_first() {
+        None => Ok(image.save_to_buffer(target)?),
+        Some((MediaTransformation::Resize { width, height, mode, crop }, rest)) => {
+            let (width, height) =
+                forced_dimension((image.width() as u32, image.height() as u32), *width, *height, *mode);
+            let resized = image.resize(
+                width as i32,
+                height as i32,
+                Some(vips_size_mode(*mode)),
+                Some(vips_crop_mode(*crop)),
+            )?;
+            apply(&resized, rest, target)
+        }
+    }
+}
+
+/// Detects the format of the encoded image.
+pub fn find_format(input: &[u8]) -> Result<ImageFormat, TranscoderError> {
+    vips()?
+        .find_image_format_from_buffer(input)
+        .map(image_format)
+        .map_err(|_| TranscoderError::UnsupportedSource)
+}
+
+/// Returns true if the image can be encoded in the format.
+pub fn has_encoder(format: ImageFormat) -> Result<bool, TranscoderError> {
+    Ok(vips()?.has_saver(&vips_format(format)))
+}
+
+/// Transcodes the image, the transformations must be validated beforehand.
+pub fn transcode(
+    source: ImageFormat,
+    target: ImageFormat,
+    transforms: &[MediaTransformation],
+    input: &[u8],
+) -> Result<Vec<u8>, TranscoderError> {
+    let vips = vips()?;
+    if vips.find_image_format_from_buffer(input)? != vips_format(source) {
+        return Err(TranscoderError::SourceMismatch);
+    }
+    let image = vips.load_image_from_buffer(input)?;
+    apply(&image, transforms, &vips_format(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::media::ImageFormat;
+    use crate::load_fixture;
+    use crate::transcoder::{
+        transcode, CropMode, MediaDescription, MediaTransformation, ResizeMode, TranscoderError,
+    };
+    use ::vips::VipsFormat;
+    use std::path::Path;
+
+    #[test]
+    fn test_transcode_image() {
+        let input = load_fixture(Path::new("images").join("rgb.jpeg"));
+        let transcoder = transcode(
+            MediaDescription::Image(ImageFormat::Jpeg),
+            MediaDescription::Image(ImageFormat::WebP),
+            vec![MediaTransformation::Resize {
+                width: 100,
+                height: 100,
+                mode: ResizeMode::Both,
+                crop: CropMode::Center,
+            }],
+        )
+        .unwrap();
+        let output = transcoder.spawn(input).wait().expect("transcoding failed!");
+
+        let vips = super::VIPS.as_ref().unwrap();
+        assert_eq!(vips.find_image_format_from_buffer(&output).unwrap(), VipsFormat::VipsWebP);
+        let image = vips.load_image_from_buffer(&output).unwrap();
+        assert_eq!((image.width(), image.height()), (100, 100));
+    }
+
+    #[test]
+    fn test_transcode_unsupported_target() {
+        let source = MediaDescription::Image(ImageFormat::Png);
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Gif, ImageFormat::WebP, ImageFormat::Svg].iter() {
+            let result = transcode(source.clone(), MediaDescription::Image(*format), vec![]);
+            if super::has_encoder(*format).unwrap() {
+                assert!(result.is_ok());
+            } else {
+                assert_eq!(result.unwrap_err(), TranscoderError::UnsupportedTarget);
+            }
+        }
+        assert!(super::has_encoder(ImageFormat::WebP).unwrap());
+    }
+
+    #[test]
+    fn test_forced_dimension() {
+        assert_eq!(super::forced_dimension((1000, 1000), 400, 300, ResizeMode::ForceDown), (400, 300));
+        assert_eq!(super::forced_dimension((200, 200), 400, 300, ResizeMode::ForceDown), (200, 150));
+        assert_eq!(super::forced_dimension((200, 200), 400, 300, ResizeMode::Force), (400, 300));
+    }
+
+    #[test]
+    fn test_transcode_source_mismatch() {
+        let input = load_fixture(Path::new("images").join("rgba.png"));
+        let transcoder = transcode(
+            MediaDescription::Image(ImageFormat::Jpeg),
+            MediaDescription::Image(ImageFormat::Png),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(transcoder.run(&input).unwrap_err(), TranscoderError::SourceMismatch);
+        assert_eq!(super::find_format(&input).unwrap(), ImageFormat::Png);
+        assert_eq!(super::find_format(&[0, 42, 0]).unwrap_err(), TranscoderError::UnsupportedSource);
+    }
+}
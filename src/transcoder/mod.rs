@@ -1,37 +1,273 @@
-
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
 //! Transcoding is the process of conversion of some media content encoded in some way
 //! into some other encoding. This includes conversion of image file formats (such as
 //! JPEG into WebP), conversion of video containers (such as WEBM into MKV), conversion
 //! of video or audio codecs (such as H264 into VP9). All conversions can be performed
 //! in both directions. We also include things like resizing and cropping in this process
 //! even though this is technically not a transcoding.
-//! 
+//!
 //! The transcoding process is implemented using a basic scheme:
-//! 
+//!
 //! MediaType - audio or video
 //! MediaDescription - includes a MediaType, MimeType, and more detailed description
 //!                    such as container, video coded, audio codec, profiles, bitrate
 //!                    or quality for instance. This depends on the concrete type.
 //! MediaTransformation - such as resizing to a specific size, etc.
-//! 
+//!
 //! The transformation process can then be triggered using the transcode function:
-//! 
-//! fn transcode(MediaType, source: MediaDescription, target: MediaDescription, transforms:
+//!
+//! fn transcode(source: MediaDescription, target: MediaDescription, transforms:
 //! Vec<MediaTransformation>) -> Result<Transcoder, TranscoderError>
-//! 
+//!
 //! The returned transcoder runs the transcoding process in a seperate thread and
 //! provides a convenient interface in blocking and concurrent scenarios.
 //! The transcode function does validation on the descriptions and ensures there is
 //! a matching encoder implementation available.
-//! 
-//! 
+//!
+//! Only images are implemented for now (using vips), there is no video encoder yet.
+//!
+pub mod config;
+pub mod image;
+pub mod variant;
+
+use crate::domain::media::{ImageFormat, MediaType};
+use std::convert::TryFrom;
+use std::fmt;
+use std::thread;
+use vips::VipsError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranscoderError {
+    /// The source and target describe different media types.
+    MediaTypeMismatch,
+    /// There is no decoder implemented for the source.
+    UnsupportedSource,
+    /// There is no encoder implemented for the target.
+    UnsupportedTarget,
+    /// The transformation can't be applied, for instance a resize to zero pixels.
+    InvalidTransformation,
+    /// The variant parameters are invalid.
+    InvalidParameter(&'static str),
+    /// The input isn't encoded as described by the source.
+    SourceMismatch,
+    /// Error reported by the image library.
+    ImageError(VipsError),
+    /// The transcoding thread panicked.
+    ThreadError,
+}
+
+impl From<VipsError> for TranscoderError {
+    fn from(err: VipsError) -> Self {
+        eprintln!("Transcoder: image library error: {:?}", err);
+        TranscoderError::ImageError(err)
+    }
+}
+
+/// Describes how media content is encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaDescription {
+    Image(ImageFormat),
+    /// Video content, identified by the mime type of its container.
+    Video(String),
+}
+
+impl MediaDescription {
+    pub fn media_type(&self) -> MediaType {
+        match self {
+            MediaDescription::Image(_) => MediaType::Image,
+            MediaDescription::Video(_) => MediaType::Video,
+        }
+    }
+
+    pub fn mime(&self) -> &str {
+        match self {
+            MediaDescription::Image(format) => format.mime(),
+            MediaDescription::Video(mime) => mime,
+        }
+    }
+}
+
+/// How the resize treats the aspect ratio and upscaling, see `vips::VipsSizeMode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResizeMode {
+    /// Resize up and down, keeping the aspect ratio.
+    Both,
+    /// Only upsize.
+    Up,
+    /// Only downsize.
+    Down,
+    /// Resize to exactly the given dimension, breaking the aspect ratio.
+    Force,
+    /// Like `Force`, but the dimension is scaled down to fit the image if it is smaller,
+    /// keeping the aspect ratio of the dimension.
+    ForceDown,
+}
+
+/// Which part of the image to keep when cropping, see `vips::VipsCropMode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropMode {
+    /// Don't crop, the image fits within the dimension.
+    None,
+    /// Keep the center.
+    Center,
+    /// Keep the region with the highest entropy.
+    Entropy,
+    /// Keep features likely to draw human attention.
+    Attention,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaTransformation {
+    Resize {
+        width: u32,
+        height: u32,
+        mode: ResizeMode,
+        crop: CropMode,
+    },
+}
+
+impl MediaTransformation {
+    fn validate(&self) -> Result<(), TranscoderError> {
+        match *self {
+            MediaTransformation::Resize { width, height, .. } => {
+                if width == 0 || height == 0 || i32::try_from(width.max(height)).is_err() {
+                    return Err(TranscoderError::InvalidTransformation);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaTransformation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MediaTransformation::Resize { width, height, mode, crop } => {
+                write!(f, "resize({}x{},{:?},{:?})", width, height, mode, crop)
+            }
+        }
+    }
+}
+
+/// A validated transcoding process.
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    source: MediaDescription,
+    target: MediaDescription,
+    transforms: Vec<MediaTransformation>,
+}
+
+impl Transcoder {
+    pub fn source(&self) -> &MediaDescription {
+        &self.source
+    }
+
+    pub fn target(&self) -> &MediaDescription {
+        &self.target
+    }
+
+    /// Transcodes the input on the calling thread.
+    pub fn run(&self, input: &[u8]) -> Result<Vec<u8>, TranscoderError> {
+        match (&self.source, &self.target) {
+            (MediaDescription::Image(source), MediaDescription::Image(target)) => {
+                image::vips::transcode(*source, *target, &self.transforms, input)
+            }
+            _ => Err(TranscoderError::UnsupportedTarget),
+        }
+    }
+
+    /// Transcodes the input in a seperate thread, the result is returned by the handle.
+    pub fn spawn(self, input: Vec<u8>) -> TranscoderHandle {
+        TranscoderHandle(thread::spawn(move || self.run(&input)))
+    }
+}
+
+/// Handle of a transcoding process running in a seperate thread.
+pub struct TranscoderHandle(thread::JoinHandle<Result<Vec<u8>, TranscoderError>>);
 
-ResizeTransform {
-    width: 200,
-    height: 300,
-    scale: ScaleMode::ENTROPY,
-    crop: CropMode::ENTROPY,
+impl TranscoderHandle {
+    /// Blocks until the transcoding is finished.
+    pub fn wait(self) -> Result<Vec<u8>, TranscoderError> {
+        self.0.join().map_err(|_| {
+            eprintln!("Transcoder: transcoding thread panicked!");
+            TranscoderError::ThreadError
+        })?
+    }
+}
+
+/// Detects the description of the encoded input, only images are recognized.
+pub fn describe(input: &[u8]) -> Result<MediaDescription, TranscoderError> {
+    Ok(MediaDescription::Image(image::vips::find_format(input)?))
+}
 
+/// Validates the descriptions and transformations and returns the transcoder.
+pub fn transcode(
+    source: MediaDescription,
+    target: MediaDescription,
+    transforms: Vec<MediaTransformation>,
+) -> Result<Transcoder, TranscoderError> {
+    if source.media_type() != target.media_type() {
+        return Err(TranscoderError::MediaTypeMismatch);
+    }
+    match (&source, &target) {
+        (MediaDescription::Image(_), MediaDescription::Image(_)) => {}
+        (MediaDescription::Video(_), _) => return Err(TranscoderError::UnsupportedSource),
+        _ => return Err(TranscoderError::UnsupportedTarget),
+    }
+    for transform in transforms.iter() {
+        transform.validate()?;
+    }
+    if let MediaDescription::Image(format) = target {
+        if !image::vips::has_encoder(format)? {
+            return Err(TranscoderError::UnsupportedTarget);
+        }
+    }
+    Ok(Transcoder {
+        source,
+        target,
+        transforms,
+    })
 }
 
-transcode(&input, MediaType::Jpeg, MediaType::Png, &transforms)
+#[cfg(test)]
+mod tests {
+    use crate::domain::media::ImageFormat;
+    use crate::transcoder::{
+        transcode, CropMode, MediaDescription, MediaTransformation, ResizeMode, TranscoderError,
+    };
+
+    fn resize(width: u32, height: u32) -> MediaTransformation {
+        MediaTransformation::Resize {
+            width,
+            height,
+            mode: ResizeMode::Both,
+            crop: CropMode::Attention,
+        }
+    }
+
+    #[test]
+    fn test_transcode_validation() {
+        let image = MediaDescription::Image(ImageFormat::Png);
+        let video = MediaDescription::Video("video/webm".to_string());
+
+        assert!(transcode(image.clone(), MediaDescription::Image(ImageFormat::WebP), vec![resize(100, 100)]).is_ok());
+        assert_eq!(
+            transcode(image.clone(), video.clone(), vec![]).unwrap_err(),
+            TranscoderError::MediaTypeMismatch
+        );
+        assert_eq!(
+            transcode(video.clone(), video, vec![]).unwrap_err(),
+            TranscoderError::UnsupportedSource
+        );
+        assert_eq!(
+            transcode(image.clone(), image.clone(), vec![resize(0, 100)]).unwrap_err(),
+            TranscoderError::InvalidTransformation
+        );
+        assert_eq!(
+            transcode(image.clone(), image, vec![resize(u32::MAX, 100)]).unwrap_err(),
+            TranscoderError::InvalidTransformation
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// Rupee - Rust Image Service
+// Copyright (C) 2020 Matthias Hecker
+// Licensed under the Apache License, Version 2.0, or the MIT License
+//! Image Variants
+//!
+//! A variant is an image derived from the original media, described by the same
+//! parameters in the query of a request and in the configured presets. The dimension
+//! of a variant is bounded by the configured limits, and variants are never larger
+//! than the original image unless upscaling is enabled.
+//!
+use crate::domain::media::ImageFormat;
+use crate::transcoder::{CropMode, MediaTransformation, ResizeMode, TranscoderError};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Used for a missing dimension, the maximum coordinate of vips.
+pub const UNBOUNDED: u32 = 10_000_000;
+
+/// Default of the maximum width and height of a variant.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// Limits of the variants, see `TranscodingConfig`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VariantLimits {
+    /// Maximum width and height of a variant in pixels.
+    pub max_dimension: u32,
+    /// Variants may be larger than the original image.
+    pub upscale: bool,
+}
+
+impl Default for VariantLimits {
+    fn default() -> Self {
+        Self {
+            max_dimension: MAX_DIMENSION,
+            upscale: false,
+        }
+    }
+}
+
+/// Parameters of an image variant.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct VariantParams {
+    /// Width of the variant in pixels.
+    #[serde(default, alias = "w")]
+    pub width: Option<u32>,
+    /// Height of the variant in pixels.
+    #[serde(default, alias = "h")]
+    pub height: Option<u32>,
+    /// How the image fits the dimension: `contain` (default), `cover` or `fill`.
+    #[serde(default)]
+    pub fit: Option<String>,
+    /// The part of the image kept by `cover`: `center` (default), `entropy` or `attention`.
+    #[serde(default)]
+    pub crop: Option<String>,
+    /// Format of the variant, defaults to the format of the original.
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// The transformations and the target format of a variant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub transforms: Vec<MediaTransformation>,
+    /// Format of the variant, `None` keeps the format of the original.
+    pub format: Option<ImageFormat>,
+}
+
+impl Variant {
+    /// Returns the canonical form of the variant, equal variants always result in the
+    /// same string.
+    pub fn canonical(&self) -> String {
+        let mut canonical: Vec<String> = self.transforms.iter().map(|transform| transform.to_string()).collect();
+        canonical.push(format!("format({})", self.format.map_or("original", |format| format.extension())));
+        canonical.join(";")
+    }
+}
+
+impl VariantParams {
+    pub fn is_empty(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.fit.is_none()
+            && self.crop.is_none()
+            && self.format.is_none()
+    }
+
+    pub fn variant(&self, limits: &VariantLimits) -> Result<Variant, TranscoderError> {
+        if self.width.max(self.height) > Some(limits.max_dimension) {
+            return Err(TranscoderError::InvalidParameter("dimension exceeds the maximum"));
+        }
+
+        let format = match &self.format {
+            Some(format) => Some(
+                ImageFormat::from_str(format).map_err(|_| TranscoderError::InvalidParameter("unknown format"))?,
+            ),
+            None => None,
+        };
+
+        let (resize, force) = match limits.upscale {
+            true => (ResizeMode::Both, ResizeMode::Force),
+            false => (ResizeMode::Down, ResizeMode::ForceDown),
+        };
+        let (mode, crop) = match (self.fit.as_deref().unwrap_or("contain"), self.crop.as_deref()) {
+            ("contain", None) => (resize, CropMode::None),
+            ("fill", None) => (force, CropMode::None),
+            ("cover", crop) => {
+                let crop = match crop.unwrap_or("center") {
+                    "center" => CropMode::Center,
+                    "entropy" => CropMode::Entropy,
+                    "attention" => CropMode::Attention,
+                    _ => return Err(TranscoderError::InvalidParameter("unknown crop")),
+                };
+                (resize, crop)
+            }
+            ("contain", Some(_)) | ("fill", Some(_)) => {
+                return Err(TranscoderError::InvalidParameter("crop requires fit=cover"))
+            }
+            _ => return Err(TranscoderError::InvalidParameter("unknown fit")),
+        };
+
+        let transforms = match (self.width, self.height) {
+            (None, None) if self.fit.is_none() && self.crop.is_none() => vec![],
+            (None, None) => return Err(TranscoderError::InvalidParameter("fit requires a width or height")),
+            (Some(_), None) | (None, Some(_)) if crop != CropMode::None || mode == force => {
+                return Err(TranscoderError::InvalidParameter("fit requires a width and height"))
+            }
+            (width, height) => vec![MediaTransformation::Resize {
+                width: width.unwrap_or(UNBOUNDED),
+                height: height.unwrap_or(UNBOUNDED),
+                mode,
+                crop,
+            }],
+        };
+
+        Ok(Variant { transforms, format })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::media::ImageFormat;
+    use crate::transcoder::variant::{Variant, VariantLimits, VariantParams, UNBOUNDED};
+    use crate::transcoder::{CropMode, MediaTransformation, ResizeMode};
+
+    fn variant(yaml: &str) -> Option<Variant> {
+        let limits = VariantLimits { max_dimension: 1000, upscale: true };
+        serde_yaml::from_str::<VariantParams>(yaml).unwrap().variant(&limits).ok()
+    }
+
+    fn resized(width: u32, height: u32, mode: ResizeMode, crop: CropMode, format: Option<ImageFormat>) -> Option<Variant> {
+        Some(Variant {
+            transforms: vec![MediaTransformation::Resize { width, height, mode, crop }],
+            format,
+        })
+    }
+
+    #[test]
+    fn test_variant_params() {
+        assert_eq!(
+            variant("{w: 200, h: 300, fit: cover, crop: attention, format: webp}"),
+            resized(200, 300, ResizeMode::Both, CropMode::Attention, Some(ImageFormat::WebP))
+        );
+        assert_eq!(
+            variant("{width: 200}"),
+            resized(200, UNBOUNDED, ResizeMode::Both, CropMode::None, None)
+        );
+        assert_eq!(
+            variant("{w: 200, h: 300, fit: fill}"),
+            resized(200, 300, ResizeMode::Force, CropMode::None, None)
+        );
+        assert_eq!(variant("{format: png}"), Some(Variant { transforms: vec![], format: Some(ImageFormat::Png) }));
+        assert_eq!(variant("{w: 200, fit: cover}"), None);
+        assert_eq!(variant("{w: 200, crop: entropy}"), None);
+        assert_eq!(variant("{w: 200, fit: stretch}"), None);
+        assert_eq!(variant("{format: tiff}"), None);
+        assert_eq!(variant("{fit: contain}"), None);
+        assert_eq!(variant("{w: 1000, h: 1001}"), None);
+    }
+
+    #[test]
+    fn test_variant_params_upscale() {
+        let params: VariantParams = serde_yaml::from_str("{w: 200, h: 300, fit: fill}").unwrap();
+        assert_eq!(
+            Some(params.variant(&VariantLimits::default()).unwrap()),
+            resized(200, 300, ResizeMode::ForceDown, CropMode::None, None)
+        );
+        let params: VariantParams = serde_yaml::from_str("{w: 200, h: 300, fit: cover}").unwrap();
+        assert_eq!(
+            Some(params.variant(&VariantLimits::default()).unwrap()),
+            resized(200, 300, ResizeMode::Down, CropMode::Center, None)
+        );
+    }
+
+    #[test]
+    fn test_variant_canonical() {
+        assert_eq!(
+            variant("{w: 200, h: 300, fit: cover, crop: attention, format: webp}").unwrap().canonical(),
+            "resize(200x300,Both,Attention);format(webp)"
+        );
+        assert_eq!(variant("{}").unwrap().canonical(), "format(original)");
+    }
+}